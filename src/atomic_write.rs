@@ -0,0 +1,122 @@
+// Replacing files so readers see either the old or the new content, never a mix.
+use std::{fs::{File, OpenOptions}, io::Write, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}};
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// A hidden sibling of `path`, so the rename stays on one filesystem.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let unique = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{}.{}-{}.tmp", name, std::process::id(), unique))
+}
+
+// A file written in pieces that only appears at `path` once committed. Dropping it
+// uncommitted removes the temporary file.
+pub(crate) struct AtomicFile {
+    path: PathBuf,
+    temp: PathBuf,
+    file: File,
+}
+
+impl AtomicFile {
+    pub(crate) fn create(path: &Path) -> std::io::Result<AtomicFile> {
+        let temp = temp_path(path);
+        let file = OpenOptions::new().write(true).create_new(true).open(&temp)?;
+        Ok(AtomicFile { path: path.to_path_buf(), temp, file })
+    }
+
+    pub(crate) fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    // fsync the data and rename it over `path`. The parent directory is synced as well
+    // so the rename itself survives a crash.
+    pub(crate) fn commit(self) -> std::io::Result<()> {
+        self.file.sync_all()?;
+        std::fs::rename(&self.temp, &self.path)?;
+        sync_parent(&self.path)
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // Still present unless the rename went through
+        let _ = std::fs::remove_file(&self.temp);
+    }
+}
+
+// Write `data` to a temporary file and rename it over `path`.
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(data)?;
+    file.commit()
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+// Directories cannot be opened for syncing on every platform.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn entries(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir.root()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn write_file_creates_and_replaces_without_leftovers() {
+        let dir = TempDir::new();
+        let path = Path::new(dir.root()).join("data.txt");
+        write_file(&path, b"first").unwrap();
+        write_file(&path, b"second").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert_eq!(entries(&dir), vec!["data.txt"]);
+    }
+
+    #[test]
+    fn content_only_appears_once_committed() {
+        let dir = TempDir::new();
+        let path = dir.write("data.txt", "old");
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"new ").unwrap();
+        file.write_all(b"content").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        file.commit().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new content");
+    }
+
+    #[test]
+    fn dropping_an_uncommitted_file_removes_the_temporary_file() {
+        let dir = TempDir::new();
+        let path = dir.write("data.txt", "old");
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"partial").unwrap();
+        assert_eq!(entries(&dir).len(), 2);
+        drop(file);
+        assert_eq!(entries(&dir), vec!["data.txt"]);
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+    }
+
+    #[test]
+    fn missing_parent_directory_fails() {
+        let dir = TempDir::new();
+        let path = Path::new(dir.root()).join("missing/data.txt");
+        assert_eq!(write_file(&path, b"data").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,372 @@
+// Content codings (RFC 9110, section 8.4.1) and the registry the server picks them from.
+use std::{io::{Read, Write}, sync::Arc};
+
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+pub trait ContentCoding: Send + Sync {
+    // Token used in Accept-Encoding and Content-Encoding, e.g. "gzip".
+    fn name(&self) -> &str;
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>>;
+    // Reader producing the decoded form of `data`, for codings that can also decode
+    // request bodies.
+    fn decoder<'a>(&self, _data: &'a [u8]) -> Option<Box<dyn Read + 'a>> {
+        None
+    }
+    // Writer encoding everything written to it into `output`, for codings that can
+    // compress bodies too large to hold in memory, such as files.
+    fn encoder<'a>(&self, _output: Box<dyn Write + 'a>) -> Option<Box<dyn StreamEncoder + 'a>> {
+        None
+    }
+}
+
+// An encoder in the middle of a stream; `finish` writes whatever the coding needs to
+// end it.
+pub trait StreamEncoder: Write {
+    fn finish(self: Box<Self>) -> std::io::Result<()>;
+}
+
+impl<W: Write> StreamEncoder for GzEncoder<W> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        GzEncoder::finish(*self).map(drop)
+    }
+}
+
+impl<W: Write> StreamEncoder for ZlibEncoder<W> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        ZlibEncoder::finish(*self).map(drop)
+    }
+}
+
+impl<W: Write> StreamEncoder for brotli::CompressorWriter<W> {
+    fn finish(mut self: Box<Self>) -> std::io::Result<()> {
+        // into_inner swallows write errors, so flush first to see those at least
+        self.flush()?;
+        self.into_inner();
+        Ok(())
+    }
+}
+
+impl<W: Write> StreamEncoder for zstd::stream::write::Encoder<'static, W> {
+    fn finish(self: Box<Self>) -> std::io::Result<()> {
+        zstd::stream::write::Encoder::finish(*self).map(drop)
+    }
+}
+
+pub struct Gzip {
+    level: u32,
+}
+
+impl Gzip {
+    // Level 0 (store) to 9 (best).
+    pub fn new(level: u32) -> Gzip {
+        Gzip { level: level.min(9) }
+    }
+}
+
+impl Default for Gzip {
+    fn default() -> Gzip {
+        Gzip::new(6)
+    }
+}
+
+impl ContentCoding for Gzip {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.level));
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+    fn decoder<'a>(&self, data: &'a [u8]) -> Option<Box<dyn Read + 'a>> {
+        Some(Box::new(MultiGzDecoder::new(data)))
+    }
+    fn encoder<'a>(&self, output: Box<dyn Write + 'a>) -> Option<Box<dyn StreamEncoder + 'a>> {
+        Some(Box::new(GzEncoder::new(output, Compression::new(self.level))))
+    }
+}
+
+// The "deflate" coding is a zlib stream (RFC 1950) wrapping DEFLATE data, not raw DEFLATE.
+pub struct Deflate {
+    level: u32,
+}
+
+impl Deflate {
+    // Level 0 (store) to 9 (best).
+    pub fn new(level: u32) -> Deflate {
+        Deflate { level: level.min(9) }
+    }
+}
+
+impl Default for Deflate {
+    fn default() -> Deflate {
+        Deflate::new(6)
+    }
+}
+
+impl ContentCoding for Deflate {
+    fn name(&self) -> &str {
+        "deflate"
+    }
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(self.level));
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+    fn decoder<'a>(&self, data: &'a [u8]) -> Option<Box<dyn Read + 'a>> {
+        Some(Box::new(ZlibDecoder::new(data)))
+    }
+    fn encoder<'a>(&self, output: Box<dyn Write + 'a>) -> Option<Box<dyn StreamEncoder + 'a>> {
+        Some(Box::new(ZlibEncoder::new(output, Compression::new(self.level))))
+    }
+}
+
+pub struct Brotli {
+    quality: u32,
+    window: u32,
+}
+
+impl Brotli {
+    // Quality 0 (fastest) to 11 (best), with a 4 MiB window.
+    pub fn new(quality: u32) -> Brotli {
+        Brotli { quality: quality.min(11), window: 22 }
+    }
+}
+
+impl Default for Brotli {
+    // Quality 11 is far too slow for compressing on the fly.
+    fn default() -> Brotli {
+        Brotli::new(4)
+    }
+}
+
+impl ContentCoding for Brotli {
+    fn name(&self) -> &str {
+        "br"
+    }
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, self.quality, self.window);
+        encoder.write_all(data)?;
+        Ok(encoder.into_inner())
+    }
+    fn decoder<'a>(&self, data: &'a [u8]) -> Option<Box<dyn Read + 'a>> {
+        Some(Box::new(brotli::Decompressor::new(data, 4096)))
+    }
+    fn encoder<'a>(&self, output: Box<dyn Write + 'a>) -> Option<Box<dyn StreamEncoder + 'a>> {
+        Some(Box::new(brotli::CompressorWriter::new(output, 4096, self.quality, self.window)))
+    }
+}
+
+pub struct Zstd {
+    level: i32,
+}
+
+impl Zstd {
+    // Level 1 (fastest) to 22 (best); out of range levels are clamped.
+    pub fn new(level: i32) -> Zstd {
+        let range = zstd::compression_level_range();
+        Zstd { level: level.clamp(*range.start(), *range.end()) }
+    }
+}
+
+impl Default for Zstd {
+    fn default() -> Zstd {
+        Zstd::new(3)
+    }
+}
+
+impl ContentCoding for Zstd {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        zstd::stream::encode_all(data, self.level)
+    }
+    fn decoder<'a>(&self, data: &'a [u8]) -> Option<Box<dyn Read + 'a>> {
+        let decoder = zstd::stream::read::Decoder::with_buffer(data).ok()?;
+        Some(Box::new(decoder))
+    }
+    fn encoder<'a>(&self, output: Box<dyn Write + 'a>) -> Option<Box<dyn StreamEncoder + 'a>> {
+        let encoder = zstd::stream::write::Encoder::new(output, self.level).ok()?;
+        Some(Box::new(encoder))
+    }
+}
+
+// Codings in order of server preference, used to break ties between codings the
+// client weighs equally.
+#[derive(Clone)]
+pub struct CodingRegistry {
+    codings: Vec<Arc<dyn ContentCoding>>,
+}
+
+impl CodingRegistry {
+    pub fn new() -> CodingRegistry {
+        CodingRegistry { codings: Vec::new() }
+    }
+
+    // Add a coding, or replace the one with the same name while keeping its position.
+    pub fn register<C: ContentCoding + 'static>(&mut self, coding: C) {
+        let coding: Arc<dyn ContentCoding> = Arc::new(coding);
+        match self.codings.iter().position(|existing| existing.name().eq_ignore_ascii_case(coding.name())) {
+            Some(index) => self.codings[index] = coding,
+            None => self.codings.push(coding),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.codings.retain(|coding| !coding.name().eq_ignore_ascii_case(name));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn ContentCoding>> {
+        self.codings.iter().find(|coding| coding.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.codings.iter().map(|coding| coding.name()).collect()
+    }
+
+    // Names of the codings that can decode request bodies.
+    pub fn decoder_names(&self) -> Vec<&str> {
+        self.codings
+            .iter()
+            .filter(|coding| coding.decoder(&[]).is_some())
+            .map(|coding| coding.name())
+            .collect()
+    }
+
+    // Names of the codings that can encode a body as a stream.
+    pub fn encoder_names(&self) -> Vec<&str> {
+        self.codings
+            .iter()
+            .filter(|coding| coding.encoder(Box::new(std::io::sink())).is_some())
+            .map(|coding| coding.name())
+            .collect()
+    }
+}
+
+impl Default for CodingRegistry {
+    fn default() -> CodingRegistry {
+        let mut registry = CodingRegistry::new();
+        registry.register(Brotli::default());
+        registry.register(Zstd::default());
+        registry.register(Gzip::default());
+        registry.register(Deflate::default());
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A coding that can only encode, standing in for third-party codings.
+    struct Reverse;
+
+    impl ContentCoding for Reverse {
+        fn name(&self) -> &str {
+            "x-reverse"
+        }
+        fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(data.iter().rev().copied().collect())
+        }
+    }
+
+    fn round_trip(coding: &dyn ContentCoding, data: &[u8]) -> Vec<u8> {
+        let encoded = coding.encode(data).unwrap();
+        let mut decoded = Vec::new();
+        coding.decoder(&encoded).unwrap().read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn built_in_codings_decode_what_they_encode() {
+        let data = "The quick brown fox jumps over the lazy dog. ".repeat(50).into_bytes();
+        for coding in CodingRegistry::default().codings.iter() {
+            assert_eq!(round_trip(coding.as_ref(), &data), data, "{}", coding.name());
+            assert_eq!(round_trip(coding.as_ref(), b""), b"", "{}", coding.name());
+        }
+    }
+
+    #[test]
+    fn deflate_is_a_zlib_stream() {
+        let encoded = Deflate::default().encode(b"abc").unwrap();
+        // zlib header: deflate method with a 32K window
+        assert_eq!(encoded[0], 0x78);
+        assert_eq!((u16::from(encoded[0]) << 8 | u16::from(encoded[1])) % 31, 0);
+    }
+
+    #[test]
+    fn gzip_decodes_concatenated_members() {
+        let mut data = Gzip::default().encode(b"abc").unwrap();
+        data.extend(Gzip::default().encode(b"def").unwrap());
+        let mut decoded = Vec::new();
+        Gzip::default().decoder(&data).unwrap().read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, b"abcdef");
+    }
+
+    #[test]
+    fn corrupt_input_fails_to_decode() {
+        for coding in CodingRegistry::default().codings.iter() {
+            let mut decoded = Vec::new();
+            let mut reader = coding.decoder(b"definitely not compressed data").unwrap();
+            assert!(reader.read_to_end(&mut decoded).is_err(), "{}", coding.name());
+        }
+    }
+
+    #[test]
+    fn levels_are_clamped() {
+        assert_eq!(Gzip::new(42).level, 9);
+        assert_eq!(Deflate::new(10).level, 9);
+        assert_eq!(Brotli::new(99).quality, 11);
+        assert_eq!(Zstd::new(1000).level, *zstd::compression_level_range().end());
+    }
+
+    #[test]
+    fn default_registry_prefers_br_then_zstd_then_gzip_then_deflate() {
+        assert_eq!(CodingRegistry::default().names(), vec!["br", "zstd", "gzip", "deflate"]);
+    }
+
+    #[test]
+    fn registering_a_known_name_replaces_it_in_place() {
+        let mut registry = CodingRegistry::default();
+        registry.register(Gzip::new(1));
+        assert_eq!(registry.names(), vec!["br", "zstd", "gzip", "deflate"]);
+        registry.register(Reverse);
+        assert_eq!(registry.names().last(), Some(&"x-reverse"));
+        assert_eq!(registry.get("X-Reverse").unwrap().encode(b"abc").unwrap(), b"cba");
+    }
+
+    #[test]
+    fn removed_and_encode_only_codings_are_not_offered_for_decoding() {
+        let mut registry = CodingRegistry::default();
+        registry.register(Reverse);
+        registry.remove("BR");
+        assert!(registry.get("br").is_none());
+        assert_eq!(registry.decoder_names(), vec!["zstd", "gzip", "deflate"]);
+    }
+
+    #[test]
+    fn stream_encoders_match_the_decoders() {
+        let data = "The quick brown fox jumps over the lazy dog. ".repeat(5000).into_bytes();
+        for coding in CodingRegistry::default().codings.iter() {
+            let mut encoded = Vec::new();
+            let mut encoder = coding.encoder(Box::new(&mut encoded)).unwrap();
+            for piece in data.chunks(1000) {
+                encoder.write_all(piece).unwrap();
+            }
+            encoder.finish().unwrap();
+            let mut decoded = Vec::new();
+            coding.decoder(&encoded).unwrap().read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, data, "{}", coding.name());
+        }
+    }
+
+    #[test]
+    fn only_codings_with_a_stream_encoder_are_offered_for_streaming() {
+        let mut registry = CodingRegistry::default();
+        registry.register(Reverse);
+        assert_eq!(registry.encoder_names(), vec!["br", "zstd", "gzip", "deflate"]);
+    }
+}
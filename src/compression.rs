@@ -0,0 +1,407 @@
+// Response compression: Accept-Encoding negotiation (RFC 9110, section 12.5.3) and
+// encoding of response bodies.
+use std::{io::Read, sync::Arc};
+
+use crate::{negotiate, CodingRegistry, ContentCoding, HeaderMap, HttpError, HttpErrorKind, HttpFrame, Method, RequestContext, Version};
+
+// Media types that are already compressed, or where compression rarely pays off.
+const INCOMPRESSIBLE_TYPES: [&str; 12] = [
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/pdf",
+    "font/woff",
+    "font/woff2",
+    "application/wasm",
+];
+
+#[derive(Clone)]
+pub(crate) struct CompressionConfig {
+    // Bodies smaller than this are always sent as they are.
+    pub(crate) min_size: usize,
+    pub(crate) codings: CodingRegistry,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig { min_size: 256, codings: CodingRegistry::default() }
+    }
+}
+
+// Pick a content coding from the Accept-Encoding values. `Ok(None)` means identity,
+// and `Err` a 406 because the client refused identity and everything we can produce.
+pub(crate) fn negotiate_coding(accept_encoding: Option<&Vec<String>>, supported: &[&str]) -> Result<Option<String>, HttpError> {
+    // Without the header any coding is acceptable, but identity is the safe choice.
+    let Some(accept_encoding) = accept_encoding else {
+        return Ok(None);
+    };
+    let accepted = negotiate::parse_quality_list(accept_encoding);
+    let weight = |coding: &str| accepted.iter().find(|item| item.value == coding).map(|item| item.q);
+    let wildcard = weight("*");
+
+    let mut best: Option<(&str, f32)> = None;
+    for coding in supported.iter() {
+        let q = weight(&coding.to_ascii_lowercase()).or(wildcard).unwrap_or(0.0);
+        let better = match best {
+            Some((_, current)) => q > current,
+            None => true,
+        };
+        if q > 0.0 && better {
+            best = Some((coding, q));
+        }
+    }
+
+    // Identity is acceptable unless refused explicitly or through "*;q=0".
+    let identity = weight("identity").or(wildcard).unwrap_or(1.0);
+    match best {
+        Some((coding, q)) if q >= identity => Ok(Some(coding.to_string())),
+        _ if identity > 0.0 => Ok(None),
+        _ => Err(HttpError::from_status(406, "None of the acceptable content codings is available")
+                 .with_header("Accept-Encoding", &supported.join(", "))),
+    }
+}
+
+fn is_compressible(content_type: Option<&String>) -> bool {
+    let Some(content_type) = content_type else {
+        return true;
+    };
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if media_type == "image/svg+xml" {
+        return true;
+    }
+    !(media_type.starts_with("image/")
+        || media_type.starts_with("audio/")
+        || media_type.starts_with("video/")
+        || INCOMPRESSIBLE_TYPES.contains(&media_type.as_str()))
+}
+
+fn add_vary(headers: &mut HeaderMap, field: &str) {
+    let vary = headers.map.entry("Vary".to_string()).or_default();
+    if !vary.iter().any(|value| value == "*" || value.eq_ignore_ascii_case(field)) {
+        vary.push(field.to_string());
+    }
+}
+
+// Compress the response body in place when the client accepts one of our codings and
+// the response is worth compressing. A file body is left in place for the writer to
+// encode while it sends it, and the coding to do that with is returned.
+pub(crate) fn compress_response(config: &CompressionConfig, request: &HttpFrame, response: &mut [HttpFrame]) -> Result<Option<Arc<dyn ContentCoding>>, HttpError> {
+    if request.get_method() == Method::HEAD || response.len() != 2 {
+        return Ok(None);
+    }
+    let (head, body) = response.split_at_mut(1);
+    let HttpFrame::ResponseHead { status, headers, .. } = &mut head[0] else {
+        return Ok(None);
+    };
+    // Informational, 204, 206 and 304 responses either have no content or must keep
+    // the coding of the representation they refer to.
+    if status.0 < 200 || status.0 == 204 || status.0 == 206 || status.0 == 304 {
+        return Ok(None);
+    }
+    if headers.get("Content-Encoding").is_some() || !is_compressible(headers.get("Content-Type").and_then(|values| values.first())) {
+        return Ok(None);
+    }
+    let (length, names) = match &body[0] {
+        HttpFrame::BodyChunk { chunk } => (chunk.len() as u64, config.codings.names()),
+        // Encoded files are sent chunked, which HTTP/1.0 clients cannot read
+        HttpFrame::FileBody { length, .. } if matches!(request, HttpFrame::RequestHead { version: Version::Http1_1, .. }) => {
+            (*length, config.codings.encoder_names())
+        },
+        _ => return Ok(None),
+    };
+    if length < config.min_size as u64 {
+        return Ok(None);
+    }
+
+    add_vary(headers, "Accept-Encoding");
+    let Some(name) = negotiate_coding(request.get_headers().get("Accept-Encoding"), &names)? else {
+        return Ok(None);
+    };
+    let Some(coding) = config.codings.get(&name) else {
+        return Ok(None);
+    };
+    let mut streamed = None;
+    match &body[0] {
+        HttpFrame::BodyChunk { chunk } => {
+            let encoded = coding.encode(chunk)
+                .map_err(|e| HttpError::new(HttpErrorKind::IOError, "Error compressing response", None).with_source(e))?;
+            body[0] = HttpFrame::BodyChunk { chunk: encoded };
+        },
+        _ => streamed = Some(coding.clone()),
+    }
+    headers.map.insert("Content-Encoding".to_string(), vec![name]);
+    // The encoded bytes differ from those a strong ETag promised, but the content is
+    // still equivalent, so it keeps matching If-None-Match as a weak one.
+    if let Some(etag) = headers.map.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("ETag")).and_then(|(_, values)| values.first_mut()) {
+        if etag.starts_with('"') {
+            etag.insert_str(0, "W/");
+        }
+    }
+    Ok(streamed)
+}
+
+// Wrap `handler` so that request bodies sent with a Content-Encoding reach it decoded.
+// Codings the server cannot decode are refused with 415, and bodies larger than
+// `max_size` bytes, as sent or once decoded, with 413.
+pub fn decode_request_body<F>(max_size: usize, handler: F) -> impl Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> + Send + Sync
+    where F: Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> + Send + Sync
+{
+    move |mut frames: Vec<HttpFrame>, ctx: &mut RequestContext| {
+        decode_body(ctx.codings(), max_size, &mut frames)?;
+        handler(frames, ctx)
+    }
+}
+
+fn decode_body(codings: &CodingRegistry, max_size: usize, frames: &mut [HttpFrame]) -> Result<(), HttpError> {
+    if frames[1..].iter().filter_map(|frame| frame.body_length()).sum::<u64>() > max_size as u64 {
+        return Err(HttpError::from_status(413, "Request body is too large"));
+    }
+    let HttpFrame::RequestHead { headers, .. } = &mut frames[0] else {
+        return Ok(());
+    };
+    let Some(key) = headers.map.keys().find(|key| key.eq_ignore_ascii_case("Content-Encoding")).cloned() else {
+        return Ok(());
+    };
+    let applied = headers.map.remove(&key).unwrap_or_default();
+    let unsupported = || HttpError::from_status(415, "Unsupported Content-Encoding")
+        .with_header("Accept-Encoding", &codings.decoder_names().join(", "));
+
+    let Some(HttpFrame::BodyChunk { chunk }) = frames.get_mut(1) else {
+        return Ok(());
+    };
+    // Codings are listed in the order they were applied, so undo them from the last one
+    for name in applied.iter().rev().filter(|name| !name.eq_ignore_ascii_case("identity")) {
+        let coding = codings.get(name.trim()).ok_or_else(unsupported)?;
+        let decoder = coding.decoder(chunk).ok_or_else(unsupported)?;
+        let mut decoded = Vec::new();
+        decoder.take(max_size as u64 + 1).read_to_end(&mut decoded)
+            .map_err(|e| HttpError::bad_request("Malformed request body encoding").with_source(e))?;
+        if decoded.len() > max_size {
+            return Err(HttpError::from_status(413, "Decoded request body is too large"));
+        }
+        *chunk = decoded;
+    }
+
+    let length = chunk.len();
+    if let HttpFrame::RequestHead { headers, .. } = &mut frames[0] {
+        headers.map.retain(|key, _| !key.eq_ignore_ascii_case("Content-Length"));
+        headers.map.insert("Content-Length".to_string(), vec![length.to_string()]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{status_code, test_util::list};
+
+    const SUPPORTED: [&str; 3] = ["br", "zstd", "gzip"];
+
+    fn negotiate(accept_encoding: &str) -> Result<Option<String>, HttpError> {
+        negotiate_coding(Some(&list(accept_encoding)), &SUPPORTED)
+    }
+
+    #[test]
+    fn missing_header_means_identity() {
+        assert_eq!(negotiate_coding(None, &SUPPORTED).unwrap(), None);
+    }
+
+    #[test]
+    fn highest_weight_wins_and_ties_follow_server_order() {
+        assert_eq!(negotiate("gzip, br;q=0.5").unwrap().as_deref(), Some("gzip"));
+        assert_eq!(negotiate("GZIP, zstd").unwrap().as_deref(), Some("zstd"));
+        assert_eq!(negotiate("*").unwrap().as_deref(), Some("br"));
+        assert_eq!(negotiate("*, br;q=0").unwrap().as_deref(), Some("zstd"));
+    }
+
+    #[test]
+    fn identity_wins_when_weighed_higher_or_nothing_matches() {
+        assert_eq!(negotiate("gzip;q=0.5, identity").unwrap(), None);
+        assert_eq!(negotiate("compress").unwrap(), None);
+        assert_eq!(negotiate("gzip;q=0").unwrap(), None);
+    }
+
+    #[test]
+    fn refusing_identity_and_every_coding_is_406() {
+        for accept_encoding in ["compress, identity;q=0", "*;q=0"] {
+            let error = negotiate(accept_encoding).unwrap_err();
+            assert_eq!(error.status().map(|status| status.0), Some(406));
+            assert_eq!(error.headers().get_joined("Accept-Encoding").as_deref(), Some("br, zstd, gzip"));
+        }
+        // Refusing identity is fine while a coding is acceptable
+        assert_eq!(negotiate("identity;q=0, gzip").unwrap().as_deref(), Some("gzip"));
+    }
+
+    fn request(method: Method, accept_encoding: &str) -> HttpFrame {
+        let mut headers = HeaderMap::new();
+        headers.map.insert("Accept-Encoding".to_string(), list(accept_encoding));
+        HttpFrame::RequestHead { method, uri: "/".to_string(), version: Version::Http1_1, headers }
+    }
+
+    fn response(code: u16, headers: &[(&str, &str)], body: &[u8]) -> Vec<HttpFrame> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.map.insert(name.to_string(), vec![value.to_string()]);
+        }
+        vec![
+            HttpFrame::ResponseHead { status: status_code(code), version: Version::Http1_1, headers: map },
+            HttpFrame::BodyChunk { chunk: body.to_vec() },
+        ]
+    }
+
+    fn compress(request: &HttpFrame, mut frames: Vec<HttpFrame>) -> Vec<HttpFrame> {
+        compress_response(&CompressionConfig::default(), request, &mut frames).unwrap();
+        frames
+    }
+
+    fn header(frames: &[HttpFrame], name: &str) -> Option<String> {
+        frames[0].get_headers().get_joined(name)
+    }
+
+    #[test]
+    fn compressible_bodies_are_encoded_with_vary_and_a_weak_etag() {
+        let body = "hello world ".repeat(100);
+        let frames = compress(&request(Method::GET, "gzip"), response(200, &[("Content-Type", "text/plain"), ("ETag", "\"abc\"")], body.as_bytes()));
+        assert_eq!(header(&frames, "Content-Encoding").as_deref(), Some("gzip"));
+        assert_eq!(header(&frames, "Vary").as_deref(), Some("Accept-Encoding"));
+        assert_eq!(header(&frames, "ETag").as_deref(), Some("W/\"abc\""));
+        let HttpFrame::BodyChunk { chunk } = &frames[1] else {
+            panic!("expected a body");
+        };
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(chunk.as_slice()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn vary_is_added_even_when_identity_is_chosen() {
+        let body = "x".repeat(1000);
+        let frames = compress(&request(Method::GET, "identity"), response(200, &[("Vary", "Accept")], body.as_bytes()));
+        assert!(header(&frames, "Content-Encoding").is_none());
+        assert_eq!(header(&frames, "Vary").as_deref(), Some("Accept, Accept-Encoding"));
+    }
+
+    #[test]
+    fn some_responses_are_left_alone() {
+        let body = "x".repeat(1000);
+        let gzip = request(Method::GET, "gzip");
+        let untouched = [
+            compress(&request(Method::HEAD, "gzip"), response(200, &[], body.as_bytes())),
+            compress(&gzip, response(200, &[], b"tiny")),
+            compress(&gzip, response(200, &[("Content-Type", "image/png")], body.as_bytes())),
+            compress(&gzip, response(200, &[("Content-Type", "application/zip; name=a")], body.as_bytes())),
+            compress(&gzip, response(200, &[("Content-Encoding", "br")], body.as_bytes())),
+            compress(&gzip, response(206, &[], body.as_bytes())),
+            compress(&gzip, response(304, &[], body.as_bytes())),
+        ];
+        for frames in untouched.iter() {
+            assert_ne!(header(frames, "Content-Encoding").as_deref(), Some("gzip"), "{:?}", frames[0]);
+            assert!(matches!(&frames[1], HttpFrame::BodyChunk { chunk } if chunk.len() == 1000 || chunk == b"tiny"));
+        }
+        // SVG is text even though it is an image type
+        let frames = compress(&gzip, response(200, &[("Content-Type", "image/svg+xml")], body.as_bytes()));
+        assert_eq!(header(&frames, "Content-Encoding").as_deref(), Some("gzip"));
+    }
+
+    #[test]
+    fn file_bodies_are_left_to_be_encoded_while_they_are_sent() {
+        let dir = crate::test_util::TempDir::new();
+        let file = Arc::new(std::fs::File::open(dir.write("notes.txt", "x".repeat(1000))).unwrap());
+        let mut frames = response(200, &[("Content-Type", "text/plain")], b"");
+        frames[1] = HttpFrame::FileBody { file, offset: 0, length: 1000 };
+
+        let mut encoded = frames.clone();
+        let coding = compress_response(&CompressionConfig::default(), &request(Method::GET, "gzip"), &mut encoded).unwrap();
+        assert_eq!(coding.map(|coding| coding.name().to_string()).as_deref(), Some("gzip"));
+        assert_eq!(header(&encoded, "Content-Encoding").as_deref(), Some("gzip"));
+        assert!(matches!(&encoded[1], HttpFrame::FileBody { length: 1000, .. }));
+
+        // HTTP/1.0 clients cannot read the chunked body an encoded file is sent as
+        let mut request = request(Method::GET, "gzip");
+        if let HttpFrame::RequestHead { version, .. } = &mut request {
+            *version = Version::Http1_0;
+        }
+        assert!(compress_response(&CompressionConfig::default(), &request, &mut frames).unwrap().is_none());
+        assert!(header(&frames, "Content-Encoding").is_none());
+    }
+
+    #[test]
+    fn unacceptable_coding_is_406() {
+        let body = "x".repeat(1000);
+        let mut frames = response(200, &[], body.as_bytes());
+        let Err(error) = compress_response(&CompressionConfig::default(), &request(Method::GET, "*;q=0"), &mut frames) else {
+            panic!("expected a 406");
+        };
+        assert_eq!(error.status().map(|status| status.0), Some(406));
+    }
+
+    fn upload(content_encoding: &str, body: Vec<u8>) -> Vec<HttpFrame> {
+        let mut headers = HeaderMap::new();
+        headers.map.insert("content-encoding".to_string(), list(content_encoding));
+        headers.map.insert("Content-Length".to_string(), vec![body.len().to_string()]);
+        vec![
+            HttpFrame::RequestHead { method: Method::PUT, uri: "/".to_string(), version: Version::Http1_1, headers },
+            HttpFrame::BodyChunk { chunk: body },
+        ]
+    }
+
+    fn decode(max_size: usize, frames: &mut [HttpFrame]) -> Result<(), HttpError> {
+        decode_body(&CodingRegistry::default(), max_size, frames)
+    }
+
+    #[test]
+    fn request_bodies_are_decoded_in_reverse_order_of_application() {
+        let codings = CodingRegistry::default();
+        let deflated = codings.get("deflate").unwrap().encode(b"payload").unwrap();
+        let body = codings.get("gzip").unwrap().encode(&deflated).unwrap();
+        let mut frames = upload("deflate, identity, gzip", body);
+        decode(1024, &mut frames).unwrap();
+        assert!(matches!(&frames[1], HttpFrame::BodyChunk { chunk } if chunk == b"payload"));
+        assert!(frames[0].get_headers().get("Content-Encoding").is_none());
+        assert_eq!(frames[0].get_headers().get_joined("Content-Length").as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn unknown_request_codings_are_415_listing_the_decoders() {
+        let mut frames = upload("compress", b"data".to_vec());
+        let error = decode(1024, &mut frames).unwrap_err();
+        assert_eq!(error.status().map(|status| status.0), Some(415));
+        assert_eq!(error.headers().get_joined("Accept-Encoding").as_deref(), Some("br, zstd, gzip, deflate"));
+    }
+
+    #[test]
+    fn oversized_and_malformed_request_bodies_are_refused() {
+        let body = CodingRegistry::default().get("gzip").unwrap().encode(&[0; 2048]).unwrap();
+        let error = decode(2047, &mut upload("gzip", body.clone())).unwrap_err();
+        assert_eq!(error.status().map(|status| status.0), Some(413));
+        decode(2048, &mut upload("gzip", body)).unwrap();
+
+        let error = decode(1024, &mut upload("gzip", b"not gzip".to_vec())).unwrap_err();
+        assert_eq!(error.status().map(|status| status.0), Some(400));
+    }
+
+    #[test]
+    fn requests_without_a_coding_reach_the_handler_as_sent() {
+        let handler = decode_request_body(16, |frames: Vec<HttpFrame>, _ctx: &mut RequestContext| Ok(frames));
+        let mut ctx = RequestContext::new(None, None, 0, Default::default(), CodingRegistry::default());
+        let mut frames = upload("identity", b"plain".to_vec());
+        if let HttpFrame::RequestHead { headers, .. } = &mut frames[0] {
+            headers.map.remove("content-encoding");
+        }
+        let frames = handler(frames, &mut ctx).unwrap();
+        assert!(matches!(&frames[1], HttpFrame::BodyChunk { chunk } if chunk == b"plain"));
+    }
+
+    #[test]
+    fn oversized_plain_request_bodies_are_refused() {
+        let mut frames = upload("identity", vec![0; 17]);
+        assert_eq!(decode(16, &mut frames).unwrap_err().status().map(|status| status.0), Some(413));
+        let mut frames = upload("identity", vec![0; 16]);
+        decode(16, &mut frames).unwrap();
+    }
+}
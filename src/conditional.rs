@@ -0,0 +1,256 @@
+// Validators and conditional requests (RFC 9110, sections 8.8 and 13).
+use std::{fs::Metadata, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{HeaderMap, HttpError, HttpFrame, Method};
+
+#[derive(Clone, Debug)]
+pub(crate) struct Validators {
+    // Including the quotes and any "W/" prefix, as sent in the ETag header.
+    pub(crate) etag: String,
+    pub(crate) last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    // A strong ETag built from the inode, size and modification time. Replacing or
+    // rewriting the file changes at least one of them.
+    pub(crate) fn from_metadata(metadata: &Metadata) -> Validators {
+        let modified = metadata.modified().ok();
+        let nanos = modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_nanos());
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Validators {
+            etag: format!("\"{:x}-{:x}-{:x}\"", inode, metadata.len(), nanos),
+            last_modified: modified,
+        }
+    }
+
+    pub(crate) fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.map.insert("ETag".to_string(), vec![self.etag.clone()]);
+        if let Some(modified) = self.last_modified {
+            headers.map.insert("Last-Modified".to_string(), vec![httpdate::fmt_http_date(modified)]);
+        }
+    }
+}
+
+// Outcome of evaluating the preconditions of a request that passed.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Precondition {
+    Proceed,
+    NotModified,
+}
+
+struct EntityTag<'a> {
+    weak: bool,
+    opaque: &'a str,
+}
+
+fn parse_entity_tag(tag: &str) -> Option<EntityTag<'_>> {
+    let (weak, quoted) = match tag.strip_prefix("W/") {
+        Some(rest) => (true, rest),
+        None => (false, tag),
+    };
+    let opaque = quoted.strip_prefix('"')?.strip_suffix('"')?;
+    if opaque.contains('"') {
+        return None;
+    }
+    Some(EntityTag { weak, opaque })
+}
+
+// Split an If-Match / If-None-Match value into its entity tags. Tags may contain
+// commas, so the value is scanned for quotes rather than split.
+fn parse_entity_tags(value: &str) -> Vec<EntityTag<'_>> {
+    let mut tags = Vec::new();
+    let mut rest = value.trim_start_matches([',', ' ', '\t']);
+    while !rest.is_empty() {
+        let start = if rest.starts_with("W/") { 2 } else { 0 };
+        let end = match rest[start..].strip_prefix('"').and_then(|inner| inner.find('"')) {
+            Some(position) => start + position + 2,
+            None => break,
+        };
+        if let Some(tag) = parse_entity_tag(&rest[..end]) {
+            tags.push(tag);
+        }
+        rest = rest[end..].trim_start_matches([',', ' ', '\t']);
+    }
+    tags
+}
+
+fn etag_matches(header: &str, current: &str, weak_comparison: bool) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+    let Some(current) = parse_entity_tag(current) else {
+        return false;
+    };
+    parse_entity_tags(header).iter().any(|tag| {
+        tag.opaque == current.opaque && (weak_comparison || (!tag.weak && !current.weak))
+    })
+}
+
+fn parse_date(headers: &HeaderMap, name: &str) -> Option<SystemTime> {
+    headers.get_joined(name).and_then(|value| httpdate::parse_http_date(&value).ok())
+}
+
+// HTTP dates have a resolution of one second.
+fn truncate_to_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+// Evaluate the preconditions of `request` against the current state of the target,
+// `None` meaning it does not exist. Fails with 412 when one of them is false.
+pub(crate) fn evaluate(request: &HttpFrame, current: Option<&Validators>) -> Result<Precondition, HttpError> {
+    let headers = request.get_headers();
+    let is_read = matches!(request.get_method(), Method::GET | Method::HEAD);
+    let last_modified = current.and_then(|current| current.last_modified).map(truncate_to_seconds);
+
+    // If-Match, or If-Unmodified-Since when it is absent
+    if let Some(if_match) = headers.get_joined("If-Match") {
+        let matches = match current {
+            Some(current) => etag_matches(&if_match, &current.etag, false),
+            None => false,
+        };
+        if !matches {
+            return Err(HttpError::from_status(412, "If-Match did not match the current representation"));
+        }
+    } else if let (Some(since), Some(last_modified)) = (parse_date(headers, "If-Unmodified-Since"), last_modified) {
+        if last_modified > truncate_to_seconds(since) {
+            return Err(HttpError::from_status(412, "The resource was modified since the given date"));
+        }
+    }
+
+    // If-None-Match, or If-Modified-Since for reads when it is absent
+    if let Some(if_none_match) = headers.get_joined("If-None-Match") {
+        let matches = match current {
+            Some(current) => etag_matches(&if_none_match, &current.etag, true),
+            None => false,
+        };
+        if matches {
+            return if is_read {
+                Ok(Precondition::NotModified)
+            } else {
+                Err(HttpError::from_status(412, "If-None-Match matched the current representation"))
+            };
+        }
+    } else if is_read {
+        if let (Some(since), Some(last_modified)) = (parse_date(headers, "If-Modified-Since"), last_modified) {
+            if last_modified <= truncate_to_seconds(since) {
+                return Ok(Precondition::NotModified);
+            }
+        }
+    }
+    Ok(Precondition::Proceed)
+}
+
+// Whether a Range request may be honoured: true without If-Range, or when its
+// validator still matches the representation (strong comparison for entity tags, an
+// exact date for Last-Modified).
+pub(crate) fn if_range_matches(request: &HttpFrame, current: &Validators) -> bool {
+    let Some(if_range) = request.get_headers().get_joined("If-Range") else {
+        return true;
+    };
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return match (parse_entity_tag(if_range), parse_entity_tag(&current.etag)) {
+            (Some(tag), Some(current)) => !tag.weak && !current.weak && tag.opaque == current.opaque,
+            _ => false,
+        };
+    }
+    match (httpdate::parse_http_date(if_range), current.last_modified) {
+        (Ok(date), Some(last_modified)) => truncate_to_seconds(date) == truncate_to_seconds(last_modified),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::{request, TempDir};
+
+    fn validators(etag: &str) -> Validators {
+        Validators { etag: etag.to_string(), last_modified: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_500)) }
+    }
+
+    fn check(method: &str, headers: &[(&str, &str)], current: Option<&Validators>) -> Result<Precondition, u16> {
+        evaluate(&request(method, "/", headers, b"")[0], current).map_err(|error| error.status().unwrap().0)
+    }
+
+    const MODIFIED: &str = "Tue, 14 Nov 2023 22:13:20 GMT";
+    const BEFORE: &str = "Tue, 14 Nov 2023 22:13:19 GMT";
+
+    #[test]
+    fn metadata_gives_a_strong_etag_that_changes_with_the_file() {
+        let dir = TempDir::new();
+        let path = dir.write("file.txt", "one");
+        let first = Validators::from_metadata(&std::fs::metadata(&path).unwrap());
+        assert!(first.etag.starts_with('"') && first.etag.ends_with('"'));
+        std::fs::write(&path, "three").unwrap();
+        let second = Validators::from_metadata(&std::fs::metadata(&path).unwrap());
+        assert_ne!(first.etag, second.etag);
+
+        let mut headers = HeaderMap::new();
+        second.add_headers(&mut headers);
+        assert_eq!(headers.get_joined("ETag"), Some(second.etag.clone()));
+        assert!(headers.get_joined("Last-Modified").unwrap().ends_with(" GMT"));
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison_and_gives_304_for_reads() {
+        let current = validators("\"abc\"");
+        assert_eq!(check("GET", &[("If-None-Match", "W/\"abc\"")], Some(&current)), Ok(Precondition::NotModified));
+        assert_eq!(check("HEAD", &[("If-None-Match", "\"x\", \"abc\"")], Some(&current)), Ok(Precondition::NotModified));
+        assert_eq!(check("GET", &[("If-None-Match", "\"other\"")], Some(&current)), Ok(Precondition::Proceed));
+        assert_eq!(check("PUT", &[("If-None-Match", "*")], Some(&current)), Err(412));
+        // "*" on a missing target lets a create-only PUT through
+        assert_eq!(check("PUT", &[("If-None-Match", "*")], None), Ok(Precondition::Proceed));
+    }
+
+    #[test]
+    fn if_match_uses_strong_comparison() {
+        let current = validators("\"abc\"");
+        assert_eq!(check("PUT", &[("If-Match", "\"abc\"")], Some(&current)), Ok(Precondition::Proceed));
+        assert_eq!(check("PUT", &[("If-Match", "W/\"abc\"")], Some(&current)), Err(412));
+        assert_eq!(check("PUT", &[("If-Match", "\"a,b\", \"abc\"")], Some(&current)), Ok(Precondition::Proceed));
+        assert_eq!(check("DELETE", &[("If-Match", "*")], None), Err(412));
+        assert_eq!(check("PUT", &[("If-Match", "\"abc\"")], Some(&validators("W/\"abc\""))), Err(412));
+    }
+
+    #[test]
+    fn dates_are_compared_to_the_second() {
+        let current = validators("\"abc\"");
+        assert_eq!(check("GET", &[("If-Modified-Since", MODIFIED)], Some(&current)), Ok(Precondition::NotModified));
+        assert_eq!(check("GET", &[("If-Modified-Since", BEFORE)], Some(&current)), Ok(Precondition::Proceed));
+        assert_eq!(check("PUT", &[("If-Modified-Since", MODIFIED)], Some(&current)), Ok(Precondition::Proceed));
+        assert_eq!(check("GET", &[("If-Modified-Since", "yesterday")], Some(&current)), Ok(Precondition::Proceed));
+        assert_eq!(check("PUT", &[("If-Unmodified-Since", MODIFIED)], Some(&current)), Ok(Precondition::Proceed));
+        assert_eq!(check("PUT", &[("If-Unmodified-Since", BEFORE)], Some(&current)), Err(412));
+    }
+
+    #[test]
+    fn entity_tags_take_precedence_over_dates() {
+        let current = validators("\"abc\"");
+        // If-None-Match decides even though the date alone would give 304
+        let headers = [("If-None-Match", "\"other\""), ("If-Modified-Since", MODIFIED)];
+        assert_eq!(check("GET", &headers, Some(&current)), Ok(Precondition::Proceed));
+        let headers = [("If-Match", "\"abc\""), ("If-Unmodified-Since", BEFORE)];
+        assert_eq!(check("PUT", &headers, Some(&current)), Ok(Precondition::Proceed));
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match_or_the_exact_date() {
+        let current = validators("\"abc\"");
+        let if_range = |value: &str| if_range_matches(&request("GET", "/", &[("If-Range", value)], b"")[0], &current);
+        assert!(if_range_matches(&request("GET", "/", &[], b"")[0], &current));
+        assert!(if_range("\"abc\""));
+        assert!(!if_range("W/\"abc\""));
+        assert!(!if_range("\"other\""));
+        assert!(if_range(MODIFIED));
+        assert!(!if_range(BEFORE));
+        assert!(!if_range("not a date"));
+    }
+}
@@ -0,0 +1,168 @@
+use std::{any::{Any, TypeId}, collections::HashMap, net::SocketAddr, sync::Arc, time::{Duration, Instant, SystemTime}};
+
+use crate::CodingRegistry;
+
+// Values registered with `HttpServer::add_state`, keyed by their type.
+#[derive(Clone, Default)]
+pub(crate) struct AppState {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl AppState {
+    pub(crate) fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref::<T>())
+    }
+}
+
+// Typed per-request storage, used by middleware to hand data to the handlers after it.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+    // Returns the previous value of the same type, if any.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast::<T>().ok())
+            .map(|old| *old)
+    }
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref::<T>())
+    }
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut::<T>())
+    }
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast::<T>().ok())
+            .map(|old| *old)
+    }
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+}
+
+pub struct RequestContext {
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    connection_id: u64,
+    start_instant: Instant,
+    start_time: SystemTime,
+    state: Arc<AppState>,
+    codings: CodingRegistry,
+    extensions: Extensions,
+}
+
+impl RequestContext {
+    pub(crate) fn new(peer_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>, connection_id: u64, state: Arc<AppState>, codings: CodingRegistry) -> RequestContext {
+        RequestContext {
+            peer_addr,
+            local_addr,
+            connection_id,
+            start_instant: Instant::now(),
+            start_time: SystemTime::now(),
+            state,
+            codings,
+            extensions: Extensions::new(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+    // Wall-clock time at which the server started reading the request.
+    pub fn start_time(&self) -> SystemTime {
+        self.start_time
+    }
+    pub fn elapsed(&self) -> Duration {
+        self.start_instant.elapsed()
+    }
+
+    // Application state registered with `HttpServer::add_state`.
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.state.get::<T>()
+    }
+
+    // Content codings registered on the server.
+    pub fn codings(&self) -> &CodingRegistry {
+        &self.codings
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(u32);
+    struct Name(&'static str);
+
+    fn context(state: AppState) -> RequestContext {
+        RequestContext::new(None, None, 7, Arc::new(state), CodingRegistry::default())
+    }
+
+    #[test]
+    fn state_is_looked_up_by_type() {
+        let mut state = AppState::default();
+        state.insert(Counter(3));
+        state.insert(Name("server"));
+        let ctx = context(state);
+        assert_eq!(ctx.state::<Counter>().map(|counter| counter.0), Some(3));
+        assert_eq!(ctx.state::<Name>().map(|name| name.0), Some("server"));
+        assert!(ctx.state::<String>().is_none());
+    }
+
+    #[test]
+    fn registering_a_type_again_replaces_the_value() {
+        let mut state = AppState::default();
+        state.insert(Counter(1));
+        state.insert(Counter(2));
+        assert_eq!(context(state).state::<Counter>().map(|counter| counter.0), Some(2));
+    }
+
+    #[test]
+    fn extensions_hold_one_value_per_type() {
+        let mut extensions = Extensions::new();
+        assert!(extensions.insert(Counter(1)).is_none());
+        assert_eq!(extensions.insert(Counter(2)).map(|old| old.0), Some(1));
+        extensions.get_mut::<Counter>().unwrap().0 += 1;
+        assert_eq!(extensions.get::<Counter>().map(|counter| counter.0), Some(3));
+        assert!(!extensions.contains::<Name>());
+        assert_eq!(extensions.remove::<Counter>().map(|counter| counter.0), Some(3));
+        assert!(extensions.remove::<Counter>().is_none());
+        assert!(!extensions.contains::<Counter>());
+    }
+
+    #[test]
+    fn context_reports_connection_and_timing() {
+        let mut ctx = context(AppState::default());
+        assert_eq!(ctx.connection_id(), 7);
+        assert!(ctx.peer_addr().is_none());
+        assert!(ctx.start_time() <= SystemTime::now());
+        assert!(ctx.elapsed() < Duration::from_secs(60));
+        ctx.extensions_mut().insert(Name("request"));
+        assert_eq!(ctx.extensions().get::<Name>().map(|name| name.0), Some("request"));
+    }
+}
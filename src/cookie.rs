@@ -0,0 +1,440 @@
+// Cookies (RFC 6265): the jar a request carries in its Cookie header, Set-Cookie
+// values for responses, and signed or encrypted values under a server key.
+//
+// Register the key as server state and read it back in handlers:
+//     server.add_state(CookieKey::new(secret));
+//     let key = ctx.state::<CookieKey>().unwrap();
+//     let user = request[0].cookies().get_signed(key, "user");
+use std::{fmt, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use aes_gcm::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, Aes256Gcm, Nonce};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{HttpError, HttpFrame};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_SIZE: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+// A cookie to send with Set-Cookie.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+// token characters (RFC 9110, section 5.6.2)
+fn is_token(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+// cookie-octet: visible ASCII except '"', ',', ';' and '\'
+fn is_cookie_value(text: &str) -> bool {
+    text.bytes().all(|byte| matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E))
+}
+
+// Attribute values may not contain ';' or control characters.
+fn is_attribute_value(text: &str) -> bool {
+    text.bytes().all(|byte| byte != b';' && !byte.is_ascii_control())
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    // A cookie telling the client to delete `name`. Path and Domain must match the ones
+    // the cookie was set with.
+    pub fn removal(name: &str) -> Cookie {
+        let mut cookie = Cookie::new(name, "");
+        cookie.set_max_age(Duration::ZERO);
+        cookie.set_expires(UNIX_EPOCH);
+        cookie
+    }
+
+    // A cookie whose value carries a signature, so tampering is detected when it is
+    // read back with `CookieJar::get_signed`. The value stays readable by the client.
+    pub fn signed(key: &CookieKey, name: &str, value: &str) -> Cookie {
+        Cookie::new(name, &format!("{}.{}", key.signature(name, value), value))
+    }
+
+    // A cookie whose value is encrypted and authenticated, read back with
+    // `CookieJar::get_private`.
+    pub fn private(key: &CookieKey, name: &str, value: &str) -> Cookie {
+        Cookie::new(name, &key.encrypt(name, value))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn set_path(&mut self, path: &str) {
+        self.path = Some(path.to_string());
+    }
+
+    pub fn set_domain(&mut self, domain: &str) {
+        self.domain = Some(domain.to_string());
+    }
+
+    // Sent in whole seconds.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = Some(max_age);
+    }
+
+    pub fn set_expires(&mut self, expires: SystemTime) {
+        self.expires = Some(expires);
+    }
+
+    pub fn set_secure(&mut self, secure: bool) {
+        self.secure = secure;
+    }
+
+    pub fn set_http_only(&mut self, http_only: bool) {
+        self.http_only = http_only;
+    }
+
+    // Browsers drop SameSite=None cookies without Secure, so that implies Secure.
+    pub fn set_same_site(&mut self, same_site: SameSite) {
+        self.same_site = Some(same_site);
+    }
+
+    // Fails with 500 for names, values or attributes that cannot be sent as they are;
+    // encode such values first, or use a signed or private cookie.
+    pub(crate) fn validate(&self) -> Result<(), HttpError> {
+        if !is_token(&self.name) {
+            return Err(HttpError::from_status(500, &format!("Invalid cookie name \"{}\"", self.name)));
+        }
+        if !is_cookie_value(&self.value) {
+            return Err(HttpError::from_status(500, &format!("Invalid value for cookie \"{}\"", self.name)));
+        }
+        if !self.path.iter().chain(self.domain.iter()).all(|value| is_attribute_value(value)) {
+            return Err(HttpError::from_status(500, &format!("Invalid attribute for cookie \"{}\"", self.name)));
+        }
+        Ok(())
+    }
+}
+
+// The Set-Cookie field value.
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = self.path.as_ref() {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = self.domain.as_ref() {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", httpdate::fmt_http_date(expires))?;
+        }
+        if self.secure || self.same_site == Some(SameSite::None) {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => f.write_str("; SameSite=Strict")?,
+            Some(SameSite::Lax) => f.write_str("; SameSite=Lax")?,
+            Some(SameSite::None) => f.write_str("; SameSite=None")?,
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+// Keys for signed and encrypted cookies, derived from one server secret.
+#[derive(Clone)]
+pub struct CookieKey {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CookieKey { .. }")
+    }
+}
+
+impl CookieKey {
+    // Derive the keys from `secret`, which should hold at least 32 random bytes. The same
+    // secret gives the same keys, so cookies stay valid across restarts.
+    pub fn new(secret: &[u8]) -> CookieKey {
+        let derive = |purpose: &[u8]| -> [u8; 32] {
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC takes keys of any length");
+            mac.update(purpose);
+            mac.finalize().into_bytes().into()
+        };
+        CookieKey { signing: derive(b"cookie signing"), encryption: derive(b"cookie encryption") }
+    }
+
+    // A random key. Cookies issued under it are invalid after a restart.
+    pub fn generate() -> CookieKey {
+        let secret = Aes256Gcm::generate_key(OsRng);
+        CookieKey::new(&secret)
+    }
+
+    fn mac(&self, name: &str, value: &str) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.signing).expect("HMAC takes keys of any length");
+        // The name is covered too, so a value cannot be moved to another cookie
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    fn signature(&self, name: &str, value: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(name, value).finalize().into_bytes())
+    }
+
+    fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (signature, value) = signed.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(name, value).verify_slice(&signature).ok()?;
+        Some(value.to_string())
+    }
+
+    fn encrypt(&self, name: &str, value: &str) -> String {
+        let cipher = Aes256Gcm::new(&self.encryption.into());
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let sealed = cipher
+            .encrypt(&nonce, Payload { msg: value.as_bytes(), aad: name.as_bytes() })
+            .expect("AES-GCM encrypts values of any cookie size");
+        let mut data = nonce.to_vec();
+        data.extend(sealed);
+        URL_SAFE_NO_PAD.encode(data)
+    }
+
+    fn decrypt(&self, name: &str, encrypted: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, sealed) = data.split_at(NONCE_SIZE);
+        let cipher = Aes256Gcm::new(&self.encryption.into());
+        let value = cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: name.as_bytes() }).ok()?;
+        String::from_utf8(value).ok()
+    }
+}
+
+// The cookies a request carries, in the order the client sent them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CookieJar {
+    cookies: Vec<(String, String)>,
+}
+
+impl CookieJar {
+    // Parse "a=1; b=2". Pairs without '=' are skipped and quotes around values removed.
+    pub fn parse(header: &str) -> CookieJar {
+        let cookies = header
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| {
+                let value = value.trim();
+                let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+                (name.trim().to_string(), value.to_string())
+            })
+            .filter(|(name, _)| !name.is_empty())
+            .collect();
+        CookieJar { cookies }
+    }
+
+    pub fn from_request(request: &HttpFrame) -> CookieJar {
+        // One field line per entry, when the client sent several
+        let header = request.get_headers().get("Cookie").map(|values| values.join("; ")).unwrap_or_default();
+        CookieJar::parse(&header)
+    }
+
+    // The first cookie called `name`. Browsers send the one with the most specific
+    // path first.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.cookies.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    // The value of a cookie set with `Cookie::signed`, or None when it is missing or
+    // its signature does not match.
+    pub fn get_signed(&self, key: &CookieKey, name: &str) -> Option<String> {
+        self.get(name).and_then(|value| key.verify(name, value))
+    }
+
+    // The value of a cookie set with `Cookie::private`, or None when it is missing or
+    // does not decrypt.
+    pub fn get_private(&self, key: &CookieKey, name: &str) -> Option<String> {
+        self.get(name).and_then(|value| key.decrypt(name, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn key() -> CookieKey {
+        CookieKey::new(b"a test secret that is long enough for the keys")
+    }
+
+    #[test]
+    fn set_cookie_values_carry_their_attributes() {
+        let mut cookie = Cookie::new("session", "abc123");
+        cookie.set_path("/app");
+        cookie.set_domain("example.test");
+        cookie.set_max_age(Duration::from_millis(3_600_900));
+        cookie.set_expires(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        cookie.set_http_only(true);
+        cookie.set_same_site(SameSite::Lax);
+        assert_eq!(cookie.to_string(), "session=abc123; Path=/app; Domain=example.test; Max-Age=3600; \
+                                        Expires=Tue, 14 Nov 2023 22:13:20 GMT; HttpOnly; SameSite=Lax");
+        assert_eq!(Cookie::new("a", "").to_string(), "a=");
+    }
+
+    #[test]
+    fn same_site_none_implies_secure() {
+        let mut cookie = Cookie::new("a", "1");
+        cookie.set_same_site(SameSite::None);
+        assert_eq!(cookie.to_string(), "a=1; Secure; SameSite=None");
+        let mut cookie = Cookie::new("a", "1");
+        cookie.set_secure(true);
+        cookie.set_same_site(SameSite::Strict);
+        assert_eq!(cookie.to_string(), "a=1; Secure; SameSite=Strict");
+    }
+
+    #[test]
+    fn removal_expires_the_cookie() {
+        assert_eq!(Cookie::removal("session").to_string(), "session=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn invalid_names_values_and_attributes_are_refused() {
+        let status = |cookie: Cookie| cookie.validate().unwrap_err().status().unwrap().0;
+        assert!(Cookie::new("ok_name", "value-1").validate().is_ok());
+        assert_eq!(status(Cookie::new("", "x")), 500);
+        assert_eq!(status(Cookie::new("bad name", "x")), 500);
+        assert_eq!(status(Cookie::new("a=b", "x")), 500);
+        assert_eq!(status(Cookie::new("a", "two words")), 500);
+        assert_eq!(status(Cookie::new("a", "x;y")), 500);
+        assert_eq!(status(Cookie::new("a", "\"quoted\"")), 500);
+        let mut cookie = Cookie::new("a", "x");
+        cookie.set_path("/; Domain=evil.test");
+        assert_eq!(status(cookie), 500);
+        let mut cookie = Cookie::new("a", "x");
+        cookie.set_domain("example.test\r\nX: y");
+        assert_eq!(status(cookie), 500);
+    }
+
+    #[test]
+    fn jars_are_parsed_in_order_with_quotes_removed() {
+        let jar = CookieJar::parse("a=1; b=\"two\";novalue; =empty; a=3 ; c=x=y");
+        let cookies: Vec<(&str, &str)> = jar.iter().collect();
+        assert_eq!(cookies, vec![("a", "1"), ("b", "two"), ("a", "3"), ("c", "x=y")]);
+        assert_eq!(jar.get("a"), Some("1"));
+        assert!(jar.contains("c") && !jar.contains("novalue"));
+        assert_eq!(jar.len(), 4);
+        assert!(CookieJar::parse("").is_empty());
+    }
+
+    #[test]
+    fn several_cookie_lines_are_read_as_one_jar() {
+        let raw = b"GET / HTTP/1.1\r\nHost: test\r\nCookie: a=1; b=2\r\nCookie: c=3\r\n\r\n".to_vec();
+        let request = HttpFrame::from_stream(&mut raw.into_iter()).unwrap();
+        let jar = request[0].cookies();
+        assert_eq!(jar.iter().map(|(name, _)| name).collect::<Vec<_>>(), vec!["a", "b", "c"]);
+        let request = test_util::request("GET", "/", &[], b"");
+        assert!(request[0].cookies().is_empty());
+    }
+
+    #[test]
+    fn signed_cookies_detect_tampering() {
+        let key = key();
+        let cookie = Cookie::signed(&key, "user", "alice");
+        assert!(cookie.value().ends_with(".alice"));
+        assert!(cookie.validate().is_ok());
+        let jar = CookieJar::parse(&format!("user={}", cookie.value()));
+        assert_eq!(jar.get_signed(&key, "user").as_deref(), Some("alice"));
+
+        let tampered = cookie.value().replace(".alice", ".admin");
+        assert_eq!(CookieJar::parse(&format!("user={}", tampered)).get_signed(&key, "user"), None);
+        // The signature covers the name, so the value cannot move to another cookie
+        assert_eq!(CookieJar::parse(&format!("role={}", cookie.value())).get_signed(&key, "role"), None);
+        assert_eq!(jar.get_signed(&CookieKey::new(b"another secret"), "user"), None);
+        assert_eq!(CookieJar::parse("user=alice").get_signed(&key, "user"), None);
+        assert_eq!(jar.get_signed(&key, "missing"), None);
+    }
+
+    #[test]
+    fn private_cookies_are_encrypted_and_authenticated() {
+        let key = key();
+        let cookie = Cookie::private(&key, "token", "secret value");
+        assert!(!cookie.value().contains("secret"));
+        assert!(cookie.validate().is_ok());
+        assert_ne!(cookie.value(), Cookie::private(&key, "token", "secret value").value());
+        let jar = CookieJar::parse(&format!("token={}", cookie.value()));
+        assert_eq!(jar.get_private(&key, "token").as_deref(), Some("secret value"));
+
+        let mut tampered = cookie.value().to_string();
+        let last = if tampered.ends_with('A') { 'B' } else { 'A' };
+        tampered.pop();
+        tampered.push(last);
+        assert_eq!(CookieJar::parse(&format!("token={}", tampered)).get_private(&key, "token"), None);
+        assert_eq!(CookieJar::parse(&format!("other={}", cookie.value())).get_private(&key, "other"), None);
+        assert_eq!(jar.get_private(&CookieKey::generate(), "token"), None);
+        assert_eq!(CookieJar::parse("token=short").get_private(&key, "token"), None);
+        assert_eq!(CookieJar::parse("token=!!").get_private(&key, "token"), None);
+    }
+
+    #[test]
+    fn keys_are_derived_from_the_secret() {
+        let cookie = Cookie::signed(&key(), "user", "alice");
+        let jar = CookieJar::parse(&format!("user={}", cookie.value()));
+        assert_eq!(jar.get_signed(&key(), "user").as_deref(), Some("alice"));
+        assert_eq!(format!("{:?}", key()), "CookieKey { .. }");
+    }
+}
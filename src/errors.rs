@@ -0,0 +1,177 @@
+// Turns errors into responses: per-status handlers first, then static error pages,
+// then problem details or a bare status line.
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use crate::{problem, HttpError, HttpFrame};
+
+pub type ErrorHandler = Arc<dyn Fn(&HttpError, Option<&HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> + Send + Sync>;
+
+#[derive(Clone)]
+struct ErrorPage {
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ErrorResponder {
+    pub(crate) problem_details: bool,
+    handlers: HashMap<u16, ErrorHandler>,
+    // Keyed by "404" for a single status or "4xx" for a whole class.
+    pages: HashMap<String, ErrorPage>,
+}
+
+impl ErrorResponder {
+    pub(crate) fn add_handler(&mut self, code: u16, handler: ErrorHandler) {
+        self.handlers.insert(code, handler);
+    }
+
+    // Load "<status>.<ext>" and "<class>xx.<ext>" files, e.g. 404.html or 5xx.html.
+    // Other files in the directory are ignored.
+    pub(crate) fn load_pages(&mut self, dirname: &str) -> Result<usize, HttpError> {
+        let mut count = 0;
+        for entry in std::fs::read_dir(dirname)? {
+            let path = entry?.path();
+            let (Some(stem), Some(extension)) = (path.file_stem().and_then(|s| s.to_str()), path.extension().and_then(|s| s.to_str())) else {
+                continue;
+            };
+            let content_type = match extension.to_ascii_lowercase().as_str() {
+                "html" | "htm" => "text/html; charset=utf-8",
+                "txt" => "text/plain; charset=utf-8",
+                "json" => "application/json",
+                _ => continue,
+            };
+            let key = stem.to_ascii_lowercase();
+            let is_status = key.len() == 3 && key.chars().all(|c| c.is_ascii_digit());
+            let is_class = key.len() == 3 && key.as_bytes()[0].is_ascii_digit() && &key[1..] == "xx";
+            if !is_status && !is_class {
+                continue;
+            }
+            let body = std::fs::read(Path::new(&path))?;
+            self.pages.insert(key, ErrorPage { content_type, body });
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub(crate) fn respond(&self, request: Option<&HttpFrame>, error: &HttpError) -> Vec<HttpFrame> {
+        // Errors that already carry a body were fully built by a handler
+        if error.body().is_some() {
+            return error.to_frames();
+        }
+        let code = error.status().map_or(500, |status| status.0);
+
+        if let Some(handler) = self.handlers.get(&code) {
+            match handler(error, request) {
+                Ok(response) => return response,
+                Err(e) => println!("Error handler for {} failed: {}", code, e),
+            }
+        }
+
+        let page = self.pages.get(&code.to_string()).or_else(|| self.pages.get(&format!("{}xx", code / 100)));
+        if let Some(page) = page {
+            return error.clone()
+                .with_status(code)
+                .with_header("Content-Type", page.content_type)
+                .with_body(page.body.clone())
+                .to_frames();
+        }
+
+        if self.problem_details {
+            let instance = request.map(|request| request.get_uri());
+            let accept = request.and_then(|request| request.get_headers().get("Accept"));
+            return problem::render(error, instance.as_deref(), accept);
+        }
+        error.to_frames()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{status_code, test_util::TempDir, HeaderMap, Method, Version};
+
+    fn request() -> HttpFrame {
+        HttpFrame::RequestHead { method: Method::GET, uri: "/x".to_string(), version: Version::Http1_1, headers: HeaderMap::new() }
+    }
+
+    // (status, Content-Type, body) of a response.
+    fn parts(frames: &[HttpFrame]) -> (u16, Option<String>, String) {
+        let HttpFrame::ResponseHead { status, headers, .. } = &frames[0] else {
+            panic!("expected a response head");
+        };
+        let body = match frames.get(1) {
+            Some(HttpFrame::BodyChunk { chunk }) => String::from_utf8(chunk.clone()).unwrap(),
+            _ => String::new(),
+        };
+        (status.0, headers.get_joined("Content-Type"), body)
+    }
+
+    fn teapot(_error: &HttpError, request: Option<&HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
+        let uri = request.map(|request| request.get_uri()).unwrap_or_default();
+        Ok(vec![
+            HttpFrame::ResponseHead { status: status_code(404), version: Version::Http1_1, headers: HeaderMap::new() },
+            HttpFrame::BodyChunk { chunk: format!("handled {}", uri).into_bytes() },
+        ])
+    }
+
+    #[test]
+    fn handlers_take_precedence_over_pages() {
+        let dir = TempDir::new();
+        dir.write("404.html", "page");
+        let mut errors = ErrorResponder::default();
+        errors.load_pages(dir.root()).unwrap();
+        errors.add_handler(404, Arc::new(teapot));
+        assert_eq!(parts(&errors.respond(Some(&request()), &HttpError::not_found())).2, "handled /x");
+    }
+
+    #[test]
+    fn failing_handlers_fall_back_to_the_next_option() {
+        let mut errors = ErrorResponder::default();
+        errors.add_handler(404, Arc::new(|_error: &HttpError, _request: Option<&HttpFrame>| Err(HttpError::internal("handler broke"))));
+        errors.problem_details = true;
+        let (code, content_type, _) = parts(&errors.respond(None, &HttpError::not_found()));
+        assert_eq!((code, content_type.as_deref()), (404, Some("application/problem+json")));
+    }
+
+    #[test]
+    fn pages_match_the_status_before_its_class() {
+        let dir = TempDir::new();
+        dir.write("404.html", "not found page");
+        dir.write("4xx.txt", "client error page");
+        dir.write("readme.md", "ignored");
+        dir.write("teapot.html", "ignored");
+        let mut errors = ErrorResponder::default();
+        assert_eq!(errors.load_pages(dir.root()).unwrap(), 2);
+
+        let (code, content_type, body) = parts(&errors.respond(None, &HttpError::not_found()));
+        assert_eq!((code, content_type.as_deref(), body.as_str()), (404, Some("text/html; charset=utf-8"), "not found page"));
+        let (code, content_type, body) = parts(&errors.respond(None, &HttpError::forbidden()));
+        assert_eq!((code, content_type.as_deref(), body.as_str()), (403, Some("text/plain; charset=utf-8"), "client error page"));
+        // No page for 5xx: a bare status line
+        let (code, _, body) = parts(&errors.respond(None, &HttpError::internal("oops")));
+        assert_eq!((code, body.as_str()), (500, ""));
+    }
+
+    #[test]
+    fn errors_without_a_status_use_the_500_handler() {
+        let mut errors = ErrorResponder::default();
+        errors.add_handler(500, Arc::new(teapot));
+        let error = HttpError::new(crate::HttpErrorKind::IOError, "I/O Error", None);
+        assert_eq!(parts(&errors.respond(None, &error)).2, "handled ");
+    }
+
+    #[test]
+    fn errors_with_a_body_are_sent_as_they_are() {
+        let mut errors = ErrorResponder::default();
+        errors.add_handler(400, Arc::new(teapot));
+        errors.problem_details = true;
+        let error = HttpError::bad_request("Bad").with_body(b"custom".to_vec());
+        assert_eq!(parts(&errors.respond(None, &error)), (400, None, "custom".to_string()));
+    }
+
+    #[test]
+    fn missing_page_directory_is_an_error() {
+        let mut errors = ErrorResponder::default();
+        assert!(errors.load_pages("/nonexistent/error/pages").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+// Writing `HttpFrame::FileBody` frames: straight from the page cache with sendfile(2)
+// on Linux, in fixed-size buffered reads elsewhere. Memory use does not grow with the
+// file size either way.
+use std::{fs::File, io::{BufWriter, Read, Seek, SeekFrom, Write}, net::TcpStream};
+
+use crate::ContentCoding;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// Read `length` bytes at `offset` into memory, for when the body has to be transformed.
+pub(crate) fn read(file: &File, offset: u64, length: u64) -> std::io::Result<Vec<u8>> {
+    let mut reader = file;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(length as usize);
+    reader.take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "File shrank while it was being read"));
+    }
+    Ok(data)
+}
+
+fn copy_buffered<W: Write + ?Sized>(file: &File, offset: u64, length: u64, socket: &mut W) -> std::io::Result<()> {
+    let mut reader = file;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let wanted = remaining.min(BUFFER_SIZE as u64) as usize;
+        let count = reader.read(&mut buffer[..wanted])?;
+        if count == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "File shrank while it was being sent"));
+        }
+        socket.write_all(&buffer[..count])?;
+        remaining -= count as u64;
+    }
+    Ok(())
+}
+
+// Send `length` bytes of `file` starting at `offset` to `socket`.
+#[cfg(target_os = "linux")]
+pub(crate) fn send(file: &File, offset: u64, length: u64, socket: &mut TcpStream) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut position = offset as libc::off_t;
+    let mut remaining = length;
+    while remaining > 0 {
+        // sendfile moves at most 0x7ffff000 bytes per call
+        let count = remaining.min(0x7fff_f000) as usize;
+        let sent = unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut position, count) };
+        if sent < 0 {
+            let error = std::io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // Files on filesystems without sendfile support, before anything was sent
+                Some(libc::EINVAL) | Some(libc::ENOSYS) if remaining == length => {
+                    return copy_buffered(file, offset, length, socket);
+                },
+                _ => return Err(error),
+            }
+        }
+        if sent == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "File shrank while it was being sent"));
+        }
+        remaining -= sent as u64;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn send(file: &File, offset: u64, length: u64, socket: &mut TcpStream) -> std::io::Result<()> {
+    copy_buffered(file, offset, length, socket)
+}
+
+// Writes each buffer as one chunk of the chunked transfer coding (RFC 9112, section 7.1).
+struct ChunkedWriter<'a> {
+    socket: &'a mut TcpStream,
+}
+
+impl Write for ChunkedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.socket.write_all(format!("{:x}\r\n", buf.len()).as_bytes())?;
+        self.socket.write_all(buf)?;
+        self.socket.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+// Send `length` bytes of `file` starting at `offset` encoded with `coding`, as a
+// chunked body including the last chunk. The file is read and encoded one buffer at a
+// time, so memory use stays the same whatever its size.
+pub(crate) fn send_encoded(file: &File, offset: u64, length: u64, coding: &dyn ContentCoding, socket: &mut TcpStream) -> std::io::Result<()> {
+    let mut output = BufWriter::with_capacity(BUFFER_SIZE, ChunkedWriter { socket });
+    let Some(mut encoder) = coding.encoder(Box::new(&mut output)) else {
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Coding cannot encode streams"));
+    };
+    copy_buffered(file, offset, length, &mut encoder)?;
+    encoder.finish()?;
+    output.flush()?;
+    output.get_mut().socket.write_all(b"0\r\n\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Shutdown, TcpListener};
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    // Contents longer than one buffer, so copies take several rounds.
+    fn contents() -> Vec<u8> {
+        (0..BUFFER_SIZE * 2 + 100).map(|index| (index % 251) as u8).collect()
+    }
+
+    // Run `write` against a loopback socket and return what the peer received.
+    fn transfer(write: impl FnOnce(&mut TcpStream) -> std::io::Result<()>) -> (std::io::Result<()>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut socket, _) = listener.accept().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            received
+        });
+        let result = write(&mut socket);
+        socket.shutdown(Shutdown::Write).unwrap();
+        (result, reader.join().unwrap())
+    }
+
+    #[test]
+    fn read_returns_the_requested_slice() {
+        let dir = TempDir::new();
+        let file = File::open(dir.write("data", contents())).unwrap();
+        assert_eq!(read(&file, 10, 5).unwrap(), contents()[10..15]);
+        assert_eq!(read(&file, 0, 0).unwrap(), b"");
+        // Reading again does not depend on the earlier position
+        assert_eq!(read(&file, BUFFER_SIZE as u64, 3).unwrap(), contents()[BUFFER_SIZE..BUFFER_SIZE + 3]);
+    }
+
+    #[test]
+    fn reading_past_the_end_fails() {
+        let dir = TempDir::new();
+        let file = File::open(dir.write("data", "short")).unwrap();
+        let error = read(&file, 2, 10).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn send_and_copy_write_the_range_to_the_socket() {
+        let dir = TempDir::new();
+        let file = File::open(dir.write("data", contents())).unwrap();
+        let length = (BUFFER_SIZE + 50) as u64;
+        let (result, received) = transfer(|socket| send(&file, 7, length, socket));
+        result.unwrap();
+        assert_eq!(received, contents()[7..7 + length as usize]);
+        let (result, received) = transfer(|socket| copy_buffered(&file, 7, length, socket));
+        result.unwrap();
+        assert_eq!(received, contents()[7..7 + length as usize]);
+    }
+
+    #[test]
+    fn sending_more_than_the_file_holds_fails() {
+        let dir = TempDir::new();
+        let file = File::open(dir.write("data", "short")).unwrap();
+        let (result, received) = transfer(|socket| send(&file, 0, 100, socket));
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(received, b"short");
+        let (result, _) = transfer(|socket| copy_buffered(&file, 0, 100, socket));
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn send_encoded_writes_the_encoded_range_as_bounded_chunks() {
+        let dir = TempDir::new();
+        let text = "line of text from a file on disk\n".repeat(10_000);
+        let file = File::open(dir.write("data", &text)).unwrap();
+        let length = (text.len() - 20) as u64;
+        let (result, received) = transfer(|socket| send_encoded(&file, 10, length, &crate::coding::Gzip::default(), socket));
+        result.unwrap();
+        assert!(received.ends_with(b"0\r\n\r\n"));
+        let first_size = std::str::from_utf8(&received[..received.iter().position(|&byte| byte == b'\r').unwrap()]).unwrap();
+        assert!(usize::from_str_radix(first_size, 16).unwrap() <= BUFFER_SIZE);
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(crate::test_util::dechunk(&received).as_slice()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text[10..text.len() - 10]);
+    }
+}
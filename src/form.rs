@@ -0,0 +1,266 @@
+// application/x-www-form-urlencoded bodies and URI queries as an ordered multi-map,
+// and typed extraction from them through `FromForm`.
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::{percent, HttpError, HttpFrame};
+
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+// Name / value pairs in the order they were sent. Names may repeat.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FormData {
+    pairs: Vec<(String, String)>,
+}
+
+// Types that can be built from form data. Implementations report missing or invalid
+// fields with 400, which `FormData::required` and `FormData::optional` already do:
+//
+//     impl FromForm for Search {
+//         fn from_form(form: &FormData) -> Result<Search, HttpError> {
+//             Ok(Search { q: form.required("q")?, page: form.optional("page")?.unwrap_or(1) })
+//         }
+//     }
+pub trait FromForm: Sized {
+    fn from_form(form: &FormData) -> Result<Self, HttpError>;
+}
+
+fn decode_component(component: &str) -> Result<String, HttpError> {
+    // '+' first, so an escaped "%2B" stays a plus sign
+    percent::decode(&component.replace('+', " "))
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or_else(|| HttpError::bad_request("Malformed form encoding"))
+}
+
+impl FormData {
+    pub fn new() -> FormData {
+        FormData::default()
+    }
+
+    // Parse "a=1&b=two+words&a=%C3%A9". Pairs without '=' get an empty value and empty
+    // pairs are skipped. Malformed escapes and non-UTF-8 values fail with 400.
+    pub fn parse(input: &str) -> Result<FormData, HttpError> {
+        let mut form = FormData::new();
+        for pair in input.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            form.append(&decode_component(name)?, &decode_component(value)?);
+        }
+        Ok(form)
+    }
+
+    // The query of a request target, empty when it has none.
+    pub fn from_uri(uri: &str) -> Result<FormData, HttpError> {
+        let uri = uri.split('#').next().unwrap_or("");
+        match uri.split_once('?') {
+            Some((_, query)) => FormData::parse(query),
+            None => Ok(FormData::new()),
+        }
+    }
+
+    pub fn from_query(request: &HttpFrame) -> Result<FormData, HttpError> {
+        FormData::from_uri(&request.get_uri())
+    }
+
+    // The body of a request sent as application/x-www-form-urlencoded. Other content
+    // types fail with 415.
+    pub fn from_body(request: &[HttpFrame]) -> Result<FormData, HttpError> {
+        let content_type = request[0].get_headers().get_joined("Content-Type").unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        if !media_type.eq_ignore_ascii_case(FORM_CONTENT_TYPE) {
+            return Err(HttpError::from_status(415, "Expected an application/x-www-form-urlencoded body"));
+        }
+        let body: &[u8] = match request.get(1) {
+            Some(HttpFrame::BodyChunk { chunk }) => chunk,
+            _ => &[],
+        };
+        let body = std::str::from_utf8(body).map_err(|_| HttpError::bad_request("Malformed form encoding"))?;
+        FormData::parse(body)
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.pairs.push((name.to_string(), value.to_string()));
+    }
+
+    // Replace all values of `name` with `value`, keeping the position of the first.
+    pub fn set(&mut self, name: &str, value: &str) {
+        let mut first = true;
+        self.pairs.retain(|(key, _)| key != name || std::mem::replace(&mut first, false));
+        match self.pairs.iter_mut().find(|(key, _)| key == name) {
+            Some(pair) => pair.1 = value.to_string(),
+            None => self.append(name, value),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.pairs.retain(|(key, _)| key != name);
+    }
+
+    // The first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.pairs.iter().filter(|(key, _)| key == name).map(|(_, value)| value.as_str()).collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.pairs.iter().any(|(key, _)| key == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    // The first value of `name` parsed as a `T`, failing with 400 when it is missing
+    // or does not parse.
+    pub fn required<T: FromStr>(&self, name: &str) -> Result<T, HttpError> {
+        self.optional(name)?.ok_or_else(|| HttpError::bad_request(&format!("Missing form field \"{}\"", name)))
+    }
+
+    pub fn optional<T: FromStr>(&self, name: &str) -> Result<Option<T>, HttpError> {
+        match self.get(name) {
+            Some(value) => value.parse().map(Some).map_err(|_| HttpError::bad_request(&format!("Invalid value for form field \"{}\"", name))),
+            None => Ok(None),
+        }
+    }
+
+    // Every value of `name` parsed as a `T`.
+    pub fn all<T: FromStr>(&self, name: &str) -> Result<Vec<T>, HttpError> {
+        self.get_all(name)
+            .into_iter()
+            .map(|value| value.parse().map_err(|_| HttpError::bad_request(&format!("Invalid value for form field \"{}\"", name))))
+            .collect()
+    }
+
+    pub fn extract<T: FromForm>(&self) -> Result<T, HttpError> {
+        T::from_form(self)
+    }
+}
+
+// Serializes back to "a=1&b=two+words".
+impl fmt::Display for FormData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (name, value)) in self.pairs.iter().enumerate() {
+            if index > 0 {
+                f.write_str("&")?;
+            }
+            write!(f, "{}={}", percent::encode_form_component(name), percent::encode_form_component(value))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromForm for FormData {
+    fn from_form(form: &FormData) -> Result<FormData, HttpError> {
+        Ok(form.clone())
+    }
+}
+
+// The first value of each name.
+impl FromForm for HashMap<String, String> {
+    fn from_form(form: &FormData) -> Result<HashMap<String, String>, HttpError> {
+        let mut map = HashMap::new();
+        for (name, value) in form.iter() {
+            map.entry(name.to_string()).or_insert_with(|| value.to_string());
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[derive(Debug, PartialEq)]
+    struct Search {
+        q: String,
+        page: u32,
+        tags: Vec<String>,
+    }
+
+    impl FromForm for Search {
+        fn from_form(form: &FormData) -> Result<Search, HttpError> {
+            Ok(Search { q: form.required("q")?, page: form.optional("page")?.unwrap_or(1), tags: form.all("tag")? })
+        }
+    }
+
+    fn error_status<T: fmt::Debug>(result: Result<T, HttpError>) -> u16 {
+        result.unwrap_err().status().unwrap().0
+    }
+
+    #[test]
+    fn pairs_are_decoded_in_order() {
+        let form = FormData::parse("a=1&b=two+words&&flag&a=%C3%A9&plus=%2B").unwrap();
+        let pairs: Vec<(&str, &str)> = form.iter().collect();
+        assert_eq!(pairs, vec![("a", "1"), ("b", "two words"), ("flag", ""), ("a", "é"), ("plus", "+")]);
+        assert_eq!(form.get("a"), Some("1"));
+        assert_eq!(form.get_all("a"), vec!["1", "é"]);
+        assert!(form.contains("flag") && !form.contains("missing"));
+        assert_eq!(form.len(), 5);
+    }
+
+    #[test]
+    fn malformed_encodings_are_400() {
+        assert_eq!(error_status(FormData::parse("a=%zz")), 400);
+        assert_eq!(error_status(FormData::parse("a=%2")), 400);
+        assert_eq!(error_status(FormData::parse("a=%ff")), 400);
+    }
+
+    #[test]
+    fn set_and_remove_edit_every_value() {
+        let mut form = FormData::parse("a=1&b=2&a=3").unwrap();
+        form.set("a", "x");
+        assert_eq!(form.to_string(), "a=x&b=2");
+        form.set("c", "new value & more");
+        assert_eq!(form.to_string(), "a=x&b=2&c=new+value+%26+more");
+        form.remove("a");
+        assert_eq!(FormData::parse(&form.to_string()).unwrap(), form);
+        form.remove("b");
+        form.remove("c");
+        assert!(form.is_empty());
+    }
+
+    #[test]
+    fn queries_come_from_the_request_target() {
+        let request = test_util::request("GET", "/search?q=rust&page=2#results", &[], b"");
+        let form = FormData::from_query(&request[0]).unwrap();
+        assert_eq!(form.get("q"), Some("rust"));
+        assert_eq!(form.get("page"), Some("2"));
+        assert!(FormData::from_uri("/search").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bodies_need_the_form_content_type() {
+        let body = b"q=a+b";
+        let request = test_util::request("POST", "/", &[("Content-Type", "Application/X-WWW-Form-Urlencoded; charset=utf-8")], body);
+        assert_eq!(FormData::from_body(&request).unwrap().get("q"), Some("a b"));
+        let request = test_util::request("POST", "/", &[("Content-Type", "application/json")], body);
+        assert_eq!(error_status(FormData::from_body(&request)), 415);
+        let request = test_util::request("POST", "/", &[], body);
+        assert_eq!(error_status(FormData::from_body(&request)), 415);
+        let request = test_util::request("POST", "/", &[("Content-Type", FORM_CONTENT_TYPE)], b"q=\xff");
+        assert_eq!(error_status(FormData::from_body(&request)), 400);
+    }
+
+    #[test]
+    fn typed_fields_are_extracted_or_refused_with_400() {
+        let form = FormData::parse("q=rust&tag=a&tag=b").unwrap();
+        assert_eq!(form.extract::<Search>().unwrap(), Search { q: "rust".to_string(), page: 1, tags: vec!["a".to_string(), "b".to_string()] });
+        assert_eq!(error_status(FormData::parse("page=2").unwrap().extract::<Search>()), 400);
+        assert_eq!(error_status(FormData::parse("q=x&page=two").unwrap().extract::<Search>()), 400);
+        assert_eq!(error_status(FormData::parse("q=x&tag=a").unwrap().all::<u32>("tag")), 400);
+
+        let map: HashMap<String, String> = FormData::parse("a=1&a=2&b=3").unwrap().extract().unwrap();
+        assert_eq!(map.get("a").map(String::as_str), Some("1"));
+        assert_eq!(map.len(), 2);
+    }
+}
@@ -0,0 +1,105 @@
+// JSON request bodies deserialized into handler types.
+use serde::de::DeserializeOwned;
+
+use crate::{HttpError, HttpFrame, Method};
+
+// application/json and structured syntax suffixes such as application/problem+json.
+fn is_json_media_type(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    media_type == "application/json" || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+}
+
+// Deserialize the request body as a `T`. Bodies not labelled as JSON fail with 415,
+// malformed or mismatching ones with 400 naming the problem and where it is.
+pub fn from_body<T: DeserializeOwned>(request: &[HttpFrame]) -> Result<T, HttpError> {
+    let content_type = request[0].get_headers().get_joined("Content-Type").unwrap_or_default();
+    if !is_json_media_type(&content_type) {
+        let error = HttpError::from_status(415, "Expected an application/json body");
+        // Accept-Post and Accept-Patch tell clients which media types those methods take
+        return Err(match request[0].get_method() {
+            Method::POST => error.with_header("Accept-Post", "application/json"),
+            Method::PATCH => error.with_header("Accept-Patch", "application/json"),
+            _ => error,
+        });
+    }
+    let body: &[u8] = match request.get(1) {
+        Some(HttpFrame::BodyChunk { chunk }) => chunk,
+        _ => &[],
+    };
+    if body.is_empty() {
+        return Err(HttpError::bad_request("The request body is empty"));
+    }
+    serde_json::from_slice(body).map_err(|e| {
+        let message = match e.classify() {
+            serde_json::error::Category::Data => format!("Invalid JSON body: {}", e),
+            _ => format!("Malformed JSON body: {}", e),
+        };
+        HttpError::bad_request(&message)
+            .with_extension("line", e.line())
+            .with_extension("column", e.column())
+            .with_source(e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::test_util;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct User {
+        name: String,
+        age: u8,
+    }
+
+    fn parse(method: &str, content_type: &str, body: &str) -> Result<User, HttpError> {
+        let headers: &[(&str, &str)] = if content_type.is_empty() { &[] } else { &[("Content-Type", content_type)] };
+        from_body(&test_util::request(method, "/users", headers, body.as_bytes()))
+    }
+
+    fn extension(error: &HttpError, name: &str) -> Option<serde_json::Value> {
+        error.problem().and_then(|problem| problem.extensions.get(name).cloned())
+    }
+
+    #[test]
+    fn json_bodies_are_deserialized() {
+        let user = parse("POST", "application/json; charset=utf-8", r#"{"name": "Ada", "age": 36}"#).unwrap();
+        assert_eq!(user, User { name: "Ada".to_string(), age: 36 });
+        assert!(parse("PUT", "application/merge-patch+json", r#"{"name": "Ada", "age": 36}"#).is_ok());
+    }
+
+    #[test]
+    fn other_content_types_are_415_with_the_accepted_type() {
+        let error = parse("POST", "text/plain", "{}").unwrap_err();
+        assert_eq!(error.status().unwrap().0, 415);
+        assert_eq!(error.headers().get_joined("Accept-Post").as_deref(), Some("application/json"));
+        let error = parse("PATCH", "", "{}").unwrap_err();
+        assert_eq!(error.headers().get_joined("Accept-Patch").as_deref(), Some("application/json"));
+        let error = parse("PUT", "application/jsonp", "{}").unwrap_err();
+        assert_eq!(error.status().unwrap().0, 415);
+        assert!(error.headers().map.is_empty());
+    }
+
+    #[test]
+    fn empty_bodies_are_400() {
+        let error = parse("POST", "application/json", "").unwrap_err();
+        assert_eq!(error.status().unwrap().0, 400);
+        assert_eq!(error.message(), "The request body is empty");
+    }
+
+    #[test]
+    fn malformed_and_mismatching_bodies_are_400_with_their_position() {
+        let error = parse("POST", "application/json", "{\n  \"name\": \"Ada\",\n  \"age\": }").unwrap_err();
+        assert_eq!(error.status().unwrap().0, 400);
+        assert!(error.message().starts_with("Malformed JSON body"), "{}", error.message());
+        assert_eq!(extension(&error, "line"), Some(3.into()));
+        assert_eq!(extension(&error, "column"), Some(10.into()));
+
+        let error = parse("POST", "application/json", r#"{"name": "Ada", "age": 300}"#).unwrap_err();
+        assert!(error.message().starts_with("Invalid JSON body"), "{}", error.message());
+        let error = parse("POST", "application/json", r#"{"name": "Ada"}"#).unwrap_err();
+        assert!(error.message().contains("missing field `age`"), "{}", error.message());
+    }
+}
@@ -1,10 +1,53 @@
 
 use std::{collections::HashMap, io::{Read, Write}, str::SplitWhitespace, sync::Arc};
 use std::result::Result::Ok;
-use std::fmt;
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use flate2::write::DeflateEncoder;
+
+mod atomic_write;
+pub mod coding;
+mod compression;
+mod conditional;
+mod context;
+mod cookie;
+mod errors;
+mod file_body;
+mod form;
+pub mod json;
+mod listing;
+pub mod mime;
+pub mod multipart;
+pub mod negotiate;
+pub mod percent;
+mod problem;
+mod range;
+mod response;
+mod router;
+mod safe_path;
+mod static_files;
+#[cfg(test)]
+mod test_util;
+mod tus;
+mod vhost;
+mod webdav;
+mod xml;
+pub use coding::{CodingRegistry, ContentCoding, StreamEncoder};
+pub use compression::decode_request_body;
+pub use context::{Extensions, RequestContext};
+pub use cookie::{Cookie, CookieJar, CookieKey, SameSite};
+pub use errors::ErrorHandler;
+pub use form::{FormData, FromForm};
+pub use mime::MimeTypes;
+pub use multipart::{MultipartLimits, MultipartParser};
+pub use response::Response;
+pub use router::{Handler, Middleware, Next, OriginalUri, Router};
+pub use safe_path::PathResolver;
+pub use static_files::{StaticFiles, WriteGuard};
+pub use tus::TusUploads;
+pub use webdav::WebDav;
+use compression::CompressionConfig;
+use context::AppState;
+use errors::ErrorResponder;
+use problem::ProblemFields;
+use vhost::VirtualHosts;
 
 struct DataStream {
     active: bool,
@@ -18,15 +61,18 @@ impl DataStream  {
     pub fn new(stream: std::net::TcpStream) -> DataStream {
         DataStream {
             active: true,
-            stream: stream,
+            stream,
             data: [0; 1024],
             rptr: 0,
             wptr: 0,
         }
     }
-    pub fn close(&mut self) -> () {
+    pub fn close(&mut self) {
         self.active = false;
-        self.stream.shutdown(std::net::Shutdown::Both).unwrap();
+        // The peer may have closed the connection already
+        if let Err(error) = self.stream.shutdown(std::net::Shutdown::Both) {
+            println!("Error closing stream: {}", error);
+        }
     }
 
     fn consume_byte(&mut self) -> u8 {
@@ -34,17 +80,20 @@ impl DataStream  {
         self.rptr += 1;
         byte
     }
-    pub fn write(&mut self, data: &[u8]) -> Result<usize, HttpError> {
-        match self.stream.write(data) {
-            Ok(count) => Ok(count),
-            Err(error) => {
-                println!("Error writing to stream: {}", error);
-                Err(HttpError::new(HttpErrorKind::IOError, "I/O Error", None))
-            },
-        }
+    pub fn write_all(&mut self, data: &[u8]) -> Result<(), HttpError> {
+        self.stream.write_all(data)
+            .map_err(|error| HttpError::new(HttpErrorKind::IOError, "I/O Error", None).with_source(error))
+    }
+    pub fn send_file(&mut self, file: &std::fs::File, offset: u64, length: u64) -> Result<(), HttpError> {
+        file_body::send(file, offset, length, &mut self.stream)
+            .map_err(|error| HttpError::new(HttpErrorKind::IOError, "I/O Error", None).with_source(error))
+    }
+    pub fn send_encoded_file(&mut self, file: &std::fs::File, offset: u64, length: u64, coding: &dyn ContentCoding) -> Result<(), HttpError> {
+        file_body::send_encoded(file, offset, length, coding, &mut self.stream)
+            .map_err(|error| HttpError::new(HttpErrorKind::IOError, "I/O Error", None).with_source(error))
     }
     fn next(&mut self) -> Option<u8> {
-        if self.active == false {
+        if !self.active {
             println!("Stream is closed");
             return None;
         }
@@ -61,11 +110,11 @@ impl DataStream  {
                 if count == 0 {
                     return None;
                 }
-                return Some(self.consume_byte());
+                Some(self.consume_byte())
             }
             Err(_) => {
                 println!("Error reading from socket");
-                return None;
+                None
             }
         }
     }
@@ -80,6 +129,13 @@ impl Iterator for DataStream {
 }
 
 
+// Fields whose values are not comma separated lists, kept whole when parsing.
+const UNSPLIT_HEADERS: [&str; 2] = ["Cookie", "Set-Cookie"];
+
+// Fields that may appear once; a request repeating one is refused, since the two
+// values could be read differently by a proxy in front of the server.
+const SINGLETON_HEADERS: [&str; 3] = ["Content-Length", "Host", "Content-Type"];
+
 #[derive(Debug, Clone)]
 pub struct HeaderMap {
     pub map: HashMap<String, Vec<String>>,
@@ -90,6 +146,23 @@ impl HeaderMap {
             map: HashMap::new(),
         }
     }
+    // Header names are case-insensitive, so look the name up regardless of how the peer spelled it.
+    pub fn get(&self, name: &str) -> Option<&Vec<String>> {
+        self.map
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, values)| values)
+    }
+    // The field value as sent, for headers whose values contain commas themselves
+    // (HTTP dates, for example) and were split apart while parsing.
+    pub fn get_joined(&self, name: &str) -> Option<String> {
+        self.get(name).map(|values| values.join(", "))
+    }
+}
+impl Default for HeaderMap {
+    fn default() -> HeaderMap {
+        HeaderMap::new()
+    }
 }
 
 
@@ -101,25 +174,129 @@ pub enum HttpErrorKind {
     IOError,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("HttpError: {message}")]
 pub struct HttpError {
     kind: HttpErrorKind,
-    err_msg: String,
-    err_code: u32,
+    message: String,
+    // Response to send for this error. Errors without a status become a 500.
+    status: Option<StatusCode>,
+    headers: Box<HeaderMap>,
+    body: Option<Vec<u8>>,
+    problem: Option<Box<ProblemFields>>,
+    #[source]
+    source: Option<Arc<dyn std::error::Error + Send + Sync>>,
 }
 
 impl HttpError {
-    pub fn new(kind: HttpErrorKind, msg: &str, code: Option<u32>) -> HttpError {
+    pub fn new(kind: HttpErrorKind, msg: &str, code: Option<u16>) -> HttpError {
         HttpError {
-            kind: kind,
-            err_msg: msg.to_string(),
-            err_code: code.unwrap_or(0),
+            kind,
+            message: msg.to_string(),
+            status: code.map(status_code),
+            headers: Box::default(),
+            body: None,
+            problem: None,
+            source: None,
+        }
+    }
+    // An error that is answered with `code` and its standard reason phrase.
+    pub fn from_status(code: u16, msg: &str) -> HttpError {
+        let kind = if code < 500 { HttpErrorKind::RequestError } else { HttpErrorKind::ResponseError };
+        HttpError::new(kind, msg, Some(code))
+    }
+    pub fn bad_request(msg: &str) -> HttpError {
+        HttpError::from_status(400, msg)
+    }
+    pub fn forbidden() -> HttpError {
+        HttpError::from_status(403, "Forbidden")
+    }
+    pub fn not_found() -> HttpError {
+        HttpError::from_status(404, "Not Found")
+    }
+    pub fn method_not_allowed() -> HttpError {
+        HttpError::from_status(405, "Method Not Allowed")
+    }
+    pub fn internal(msg: &str) -> HttpError {
+        HttpError::from_status(500, msg)
+    }
+
+    pub fn with_status(mut self, code: u16) -> HttpError {
+        self.status = Some(status_code(code));
+        self
+    }
+    // Adds a header to the error response, e.g. `Allow` on a 405.
+    pub fn with_header(mut self, name: &str, value: &str) -> HttpError {
+        self.headers.map.entry(name.to_string()).or_default().push(value.to_string());
+        self
+    }
+    pub fn with_body(mut self, body: Vec<u8>) -> HttpError {
+        self.body = Some(body);
+        self
+    }
+    // URI identifying the problem type in problem details responses.
+    pub fn with_problem_type(mut self, uri: &str) -> HttpError {
+        self.problem.get_or_insert_with(Box::default).problem_type = Some(uri.to_string());
+        self
+    }
+    // Extra member for problem details responses. The standard members cannot be overridden.
+    pub fn with_extension<V: Into<serde_json::Value>>(mut self, name: &str, value: V) -> HttpError {
+        self.problem.get_or_insert_with(Box::default).extensions.insert(name.to_string(), value.into());
+        self
+    }
+    pub fn with_source<E>(mut self, source: E) -> HttpError
+        where E: std::error::Error + Send + Sync + 'static
+    {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
+    pub fn kind(&self) -> &HttpErrorKind {
+        &self.kind
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    pub fn status(&self) -> Option<&StatusCode> {
+        self.status.as_ref()
+    }
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    pub fn body(&self) -> Option<&[u8]> {
+        self.body.as_deref()
+    }
+    pub(crate) fn problem(&self) -> Option<&ProblemFields> {
+        self.problem.as_deref()
+    }
+
+    // Response frames for this error, falling back to 500 when no status was set.
+    pub fn to_frames(&self) -> Vec<HttpFrame> {
+        let mut headers = (*self.headers).clone();
+        if !headers.map.contains_key("Content-Length") {
+            let length = self.body.as_ref().map_or(0, |body| body.len());
+            headers.map.insert("Content-Length".to_string(), vec![length.to_string()]);
+        }
+        let mut frames = vec![HttpFrame::ResponseHead {
+            status: self.status.clone().unwrap_or_else(|| status_code(500)),
+            version: Version::Http1_1,
+            headers,
+        }];
+        if let Some(body) = self.body.as_ref() {
+            frames.push(HttpFrame::BodyChunk { chunk: body.clone() });
         }
+        frames
     }
 }
-impl fmt::Display for HttpError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "HttpError: {}", self.err_msg)
+
+impl From<std::io::Error> for HttpError {
+    fn from(error: std::io::Error) -> HttpError {
+        let code = match error.kind() {
+            std::io::ErrorKind::NotFound => Some(404),
+            std::io::ErrorKind::PermissionDenied => Some(403),
+            _ => None,
+        };
+        HttpError::new(HttpErrorKind::IOError, "I/O Error", code).with_source(error)
     }
 }
 
@@ -135,6 +312,15 @@ pub enum Method {
     OPTIONS,
     CONNECT,
     TRACE,
+    PATCH,
+    // WebDAV (RFC 4918)
+    PROPFIND,
+    PROPPATCH,
+    MKCOL,
+    COPY,
+    MOVE,
+    LOCK,
+    UNLOCK,
 }
 impl Method {
     pub fn from_string(method: &str) -> Result<Method, HttpError> {
@@ -147,6 +333,14 @@ impl Method {
             "OPTIONS" => Ok(Method::OPTIONS),
             "CONNECT" => Ok(Method::CONNECT),
             "TRACE" => Ok(Method::TRACE),
+            "PATCH" => Ok(Method::PATCH),
+            "PROPFIND" => Ok(Method::PROPFIND),
+            "PROPPATCH" => Ok(Method::PROPPATCH),
+            "MKCOL" => Ok(Method::MKCOL),
+            "COPY" => Ok(Method::COPY),
+            "MOVE" => Ok(Method::MOVE),
+            "LOCK" => Ok(Method::LOCK),
+            "UNLOCK" => Ok(Method::UNLOCK),
             _ => Err(HttpError::new(HttpErrorKind::RequestError,"Bad Request", Some(400))),
         }
     }
@@ -160,6 +354,14 @@ impl Method {
             Method::OPTIONS => "OPTIONS",
             Method::CONNECT => "CONNECT",
             Method::TRACE => "TRACE",
+            Method::PATCH => "PATCH",
+            Method::PROPFIND => "PROPFIND",
+            Method::PROPPATCH => "PROPPATCH",
+            Method::MKCOL => "MKCOL",
+            Method::COPY => "COPY",
+            Method::MOVE => "MOVE",
+            Method::LOCK => "LOCK",
+            Method::UNLOCK => "UNLOCK",
         };
         str.to_string()
     }
@@ -174,13 +376,15 @@ pub enum Version {
 }
 
 impl Version {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(version: &str) -> Result<Version, HttpError> {
         match version {
             "HTTP/1.0" => Ok(Version::Http1_0),
             "HTTP/1.1" => Ok(Version::Http1_1),
             "HTTP/2.0" => Ok(Version::Http2_0),
             "HTTP/3.0" => Ok(Version::Http3_0),
-            _ => Err(HttpError::new(HttpErrorKind::ParseError, "Error parsing version", None)),
+            version if version.starts_with("HTTP/") => Err(HttpError::new(HttpErrorKind::ParseError, "Unsupported HTTP version", Some(505))),
+            _ => Err(HttpError::new(HttpErrorKind::ParseError, "Error parsing version", Some(400))),
         }
     }
     pub fn to_str(version: Version) -> String {
@@ -193,7 +397,58 @@ impl Version {
         str.to_string()
     }
 }
-type StatusCode = (u16, String);
+pub type StatusCode = (u16, String);
+
+pub fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Content",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        507 => "Insufficient Storage",
+        _ => "Unknown",
+    }
+}
+
+pub fn status_code(code: u16) -> StatusCode {
+    (code, reason_phrase(code).to_string())
+}
 
 //Inspirations: https://tokio.rs/tokio/tutorial/framing
 #[derive(Debug, Clone)]
@@ -212,6 +467,12 @@ pub enum HttpFrame {
     BodyChunk {
         chunk: Vec<u8>,
     },
+    // `length` bytes of `file` starting at `offset`, sent without loading them into memory.
+    FileBody {
+        file: Arc<std::fs::File>,
+        offset: u64,
+        length: u64,
+    },
 }
 
 impl HttpFrame {
@@ -221,12 +482,49 @@ impl HttpFrame {
             _ => {panic!("No uri found for frame")},
         }
     }
+    pub fn set_uri(&mut self, new_uri: String) {
+        match self {
+            HttpFrame::RequestHead { uri, .. } => *uri = new_uri,
+            _ => panic!("No uri found for frame"),
+        }
+    }
     pub fn get_method(&self) -> Method {
         match self {
             HttpFrame::RequestHead { method, .. } => method.clone(),
             _ => panic!("No method found for frame"),
         }
     }
+    pub fn get_headers(&self) -> &HeaderMap {
+        match self {
+            HttpFrame::RequestHead { headers, .. } => headers,
+            HttpFrame::ResponseHead { headers, .. } => headers,
+            _ => panic!("No headers found for frame"),
+        }
+    }
+    // The cookies sent with a request.
+    pub fn cookies(&self) -> CookieJar {
+        CookieJar::from_request(self)
+    }
+    // Add a Set-Cookie field to a response head. Cookies that cannot be sent as they
+    // are, or frames other than a response head, fail with 500.
+    pub fn add_cookie(&mut self, cookie: &Cookie) -> Result<(), HttpError> {
+        cookie.validate()?;
+        match self {
+            HttpFrame::ResponseHead { headers, .. } => {
+                headers.map.entry("Set-Cookie".to_string()).or_default().push(cookie.to_string());
+                Ok(())
+            },
+            _ => Err(HttpError::from_status(500, "Cookies can only be set on a response head")),
+        }
+    }
+    // Number of body bytes a BodyChunk or FileBody frame carries.
+    pub fn body_length(&self) -> Option<u64> {
+        match self {
+            HttpFrame::BodyChunk { chunk } => Some(chunk.len() as u64),
+            HttpFrame::FileBody { length, .. } => Some(*length),
+            _ => None,
+        }
+    }
     fn line_from_stream(data: &mut impl Iterator<Item = u8>) -> Result<Vec<u8>, HttpError> {
         let mut line: Vec<u8> = Vec::new();
         let mut found_carriage_return = false;
@@ -239,11 +537,15 @@ impl HttpFrame {
                 _ => (),
             }
         }
-        if found_carriage_return && line.last() == Some(&&b'\n') {
+        if found_carriage_return && line.last() == Some(&b'\n') {
             return Ok(line);
         }
+        // Nothing to answer when the peer closed the connection without sending anything
+        if line.is_empty() {
+            return Err(HttpError::new(HttpErrorKind::IOError, "Connection closed", None));
+        }
         println!("Error in parsing request line - No CRLF found");
-        Err(HttpError::new(HttpErrorKind::ParseError, "Error parsing message", None))
+        Err(HttpError::new(HttpErrorKind::ParseError, "Error parsing message", Some(400)))
     }
 
     fn process_request_line(mut tokens: SplitWhitespace) -> Result<(String, Version), HttpError> {
@@ -275,7 +577,8 @@ impl HttpFrame {
                 return Err(HttpError::new(HttpErrorKind::ResponseError, "Parse Error", None));
             }
         };
-        let status = str.parse::<u16>().unwrap();
+        let status = str.parse::<u16>()
+            .map_err(|_| HttpError::new(HttpErrorKind::ResponseError, "Parse Error", None))?;
         let reason = tokens.collect::<String>();
         Ok((status, reason))
     }
@@ -287,54 +590,68 @@ impl HttpFrame {
             if line == b"\r\n" {
                 break;
             }
-            let mut parts = std::str::from_utf8(&line).unwrap().splitn(2, ':');
-
-            let key = parts.next().unwrap().trim().to_string();
-            let values = parts
-                                        .next()
-                                        .unwrap()
-                                        .trim()
-                                        .to_string()
-                                        .split(",")
-                                        .map(|s| s.trim().to_string())
-                                        .collect::<Vec<String>>();
-            headers.map.insert(key, values);
+            let line = std::str::from_utf8(&line).map_err(|_| HttpError::bad_request("Header field is not valid UTF-8"))?;
+            let (key, value) = line.split_once(':').ok_or_else(|| HttpError::bad_request("Malformed header field"))?;
+
+            let key = key.trim().to_string();
+            let value = value.trim();
+            // Cookie pairs are separated by ';' and Set-Cookie's Expires contains a comma
+            let values = if UNSPLIT_HEADERS.iter().any(|name| key.eq_ignore_ascii_case(name)) {
+                vec![value.to_string()]
+            } else {
+                value.split(',').map(|s| s.trim().to_string()).collect::<Vec<String>>()
+            };
+            // Repeated field lines add to the list rather than replace it
+            match headers.map.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case(&key)) {
+                Some(_) if SINGLETON_HEADERS.iter().any(|name| key.eq_ignore_ascii_case(name)) => {
+                    return Err(HttpError::bad_request(&format!("Repeated {} header", key)));
+                },
+                Some((_, existing)) => existing.extend(values),
+                None => {
+                    headers.map.insert(key, values);
+                },
+            }
         }
         Ok(headers)
     }
 
     fn message_frame_from_stream(data: &mut impl Iterator<Item = u8>) -> Result<HttpFrame, HttpError> {
-        let line = String::from_utf8(HttpFrame::line_from_stream(data)?).unwrap();
+        let line = String::from_utf8(HttpFrame::line_from_stream(data)?)
+            .map_err(|_| HttpError::bad_request("Request line is not valid UTF-8"))?;
         let mut tokens =  line.split_whitespace();
 
         let str: &str = match tokens.next() {
             Some(str) => str,
             None => {
                 println!("Error in parsing request line - No method found");
-                return Err(HttpError::new(HttpErrorKind::ParseError, "Bad Request", None));
+                return Err(HttpError::new(HttpErrorKind::ParseError, "Malformed request line", Some(400)));
             },
         };
 
         match str {
-            "GET" | "POST" | "PUT" | "DELETE" | "HEAD" | "OPTIONS" | "CONNECT" | "TRACE" => {
+            method if Method::from_string(method).is_ok() => {
                 let (uri, version) = HttpFrame::process_request_line(tokens)?;
-                return Ok(HttpFrame::RequestHead {
+                Ok(HttpFrame::RequestHead {
                     method: Method::from_string(str)?,
-                    uri: uri,
-                    version: version,
+                    uri,
+                    version,
                     headers: HttpFrame::process_msg_headers(data)?,
-                });
+                })
             },
             "HTTP/1.0" | "HTTP/1.1" | "HTTP/2.0" | "HTTP/3.0" => {
                 let version = Version::from_str(str)?;
                 let status = HttpFrame::process_status_line(tokens)?;
-                return Ok(HttpFrame::ResponseHead {
-                    version: version,
+                Ok(HttpFrame::ResponseHead {
+                    version,
                     status: (status.0, status.1),
                     headers: HttpFrame::process_msg_headers(data)?,
-                });
+                })
+            },
+            // A well-formed request line with a method the server does not know
+            _ => match HttpFrame::process_request_line(tokens) {
+                Ok(_) => Err(HttpError::new(HttpErrorKind::RequestError, "Method not implemented", Some(501))),
+                Err(_) => Err(HttpError::new(HttpErrorKind::ParseError, "Malformed request line", Some(400))),
             },
-            _ => return Err(HttpError::new(HttpErrorKind::ParseError,"Bad Request", None)),
         }
     }
 
@@ -347,26 +664,39 @@ impl HttpFrame {
                                             uri,
                                             Version::to_str(version)
                                         ).as_bytes());
-                for (key, values) in headers.map.iter() {
-                    data.extend(format!("{}: {}\r\n", key, values.join(", ")).as_bytes());
-                }
-                data.extend(b"\r\n");
+                HttpFrame::write_headers(&mut data, &headers);
             },
             HttpFrame::ResponseHead { version, status, headers } => {
                 data.extend(format!("{} {} {}\r\n", Version::to_str(version), status.0, status.1).as_bytes());
 
-                for (key, values) in headers.map.iter() {
-                    data.extend(format!("{}: {}\r\n", key, values.join(", ")).as_bytes());
-                }
-                data.extend(b"\r\n");
+                HttpFrame::write_headers(&mut data, &headers);
             },
             HttpFrame::BodyChunk { chunk } => {
                 data.extend(chunk);
             },
+            HttpFrame::FileBody { file, offset, length } => {
+                data.extend(file_body::read(&file, offset, length)?);
+            },
         }
         Ok(data)
     }
 
+    fn write_headers(data: &mut Vec<u8>, headers: &HeaderMap) {
+        for (key, values) in headers.map.iter() {
+            if key.eq_ignore_ascii_case("Set-Cookie") {
+                // Set-Cookie values cannot be combined into one line
+                for value in values.iter() {
+                    data.extend(format!("{}: {}\r\n", key, value).as_bytes());
+                }
+            } else if key.eq_ignore_ascii_case("Cookie") {
+                data.extend(format!("{}: {}\r\n", key, values.join("; ")).as_bytes());
+            } else {
+                data.extend(format!("{}: {}\r\n", key, values.join(", ")).as_bytes());
+            }
+        }
+        data.extend(b"\r\n");
+    }
+
     pub fn body_frame_from_stream(length: u32, mut data: impl Iterator<Item = u8>) -> Result<HttpFrame, HttpError> {
         let mut body = HttpFrame::BodyChunk { chunk: Vec::new() };
 
@@ -387,16 +717,20 @@ impl HttpFrame {
         Ok(body)
     }
 
+    // The Content-Length of a message, 0 when it has none.
+    fn content_length(headers: &HeaderMap) -> Result<u32, HttpError> {
+        match headers.get("Content-Length") {
+            Some(values) => values.join(",").parse::<u32>().map_err(|_| HttpError::bad_request("Invalid Content-Length")),
+            None => Ok(0),
+        }
+    }
+
     pub fn from_stream(data: &mut impl Iterator<Item = u8>) -> Result<Vec<HttpFrame>, HttpError> {
         let mut frames: Vec<HttpFrame> = Vec::new();
         let frame = HttpFrame::message_frame_from_stream(data)?;
         let content_length:u32 = match frame {
-            HttpFrame::RequestHead { ref headers, .. } => {
-                headers.map.get("Content-Length").unwrap_or(&vec![0.to_string()])[0].parse::<u32>().unwrap()
-            },
-            HttpFrame::ResponseHead { ref headers, .. } => {
-                headers.map.get("Content-Length").unwrap_or(&vec![0.to_string()])[0].parse::<u32>().unwrap()
-            },
+            HttpFrame::RequestHead { ref headers, .. } => HttpFrame::content_length(headers)?,
+            HttpFrame::ResponseHead { ref headers, .. } => HttpFrame::content_length(headers)?,
             _ => 0,
         };
 
@@ -413,10 +747,10 @@ impl HttpFrame {
        let mut data: Vec<u8>;
 
         if frames.len() > 1 {
-            let mut chunk = match frames.pop().unwrap() {
-                HttpFrame::BodyChunk { chunk } => chunk,
-                _ => unreachable!(),
-            };
+            let mut chunk = Vec::new();
+            for body in frames.split_off(1) {
+                chunk.extend(HttpFrame::frame_to_stream(body)?);
+            }
             //Update the content length in the headers
             let mut message = frames.pop().unwrap();
 
@@ -425,18 +759,6 @@ impl HttpFrame {
                 HttpFrame::ResponseHead { ref mut headers, .. } => headers,
                 _ => unreachable!(),
             };
-            if headers.map.contains_key("Content-Encoding") {
-                let encoding = headers.map.get("Content-Encoding").unwrap().get(0).unwrap();
-                if encoding == "gzip" {
-                    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-                    encoder.write_all(&chunk).unwrap();
-                    chunk = encoder.finish().unwrap();
-                } else if encoding == "deflate" {
-                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
-                    encoder.write_all(&chunk).unwrap();
-                    chunk = encoder.finish().unwrap();
-                }
-            }
             headers.map.insert("Content-Length".to_string(), vec![chunk.len().to_string()]);
             data = HttpFrame::frame_to_stream(message)?;
             data.extend(chunk);
@@ -447,40 +769,38 @@ impl HttpFrame {
     }
 }
 
-struct Route {
-    method: Method,
-    uri: String,
-    handler: Arc<Box<dyn Fn(Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> + 'static + Send + Sync>>,
+struct ServerConfig {
+    listen_address: String,
+    listen_port: i32,
 }
 
-struct RouteConfig {
-    config: Vec<Route>,
+// Details of a handler panic, passed to the hook registered with `HttpServer::on_panic`.
+#[derive(Debug, Clone)]
+pub struct PanicReport {
+    pub method: Method,
+    pub uri: String,
+    pub connection_id: u64,
+    pub message: String,
 }
 
-impl Clone for RouteConfig {
-    fn clone(&self) -> RouteConfig {
-        let mut config: Vec<Route> = Vec::new();
-        for route in self.config.iter() {
-            config.push(Route {
-                method: route.method.clone(),
-                uri: route.uri.clone(),
-                handler: route.handler.clone(),
-            });
-        }
-        RouteConfig {
-            config: config,
-        }
-    }
-}
+pub type PanicHook = Arc<dyn Fn(&PanicReport) + Send + Sync>;
 
-struct ServerConfig {
-    listen_address: String,
-    listen_port: i32,
+// Everything a connection thread needs from the server, shared between all of them.
+struct Shared {
+    hosts: VirtualHosts,
+    state: Arc<AppState>,
+    panic_hook: Option<PanicHook>,
+    errors: ErrorResponder,
+    compression: CompressionConfig,
 }
 
 pub struct HttpServer {
     config: ServerConfig,
-    routes: RouteConfig
+    hosts: VirtualHosts,
+    state: AppState,
+    panic_hook: Option<PanicHook>,
+    errors: ErrorResponder,
+    compression: CompressionConfig,
 }
 
 impl HttpServer {
@@ -488,29 +808,88 @@ impl HttpServer {
         HttpServer {
             config : ServerConfig {
                 listen_address: listen_address.to_string(),
-                listen_port: listen_port,
-            },
-            routes: RouteConfig {
-                config: Vec::new(),
+                listen_port,
             },
+            hosts: VirtualHosts::default(),
+            state: AppState::default(),
+            panic_hook: None,
+            errors: ErrorResponder::default(),
+            compression: CompressionConfig::default(),
         }
     }
-    pub fn add_route<F>(&mut self, method: Method, uri: String, handler: F)  -> ()
-        where F: Fn(Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> + 'static + Send + Sync
+    pub fn add_route<F>(&mut self, method: Method, uri: String, handler: F)
+        where F: Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> + 'static + Send + Sync
     {
-        match method {
-            Method::GET => {
-                self.routes.config.push(Route{method: Method::GET, uri: uri, handler: Arc::new(Box::new(handler))});
-            },
-            Method::POST => {
-                self.routes.config.push(Route{method: Method::POST, uri: uri, handler: Arc::new(Box::new(handler))});
-            },
-            _ => {
-                unimplemented!();
-            }
-        }
-        // Sort the resultant vector by uri length
-        self.routes.config.sort_by(|a, b| b.uri.len().cmp(&a.uri.len()));
+        self.hosts.default.add_route(method, uri, handler);
+    }
+
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        self.hosts.default.mount(prefix, router);
+    }
+
+    // Serve requests whose Host header matches `pattern` from `router`. Patterns are
+    // either exact names ("api.example.test") or wildcards ("*.example.test") matching
+    // any subdomain. Requests for other hosts go to the routes added on the server itself.
+    pub fn add_host(&mut self, pattern: &str, router: Router) {
+        self.hosts.add(pattern, router);
+    }
+
+    // Register a value that handlers can read through `RequestContext::state::<T>()`.
+    // Registering a second value of the same type replaces the first.
+    pub fn add_state<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.state.insert(value);
+    }
+
+    // Called whenever a handler panics. The client still gets a 500 response.
+    pub fn on_panic<F>(&mut self, hook: F)
+        where F: Fn(&PanicReport) + 'static + Send + Sync
+    {
+        self.panic_hook = Some(Arc::new(hook));
+    }
+
+    // Render errors generated by the server as RFC 9457 `application/problem+json`
+    // documents (or HTML / plain text, following the client's Accept header) instead of
+    // bare status lines. Errors that carry their own body are sent as they are.
+    pub fn set_problem_details(&mut self, enabled: bool) {
+        self.errors.problem_details = enabled;
+    }
+
+    // Build the response for errors with status `code`, whether they come from a
+    // handler or from the server itself (unmatched route, malformed request, unknown
+    // method, panic). Errors without a status are treated as 500.
+    pub fn on_error<F>(&mut self, code: u16, handler: F)
+        where F: Fn(&HttpError, Option<&HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> + 'static + Send + Sync
+    {
+        self.errors.add_handler(code, Arc::new(handler));
+    }
+
+    // Serve error pages from files named after the status ("404.html") or its class
+    // ("5xx.html") in `dirname`. Handlers registered with `on_error` take precedence.
+    pub fn load_error_pages(&mut self, dirname: &str) -> Result<usize, HttpError> {
+        self.errors.load_pages(dirname)
+    }
+
+    // Response bodies smaller than `min_size` bytes are never compressed.
+    pub fn set_compression_min_size(&mut self, min_size: usize) {
+        self.compression.min_size = min_size;
+    }
+
+    // Add a content coding for response compression, or replace the registered coding
+    // of the same name, e.g. `register_coding(coding::Gzip::new(9))` to change the level.
+    // The server ships br, zstd, gzip and deflate, preferred in that order.
+    pub fn register_coding<C: ContentCoding + 'static>(&mut self, coding: C) {
+        self.compression.codings.register(coding);
+    }
+
+    pub fn remove_coding(&mut self, name: &str) {
+        self.compression.codings.remove(name);
+    }
+
+    // Catch-all handler for requests that no route of the default host matches.
+    pub fn set_fallback<F>(&mut self, handler: F)
+        where F: Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> + 'static + Send + Sync
+    {
+        self.hosts.default.set_fallback(handler);
     }
 
     pub fn listen(&mut self) -> Result<(), HttpError> {
@@ -525,12 +904,16 @@ impl HttpServer {
             }
         };
 
+        let shared = self.shared();
+        let mut connection_id: u64 = 0;
+
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    let config = self.routes.clone();
+                    let shared = shared.clone();
+                    connection_id += 1;
                     std::thread::spawn( move || {
-                        HttpServer::handle_client(stream, config);
+                        HttpServer::handle_client(stream, shared, connection_id);
                     });
                 },
                 Err(e) => {
@@ -541,98 +924,481 @@ impl HttpServer {
         Ok(())
     }
 
-    fn handle_client(stream: std::net::TcpStream, route_cfg: RouteConfig) -> () {
+    fn shared(&self) -> Arc<Shared> {
+        Arc::new(Shared {
+            hosts: self.hosts.clone(),
+            state: Arc::new(self.state.clone()),
+            panic_hook: self.panic_hook.clone(),
+            errors: self.errors.clone(),
+            compression: self.compression.clone(),
+        })
+    }
+
+    fn handle_client(stream: std::net::TcpStream, shared: Arc<Shared>, connection_id: u64) {
+        let mut ctx = RequestContext::new(stream.peer_addr().ok(), stream.local_addr().ok(), connection_id, shared.state.clone(), shared.compression.codings.clone());
         let mut data_stream = DataStream::new(stream);
 
         let frame_buf = match HttpFrame::from_stream(&mut data_stream) {
             Ok(frame_buf) => frame_buf,
             Err(e) => {
-                match e.kind {
-                    HttpErrorKind::RequestError => {
-                        data_stream.write(format!("HTTP/1.1 {} {}\r\n\r\n", e.err_code, e.err_msg).as_bytes()).unwrap();
+                match e.status() {
+                    Some(_) => {
+                        if let Err(e) = HttpServer::write_response(&mut data_stream, shared.errors.respond(None, &e), None) {
+                            println!("Error writing response: {:?}", e);
+                        }
                     },
-                    _ => {
-                        println!("Error reading from stream: {}", e.err_msg);
+                    None => {
+                        println!("Error reading from stream: {}", e.message());
                     }
                 }
                 data_stream.close();
                 return;
             }
         };
-        println!("Received frames: {:?}", frame_buf);
-        match HttpServer::handle_transaction(&mut data_stream, route_cfg, frame_buf){
+        let request = frame_buf[0].clone();
+        // Only the request line; headers and bodies may carry credentials and uploads
+        println!("Received {} {}", Method::to_string(&request.get_method()), request.get_uri());
+        match HttpServer::handle_transaction(&mut data_stream, &shared, &mut ctx, frame_buf){
             Ok(_) => (),
             Err(e) => {
-                match e.kind {
-                    HttpErrorKind::RequestError => {
-                        data_stream.write(format!("HTTP/1.1 {} {}\r\n\r\n", e.err_code, e.err_msg).as_bytes()).unwrap();
-                    },
-                    _ => {
-                        println!("Internal Server Error: {}", e.err_msg);
-                        data_stream.write(b"HTTP/1.1 500 Internal Server Error \r\n\r\n").unwrap();
-                    }
+                if e.status().is_none() {
+                    println!("Internal Server Error: {}", e.message());
+                }
+                if let Err(e) = HttpServer::write_response(&mut data_stream, shared.errors.respond(Some(&request), &e), None) {
+                    println!("Error writing response: {:?}", e);
                 }
                 data_stream.close();
-                ()
             }
         };
     }
 
-    fn process_compression_headers(request: &HttpFrame) -> Result<String, HttpError> {
-        let request_hdrs = match request {
-            HttpFrame::RequestHead { headers, .. } => headers,
-            _ => unreachable!(),
-        };
-        if request_hdrs.map.contains_key("Accept-Encoding") {
-            println!("Compression headers found");
-            let requested_algos = request_hdrs.map.get("Accept-Encoding").unwrap();
-            for encoding in requested_algos.iter() {
-                println!("Encoding: {}", encoding);
-                if encoding == "gzip" || encoding == "deflate" {
-                    println!("Found matching encryption {}", encoding);
-                    return Ok(encoding.clone())
+    // Run a handler, turning a panic into an error so the connection thread survives
+    // and the client still gets a response.
+    fn call_handler(shared: &Shared, handler: Handler, ctx: &mut RequestContext, frames: Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
+        let (method, uri) = (frames[0].get_method(), frames[0].get_uri());
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handler(frames, ctx))) {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                    message.to_string()
+                } else if let Some(message) = payload.downcast_ref::<String>() {
+                    message.clone()
+                } else {
+                    "Box<dyn Any>".to_string()
+                };
+                println!("Handler panicked on {} {}: {}", Method::to_string(&method), uri, message);
+                if let Some(hook) = shared.panic_hook.as_ref() {
+                    hook(&PanicReport { method, uri, connection_id: ctx.connection_id(), message });
                 }
+                Err(HttpError::new(HttpErrorKind::ResponseError, "Handler panicked", None))
+            }
+        }
+    }
+
+    // Write the head, then each body frame in turn so file bodies are streamed
+    // rather than copied into one buffer. With `encoding`, file bodies are encoded on
+    // the way and sent chunked, as their encoded length is unknown up front.
+    fn write_response(data_stream: &mut DataStream, mut response: Vec<HttpFrame>, encoding: Option<&dyn ContentCoding>) -> Result<(), HttpError> {
+        let bodies = response.split_off(1);
+        let mut head = response.pop().unwrap();
+        if let HttpFrame::ResponseHead { ref mut headers, .. } = head {
+            if encoding.is_some() {
+                headers.map.retain(|key, _| !key.eq_ignore_ascii_case("Content-Length"));
+                headers.map.insert("Transfer-Encoding".to_string(), vec!["chunked".to_string()]);
+            } else if !bodies.is_empty() {
+                let length: u64 = bodies.iter().filter_map(|body| body.body_length()).sum();
+                headers.map.insert("Content-Length".to_string(), vec![length.to_string()]);
+            }
+        }
+        data_stream.write_all(&HttpFrame::frame_to_stream(head)?)?;
+        for body in bodies {
+            match (body, encoding) {
+                (HttpFrame::FileBody { file, offset, length }, Some(coding)) => data_stream.send_encoded_file(&file, offset, length, coding)?,
+                (HttpFrame::FileBody { file, offset, length }, None) => data_stream.send_file(&file, offset, length)?,
+                (body, _) => data_stream.write_all(&HttpFrame::frame_to_stream(body)?)?,
             }
         }
-        Err(HttpError::new(HttpErrorKind::RequestError, "No matching compression algorithm", None))
+        Ok(())
     }
 
-    fn handle_transaction(data_stream: &mut DataStream, route_cfg: RouteConfig, frames: Vec<HttpFrame>) -> Result<(), HttpError> {
+    fn handle_transaction(data_stream: &mut DataStream, shared: &Shared, ctx: &mut RequestContext, frames: Vec<HttpFrame>) -> Result<(), HttpError> {
         let request = frames[0].clone();
         let (msg_method, msg_uri) = (request.get_method(), request.get_uri());
 
-        for route in route_cfg.config.iter(){
-            if route.method == msg_method && msg_uri.starts_with(route.uri.as_str()) {
-                let handler = route.handler.clone();
-                match handler(frames) {
-                    Ok(mut response) => {
-                       match HttpServer::process_compression_headers(&request) {
-                            Ok(encoding) => {
-                                let header = match response[0] {
-                                    HttpFrame::ResponseHead { ref mut headers, .. } => headers,
-                                    _ => unreachable!(),
-                                };
-                                header.map.insert("Content-Encoding".to_string(), vec![encoding]);
-                            },
-                            _ => (),
-                        };
-                        let data = HttpFrame::to_stream(response).unwrap();
-                        data_stream.write(&data).unwrap();
-                    },
-                    Err(e) => {
-                        println!("Error processing request: {:?}", e);
-                        data_stream.write(b"HTTP/1.1 500 Internal Server Error\r\n\r\n").unwrap();
+        let host = request.get_headers().get("Host").and_then(|values| values.first());
+        if host.is_none() && matches!(request, HttpFrame::RequestHead { version: Version::Http1_1, .. }) {
+            println!("HTTP/1.1 request without Host header");
+            return Err(HttpError::bad_request("Missing Host header"));
+        }
+        let router = shared.hosts.select(host.map(|host| host.as_str()));
+
+        if let Some(handler) = router.lookup(&msg_method, &msg_uri) {
+            match HttpServer::call_handler(shared, handler, ctx, frames) {
+                Ok(mut response) => {
+                    let encoding = compression::compress_response(&shared.compression, &request, &mut response)?;
+                    if msg_method == Method::HEAD && response.len() > 1 {
+                        // Same headers as the GET response would have, without the content
+                        let length: u64 = response.split_off(1).iter().filter_map(|body| body.body_length()).sum();
+                        if let HttpFrame::ResponseHead { ref mut headers, .. } = response[0] {
+                            headers.map.entry("Content-Length".to_string()).or_insert_with(|| vec![length.to_string()]);
+                        }
                     }
+                    // Part of the response may be out already, so there is no error response to fall back to
+                    if let Err(e) = HttpServer::write_response(data_stream, response, encoding.as_deref()) {
+                        println!("Error writing response: {:?}", e);
+                    }
+                },
+                Err(e) => {
+                    println!("Error processing request: {:?}", e);
+                    return Err(e);
                 }
-                return Ok(());
             }
+            return Ok(());
         }
-        data_stream.write(b"HTTP/1.1 400 Bad Request\r\n\r\n").unwrap();
-        data_stream.close();
-        return Ok(());
+        let allowed = router.allowed_methods(&msg_uri);
+        if !allowed.is_empty() {
+            let allow = allowed.iter().map(Method::to_string).collect::<Vec<String>>().join(", ");
+            return Err(HttpError::method_not_allowed().with_header("Allow", &allow));
+        }
+        Err(HttpError::from_status(404, "No route matches the request"))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    // Send `request` to `server` over a loopback connection and return the raw response.
+    fn exchange(server: &HttpServer, request: &str) -> String {
+        String::from_utf8_lossy(&exchange_bytes(server, request)).into_owned()
+    }
 
+    fn exchange_bytes(server: &HttpServer, request: &str) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        HttpServer::handle_client(stream, server.shared(), 1);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    fn echo(request: Vec<HttpFrame>, _ctx: &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let mut headers = HeaderMap::new();
+        headers.map.insert("Content-Type".to_string(), vec!["text/plain".to_string()]);
+        Ok(vec![
+            HttpFrame::ResponseHead { status: status_code(200), version: Version::Http1_1, headers },
+            HttpFrame::BodyChunk { chunk: request[0].get_uri().into_bytes() },
+        ])
+    }
 
+    #[test]
+    fn known_path_with_another_method_is_405_with_allow() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.add_route(Method::GET, "/echo/".to_string(), echo);
+        let mut files = Router::new();
+        files.add_route(Method::PUT, "/".to_string(), echo);
+        server.mount("/files", files);
 
+        let response = exchange(&server, "DELETE /echo/abc HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+        assert!(response.contains("Allow: GET, HEAD\r\n"), "{}", response);
+
+        let response = exchange(&server, "GET /files/a HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.contains("Allow: PUT\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn unknown_path_is_404() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.add_route(Method::GET, "/echo/".to_string(), echo);
+        let response = exchange(&server, "GET /nothing HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn mounted_handlers_answer_head_without_a_body() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        let mut api = Router::new();
+        api.add_route(Method::GET, "/".to_string(), echo);
+        server.mount("/api", api);
+        let response = exchange(&server, "HEAD /api/users HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Length: 6\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn requests_are_routed_by_host() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.add_route(Method::GET, "/".to_string(), |_request: Vec<HttpFrame>, _ctx: &mut RequestContext| {
+            Ok(vec![HttpFrame::ResponseHead { status: status_code(204), version: Version::Http1_1, headers: HeaderMap::new() }])
+        });
+        let mut api = Router::new();
+        api.add_route(Method::GET, "/".to_string(), echo);
+        server.add_host("api.example.test", api);
+
+        let response = exchange(&server, "GET /v1 HTTP/1.1\r\nHost: API.example.test:4221\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("/v1"), "{}", response);
+        let response = exchange(&server, "GET /v1 HTTP/1.1\r\nHost: www.example.test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn http_1_1_requests_need_a_host() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.add_route(Method::GET, "/".to_string(), echo);
+        let response = exchange(&server, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        let response = exchange(&server, "GET / HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn handlers_see_server_state_and_connection_details() {
+        struct Greeting(&'static str);
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.add_state(Greeting("hello"));
+        server.add_route(Method::GET, "/".to_string(), |_request: Vec<HttpFrame>, ctx: &mut RequestContext| {
+            let greeting = ctx.state::<Greeting>().map_or("missing", |greeting| greeting.0);
+            let peer = ctx.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+            Ok(vec![
+                HttpFrame::ResponseHead { status: status_code(200), version: Version::Http1_1, headers: HeaderMap::new() },
+                HttpFrame::BodyChunk { chunk: format!("{} {} {}", greeting, peer, ctx.connection_id()).into_bytes() },
+            ])
+        });
+        let response = exchange(&server, "GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.ends_with("hello 127.0.0.1 1"), "{}", response);
+    }
+
+    #[test]
+    fn handler_panics_become_500_and_reach_the_hook() {
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.add_route(Method::GET, "/boom".to_string(), |_request: Vec<HttpFrame>, _ctx: &mut RequestContext| -> Result<Vec<HttpFrame>, HttpError> {
+            panic!("boom {}", 42)
+        });
+        server.add_route(Method::GET, "/echo/".to_string(), echo);
+        let seen = reports.clone();
+        server.on_panic(move |report: &PanicReport| seen.lock().unwrap().push(report.clone()));
+
+        let response = exchange(&server, "GET /boom?x=1 HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", response);
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!((reports[0].method.clone(), reports[0].uri.as_str(), reports[0].message.as_str()), (Method::GET, "/boom?x=1", "boom 42"));
+        // The server keeps answering after a panic
+        let response = exchange(&server, "GET /echo/ok HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn malformed_requests_are_400() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.add_route(Method::POST, "/".to_string(), echo);
+        for request in [
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: abc\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: a\r\nNo colon here\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nshort",
+            "\r\n\r\n",
+        ] {
+            let response = exchange(&server, request);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}: {}", request, response);
+        }
+    }
+
+    #[test]
+    fn non_utf8_header_is_400() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nX-Name: \xff\xfe\r\n\r\n").unwrap();
+        HttpServer::handle_client(stream, HttpServer::new("127.0.0.1", 0).shared(), 1);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn content_length_reads_exactly_that_many_bytes() {
+        let frames = HttpFrame::from_stream(&mut b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef".iter().copied()).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(&frames[1], HttpFrame::BodyChunk { chunk } if chunk == b"abc"));
+        let frames = HttpFrame::from_stream(&mut b"GET / HTTP/1.1\r\n\r\n".iter().copied()).unwrap();
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[test]
+    fn errors_without_a_status_are_sent_as_500() {
+        let error = HttpError::new(HttpErrorKind::IOError, "disk on fire", None);
+        let frames = error.to_frames();
+        assert_eq!(frames.len(), 1);
+        match &frames[0] {
+            HttpFrame::ResponseHead { status, headers, .. } => {
+                assert_eq!(status, &(500, "Internal Server Error".to_string()));
+                assert_eq!(headers.get_joined("Content-Length").as_deref(), Some("0"));
+            },
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn error_headers_and_body_end_up_in_the_response() {
+        let error = HttpError::from_status(429, "Slow down")
+            .with_header("Retry-After", "30")
+            .with_body(b"try later".to_vec());
+        assert!(matches!(error.kind(), HttpErrorKind::RequestError));
+        let frames = error.to_frames();
+        match &frames[0] {
+            HttpFrame::ResponseHead { status, headers, .. } => {
+                assert_eq!(status.0, 429);
+                assert_eq!(headers.get_joined("Retry-After").as_deref(), Some("30"));
+                assert_eq!(headers.get_joined("Content-Length").as_deref(), Some("9"));
+            },
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+        assert!(matches!(&frames[1], HttpFrame::BodyChunk { chunk } if chunk == b"try later"));
+    }
+
+    #[test]
+    fn status_decides_the_error_kind_and_can_be_replaced() {
+        assert!(matches!(HttpError::from_status(503, "Down").kind(), HttpErrorKind::ResponseError));
+        let error = HttpError::not_found().with_status(410);
+        assert_eq!(error.status().map(|status| status.0), Some(410));
+        assert_eq!(error.message(), "Not Found");
+    }
+
+    #[test]
+    fn io_errors_map_to_statuses_and_keep_their_source() {
+        use std::error::Error;
+        let not_found: HttpError = std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into();
+        assert_eq!(not_found.status().map(|status| status.0), Some(404));
+        assert_eq!(not_found.source().map(|source| source.to_string()).as_deref(), Some("gone"));
+        let denied: HttpError = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "no").into();
+        assert_eq!(denied.status().map(|status| status.0), Some(403));
+        let other: HttpError = std::io::Error::new(std::io::ErrorKind::InvalidData, "broken").into();
+        assert!(other.status().is_none());
+        assert_eq!(other.to_string(), "HttpError: I/O Error");
+    }
+
+    #[test]
+    fn unknown_status_codes_have_a_placeholder_reason() {
+        assert_eq!(status_code(418), (418, "Unknown".to_string()));
+        assert_eq!(reason_phrase(413), "Content Too Large");
+    }
+
+    #[test]
+    fn parse_errors_are_rendered_as_problem_details() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.set_problem_details(true);
+        let response = exchange(&server, "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: abc\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: application/problem+json\r\n"), "{}", response);
+        assert!(response.contains("\"detail\":\"Invalid Content-Length\""), "{}", response);
+
+        let response = exchange(&server, "GET / HTTP/9.9\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn route_errors_name_the_request_target() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.set_problem_details(true);
+        let response = exchange(&server, "GET /missing?q=1 HTTP/1.1\r\nHost: a\r\nAccept: application/json\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        assert!(response.contains("\"instance\":\"/missing?q=1\""), "{}", response);
+    }
+
+    #[test]
+    fn unknown_methods_are_501_through_the_error_handlers() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.on_error(501, |error: &HttpError, request: Option<&HttpFrame>| {
+            assert!(request.is_none());
+            Ok(error.clone().with_body(b"not here".to_vec()).to_frames())
+        });
+        let response = exchange(&server, "BREW /pot HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n") && response.ends_with("not here"), "{}", response);
+        let response = exchange(&server, "BREW\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn on_error_replaces_the_response_for_route_errors() {
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.on_error(404, |_error: &HttpError, request: Option<&HttpFrame>| {
+            let uri = request.map(|request| request.get_uri()).unwrap_or_default();
+            Ok(HttpError::not_found().with_body(format!("no {}", uri).into_bytes()).to_frames())
+        });
+        let response = exchange(&server, "GET /gone HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n") && response.ends_with("no /gone"), "{}", response);
+    }
+
+    #[test]
+    fn compressible_files_are_encoded_while_sent_and_others_sent_whole() {
+        let dir = test_util::TempDir::new();
+        let text = "plain text served from disk\n".repeat(5000);
+        dir.write("notes.txt", &text);
+        let image = (0..5000).map(|index| (index % 251) as u8).collect::<Vec<u8>>();
+        dir.write("image.png", &image);
+        let mut server = HttpServer::new("127.0.0.1", 0);
+        server.mount("/files", StaticFiles::new(dir.root()).router());
+        let get = |path: &str| {
+            let request = format!("GET /files/{} HTTP/1.1\r\nHost: test\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n", path);
+            let response = exchange_bytes(&server, &request);
+            let split = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+            (String::from_utf8(response[..split].to_vec()).unwrap(), response[split + 4..].to_vec())
+        };
+
+        let (head, body) = get("notes.txt");
+        assert!(head.contains("Content-Encoding: gzip") && head.contains("Transfer-Encoding: chunked"), "{}", head);
+        assert!(!head.contains("Content-Length"), "{}", head);
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(test_util::dechunk(&body).as_slice()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        // Incompressible types go out as they are on disk, with their length
+        let (head, body) = get("image.png");
+        assert!(!head.contains("Content-Encoding") && !head.contains("Transfer-Encoding"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", image.len())), "{}", head);
+        assert_eq!(body, image);
+
+        // As does everything for clients that do not ask for a coding
+        let response = exchange(&server, "GET /files/notes.txt HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(&format!("Content-Length: {}", text.len())) && !head.contains("Content-Encoding"), "{}", head);
+        assert_eq!(body, text);
+    }
+
+    #[test]
+    fn cookies_are_only_added_to_response_heads_each_on_its_own_line() {
+        let mut request = test_util::request("GET", "/", &[], b"");
+        let error = request[0].add_cookie(&Cookie::new("a", "1")).unwrap_err();
+        assert_eq!(error.status().unwrap().0, 500);
+
+        let mut head = HttpFrame::ResponseHead { status: status_code(200), version: Version::Http1_1, headers: HeaderMap::new() };
+        let mut expiring = Cookie::new("b", "2");
+        expiring.set_expires(std::time::UNIX_EPOCH);
+        head.add_cookie(&Cookie::new("a", "1")).unwrap();
+        head.add_cookie(&expiring).unwrap();
+        assert!(head.add_cookie(&Cookie::new("bad name", "x")).is_err());
+        let data = String::from_utf8(HttpFrame::frame_to_stream(head).unwrap()).unwrap();
+        assert!(data.contains("Set-Cookie: a=1\r\n"), "{}", data);
+        assert!(data.contains("Set-Cookie: b=2; Expires=Thu, 01 Jan 1970 00:00:00 GMT\r\n"), "{}", data);
+    }
+
+    #[test]
+    fn repeated_singleton_headers_are_400_and_others_merged() {
+        let parse = |raw: &str| HttpFrame::from_stream(&mut raw.as_bytes().to_vec().into_iter());
+        for name in ["Host", "content-length", "Content-Type"] {
+            let raw = format!("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\nContent-Type: text/plain\r\n{}: 0\r\n\r\n", name);
+            let error = parse(&raw).unwrap_err();
+            assert_eq!(error.status().unwrap().0, 400, "{}", name);
+        }
+        let request = parse("GET / HTTP/1.1\r\nHost: a\r\nAccept: text/html\r\naccept: application/json, */*\r\n\r\n").unwrap();
+        assert_eq!(request[0].get_headers().get_joined("Accept").as_deref(), Some("text/html, application/json, */*"));
+    }
+}
@@ -0,0 +1,227 @@
+// Directory indexes for `StaticFiles`, as an HTML page or a JSON document.
+use std::{cmp::Ordering, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use serde_json::json;
+
+use crate::{negotiate, percent, FormData, problem::escape_html, status_code, HeaderMap, HttpError, HttpFrame, PathResolver, Version};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Entry {
+    fn kind(&self) -> &'static str {
+        if self.is_dir { "directory" } else { "file" }
+    }
+}
+
+// Sort order requested with "?sort=name|size|modified&order=asc|desc". Directories
+// always come before files.
+fn sort_order(uri: &str) -> (SortKey, bool) {
+    let query = FormData::from_uri(uri).unwrap_or_default();
+    let key = match query.get("sort") {
+        Some("size") => SortKey::Size,
+        Some("modified") => SortKey::Modified,
+        _ => SortKey::Name,
+    };
+    let descending = query.get("order") == Some("desc");
+    (key, descending)
+}
+
+fn read_entries(resolver: &PathResolver, dir: &Path, show_hidden: bool) -> Result<Vec<Entry>, HttpError> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            // Names that are not UTF-8 could not be requested anyway
+            Err(_) => continue,
+        };
+        if !show_hidden && name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        // Leave out symlinks the resolver would refuse to serve
+        if !resolver.contains(&path) {
+            continue;
+        }
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+fn sort_entries(entries: &mut [Entry], key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if descending { order.reverse() } else { order };
+        b.is_dir.cmp(&a.is_dir).then(order)
+    });
+}
+
+fn unix_seconds(time: Option<SystemTime>) -> Option<u64> {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|since| since.as_secs())
+}
+
+fn render_html(title: &str, entries: &[Entry], key: SortKey, descending: bool) -> Vec<u8> {
+    let title = escape_html(title);
+    let mut page = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {}</title></head>\n<body>\n<h1>Index of {}</h1>\n<table>\n<tr>",
+                           title, title);
+    for (label, column) in [("Name", SortKey::Name), ("Size", SortKey::Size), ("Modified", SortKey::Modified)] {
+        // Clicking the current column again flips the order
+        let order = if column == key && !descending { "desc" } else { "asc" };
+        page.push_str(&format!("<th><a href=\"?sort={}&amp;order={}\">{}</a></th>", label.to_lowercase(), order, label));
+    }
+    page.push_str("</tr>\n<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    for entry in entries.iter() {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir { "-".to_string() } else { entry.size.to_string() };
+        let modified = entry.modified.map(httpdate::fmt_http_date).unwrap_or_default();
+        page.push_str(&format!("<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                               escape_html(&percent::encode_path_segment(&entry.name)), suffix,
+                               escape_html(&entry.name), suffix, size, modified));
+    }
+    page.push_str("</table>\n</body>\n</html>\n");
+    page.into_bytes()
+}
+
+fn render_json(title: &str, entries: &[Entry]) -> Vec<u8> {
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| json!({
+            "name": entry.name,
+            "type": entry.kind(),
+            "size": entry.size,
+            "modified": unix_seconds(entry.modified),
+        }))
+        .collect();
+    json!({ "path": title, "entries": entries }).to_string().into_bytes()
+}
+
+// List `dir`, which the request target `uri` resolved to.
+pub(crate) fn render(resolver: &PathResolver, dir: &Path, uri: &str, accept: Option<&Vec<String>>, show_hidden: bool) -> Result<Vec<HttpFrame>, HttpError> {
+    let mut entries = read_entries(resolver, dir, show_hidden)?;
+    let (key, descending) = sort_order(uri);
+    sort_entries(&mut entries, key, descending);
+
+    let path = uri.split(['?', '#']).next().unwrap_or("");
+    let title = percent::decode(path)
+        .and_then(|title| String::from_utf8(title).ok())
+        .unwrap_or_else(|| path.to_string());
+
+    let (content_type, body) = match negotiate::preferred_media_type(accept, &["text/html", "application/json"]) {
+        Some("application/json") => ("application/json", render_json(&title, &entries)),
+        _ => ("text/html; charset=utf-8", render_html(&title, &entries, key, descending)),
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.map.insert("Content-Type".to_string(), vec![content_type.to_string()]);
+    headers.map.insert("Vary".to_string(), vec!["Accept".to_string()]);
+    Ok(vec![
+        HttpFrame::ResponseHead { status: status_code(200), version: Version::Http1_1, headers },
+        HttpFrame::BodyChunk { chunk: body },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{body_text, header, TempDir};
+    use serde_json::Value;
+
+    fn listing_dir() -> TempDir {
+        let dir = TempDir::new();
+        dir.write("b.txt", "12345");
+        dir.write("A.txt", "1");
+        dir.write("zeta/inner.txt", "");
+        dir.write(".hidden", "");
+        dir.write("<odd> & name.txt", "123");
+        dir
+    }
+
+    fn json_listing(dir: &TempDir, uri: &str, show_hidden: bool) -> Value {
+        let accept = vec!["application/json".to_string()];
+        let response = render(&PathResolver::new(dir.root()), Path::new(dir.root()), uri, Some(&accept), show_hidden).unwrap();
+        assert_eq!(header(&response, "Content-Type").as_deref(), Some("application/json"));
+        serde_json::from_str(&body_text(&response)).unwrap()
+    }
+
+    fn names(listing: &Value) -> Vec<String> {
+        listing["entries"].as_array().unwrap().iter().map(|entry| entry["name"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn json_lists_directories_first_then_names_case_insensitively() {
+        let dir = listing_dir();
+        let listing = json_listing(&dir, "/docs%20old/", false);
+        assert_eq!(listing["path"], "/docs old/");
+        assert_eq!(names(&listing), vec!["zeta", "<odd> & name.txt", "A.txt", "b.txt"]);
+        let zeta = &listing["entries"][0];
+        assert_eq!((zeta["type"].as_str(), zeta["size"].as_u64()), (Some("directory"), Some(0)));
+        let b = &listing["entries"][3];
+        assert_eq!((b["type"].as_str(), b["size"].as_u64()), (Some("file"), Some(5)));
+        assert!(b["modified"].as_u64().is_some());
+    }
+
+    #[test]
+    fn hidden_entries_are_listed_only_on_request() {
+        let dir = listing_dir();
+        assert!(!names(&json_listing(&dir, "/", false)).contains(&".hidden".to_string()));
+        assert!(names(&json_listing(&dir, "/", true)).contains(&".hidden".to_string()));
+    }
+
+    #[test]
+    fn sort_parameters_reorder_files_but_keep_directories_first() {
+        let dir = listing_dir();
+        assert_eq!(names(&json_listing(&dir, "/?sort=size&order=desc", false)), vec!["zeta", "b.txt", "<odd> & name.txt", "A.txt"]);
+        assert_eq!(names(&json_listing(&dir, "/?sort=size", false)), vec!["zeta", "A.txt", "<odd> & name.txt", "b.txt"]);
+        assert_eq!(names(&json_listing(&dir, "/?order=desc", false)), vec!["zeta", "b.txt", "A.txt", "<odd> & name.txt"]);
+        assert_eq!(names(&json_listing(&dir, "/?sort=bogus", false)), names(&json_listing(&dir, "/", false)));
+    }
+
+    #[test]
+    fn html_escapes_names_and_encodes_links() {
+        let dir = listing_dir();
+        let response = render(&PathResolver::new(dir.root()), Path::new(dir.root()), "/?sort=name", None, false).unwrap();
+        assert_eq!(header(&response, "Content-Type").as_deref(), Some("text/html; charset=utf-8"));
+        assert_eq!(header(&response, "Vary").as_deref(), Some("Accept"));
+        let page = body_text(&response);
+        // '&' is allowed in a path segment but must be escaped in the attribute
+        assert!(page.contains("<a href=\"%3Codd%3E%20&amp;%20name.txt\">&lt;odd&gt; &amp; name.txt</a>"), "{}", page);
+        assert!(page.contains("<a href=\"zeta/\">zeta/</a>"), "{}", page);
+        // The current column links to the reverse order
+        assert!(page.contains("<a href=\"?sort=name&amp;order=desc\">Name</a>"), "{}", page);
+    }
+
+    #[test]
+    fn symlinks_out_of_the_root_are_left_out() {
+        let outside = TempDir::new();
+        let dir = listing_dir();
+        std::os::unix::fs::symlink(outside.root(), Path::new(dir.root()).join("escape")).unwrap();
+        assert!(!names(&json_listing(&dir, "/", false)).contains(&"escape".to_string()));
+    }
+}
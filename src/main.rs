@@ -1,21 +1,25 @@
-use std::{collections::HashMap, env, fs::OpenOptions, io::Write};
-use http_server_starter_rust::{ HeaderMap, HttpError, HttpFrame, HttpServer, Method, Version };
+use std::{collections::HashMap, env, path::Path};
+use http_server_starter_rust::{ HeaderMap, HttpError, HttpFrame, HttpServer, Method, RequestContext, StaticFiles, TusUploads, Version, WebDav };
 
 
+// Upper bound for uploads after undoing their Content-Encoding
+const MAX_DECODED_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
 
-fn get_serving_directory() -> String {
-    let args: Vec<String> = env::args().collect();
-    for arg in args.iter() {
-        if arg == "--directory" {
-            return args.get(args.iter().position(|x| x == "--directory").unwrap() + 1).unwrap().to_string();
-        }
+// The directory given with --directory, "." when the flag is absent.
+fn get_serving_directory(args: &[String]) -> Result<String, String> {
+    let dirname = match args.iter().position(|arg| arg == "--directory") {
+        Some(index) => args.get(index + 1).ok_or("--directory needs a path")?.to_string(),
+        None => ".".to_string(),
+    };
+    if !Path::new(&dirname).is_dir() {
+        return Err(format!("--directory {}: not a directory", dirname));
     }
-    return ".".to_string();
+    Ok(dirname)
 }
 
-fn handle_default_path(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
+fn handle_default_path(request:Vec<HttpFrame>, _ctx: &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
     println!("Handling default path");
-    let uri = request.get(0).unwrap().get_uri();
+    let uri = request.first().unwrap().get_uri();
     if uri == "/" {
         let response = HttpFrame::ResponseHead {
             status: (200,"OK".to_string()),
@@ -26,18 +30,11 @@ fn handle_default_path(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpErr
         };
         Ok(vec![response])
     } else {
-        let response = HttpFrame::ResponseHead {
-            status: (404,"Not Found".to_string()),
-            version: Version::Http1_1,
-            headers: HeaderMap{
-                map: HashMap::new(),
-            },
-        };
-        Ok(vec![response])
+        Err(HttpError::not_found())
     }
 }
 
-fn handle_user_agent(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
+fn handle_user_agent(request:Vec<HttpFrame>, _ctx: &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
     println!("Handling user-agent");
     let response = HttpFrame::ResponseHead {
         status: (200,"OK".to_string()),
@@ -48,24 +45,21 @@ fn handle_user_agent(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError
                             ])
                         },
     };
-    let headers = match request.get(0).unwrap() {
+    let headers = match request.first().unwrap() {
         HttpFrame::RequestHead { headers, .. } => headers,
         _ => panic!("Invalid request type"),
     };
-    match headers.map.get("User-Agent").unwrap().get(0) {
-        Some(user_agent) => {
-            let response_body = HttpFrame::BodyChunk {
-                chunk: Vec::<u8>::from(user_agent.as_bytes()),
-            };
-            return Ok(vec![response, response_body]);
-        },
-        None => (),
+    if let Some(user_agent) = headers.map.get("User-Agent").unwrap().first() {
+        let response_body = HttpFrame::BodyChunk {
+            chunk: Vec::<u8>::from(user_agent.as_bytes()),
+        };
+        return Ok(vec![response, response_body]);
     };
     Ok(vec![response])
 
 }
 
-fn handle_echo(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
+fn handle_echo(request:Vec<HttpFrame>, _ctx: &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
     println!("Handling echo");
     let response = HttpFrame::ResponseHead{
         status: (200,"OK".to_string()),
@@ -76,7 +70,7 @@ fn handle_echo(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
                             ])
                         },
     };
-    let result = request.get(0).unwrap().get_uri();
+    let result = request.first().unwrap().get_uri();
     let (prefix, remaining) = result.split_at("/echo/".len());
     assert_eq!(prefix, "/echo/");
 
@@ -84,94 +78,38 @@ fn handle_echo(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
     Ok(vec![response, response_body])
 }
 
-fn handle_files_reads(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
-    println!("Handling files reads");
-    let dirname = get_serving_directory();
-    let result = request.get(0).unwrap().get_uri();
-    let (prefix, filename) = result.split_at("/files/".len());
-    assert_eq!(prefix, "/files/");
-
-    std::fs::read(format!("{}/{}",dirname,filename)).map(|content| {
-
-        let response = HttpFrame::ResponseHead {
-            status: (200,"OK".to_string()),
-            version: Version::Http1_1,
-            headers: HeaderMap{
-                map: HashMap::from([
-                                    ("Content-Type".to_string(), vec!["application/octet-stream".to_string()]),
-                                ])
-                            },
-        };
-        let response_body = HttpFrame::BodyChunk {
-            chunk: content,
-        };
-        Ok(vec![response, response_body])
-    }).unwrap_or_else(|_| {
-        let response = HttpFrame::ResponseHead {
-            status: (404,"Not Found".to_string()),
-            version: Version::Http1_1,
-            headers: HeaderMap{
-                map: HashMap::new()
-            }
-        };
-        Ok(vec![response])
-    })
-}
-
-
-fn handle_files_writes(request:Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
-    println!("Handling files writes");
-    let dirname = get_serving_directory();
-    let result = request.get(0).unwrap().get_uri();
-    let (prefix, filename) = result.split_at("/files/".len());
-    assert_eq!(prefix, "/files/");
-
-    let chunk = match request.get(1).unwrap() {
-        HttpFrame::BodyChunk { chunk } => chunk,
-        _ => panic!("Invalid request type"),
-    };
-
-    let mut file = OpenOptions::new().write(true).truncate(true).create(true).open(format!("{}/{}",dirname,filename)).unwrap();
-
-    match file.write(&chunk) {
-        Ok(_) => {
-            let response = HttpFrame::ResponseHead {
-                status: (201,"Created".to_string()),
-                version: Version::Http1_1,
-                headers: HeaderMap{
-                    map: HashMap::new(),
-                }
-            };
-            file.sync_all().unwrap();
-            Ok(vec![response])
-        },
-        Err(e) => {
-            println!("Error {}, Writing file: {}", e, format!("{}/{}",dirname,filename));
-            let response = HttpFrame::ResponseHead {
-                status: (500,"Internal Server Error".to_string()),
-                version: Version::Http1_1,
-                headers: HeaderMap{
-                    map: HashMap::new()
-                }
-            };
-            file.sync_all().unwrap();
-            Ok(vec![response])
-        },
-    }
-}
-
-
 fn main() {
     let listen_addr = "127.0.0.1";
     let listen_port = 4221;
-    let _supported_encoding = vec!("gzip".to_string(), "deflate".to_string());
+    let _supported_encoding = ["gzip".to_string(), "deflate".to_string()];
+    let args: Vec<String> = env::args().collect();
+    let dirname = match get_serving_directory(&args) {
+        Ok(dirname) => dirname,
+        Err(message) => {
+            eprintln!("Error: {}", message);
+            std::process::exit(2);
+        }
+    };
     let mut server = HttpServer::new(listen_addr, listen_port, );
-
-    server.add_route(Method::GET, "/".to_string(), &handle_default_path);
-    server.add_route(Method::GET, "/user-agent".to_string(),&handle_user_agent);
-    server.add_route(Method::GET, "/echo/".to_string(), &handle_echo);
-    server.add_route(Method::GET, "/files/".to_string(), &handle_files_reads);
-    server.add_route(Method::POST, "/files/".to_string(), &handle_files_writes);
+    server.set_problem_details(true);
+    // The echo endpoint must honour Accept-Encoding even for tiny bodies
+    server.set_compression_min_size(0);
+
+    server.add_route(Method::GET, "/".to_string(), handle_default_path);
+    server.add_route(Method::GET, "/user-agent".to_string(),handle_user_agent);
+    server.add_route(Method::GET, "/echo/".to_string(), handle_echo);
+    let mut files = StaticFiles::new(&dirname);
+    files.set_listing(true);
+    files.set_writable(true);
+    files.set_max_upload_size(MAX_DECODED_UPLOAD_SIZE);
+    // All three write to the same directory, so the others honour WebDAV locks
+    let dav = WebDav::new(&dirname);
+    files.set_write_guard(dav.write_guard());
+    let mut uploads = TusUploads::new(&dirname);
+    uploads.set_write_guard(dav.write_guard());
+    server.mount("/files", files.router());
+    server.mount("/dav", dav.router());
+    server.mount("/uploads", uploads.router());
 
     match server.listen() {
         Ok(_) => println!("Server started at http://{}", listen_addr),
@@ -179,3 +117,30 @@ fn main() {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn serving_directory_defaults_to_the_working_directory() {
+        assert_eq!(get_serving_directory(&args(&["server"])), Ok(".".to_string()));
+    }
+
+    #[test]
+    fn serving_directory_comes_from_the_flag() {
+        let dirname = env::temp_dir().to_string_lossy().into_owned();
+        assert_eq!(get_serving_directory(&args(&["server", "--directory", &dirname])), Ok(dirname));
+    }
+
+    #[test]
+    fn serving_directory_must_be_given_and_exist() {
+        assert!(get_serving_directory(&args(&["server", "--directory"])).is_err());
+        assert!(get_serving_directory(&args(&["server", "--directory", "/nonexistent/serving/dir"])).is_err());
+        assert!(get_serving_directory(&args(&["server", "--directory", "Cargo.toml"])).is_err());
+    }
+}
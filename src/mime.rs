@@ -0,0 +1,235 @@
+// Content-Type detection for served files: by extension, or, when sniffing is
+// enabled, by the signature at the start of files that have none.
+use std::{collections::HashMap, io::Read, path::Path};
+
+use crate::HttpError;
+
+const DEFAULT_TYPE: &str = "application/octet-stream";
+
+// How many leading bytes sniffing looks at, enough for every signature below.
+const SNIFF_LENGTH: u64 = 16;
+
+const BUILTIN_TYPES: [(&str, &str); 50] = [
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("log", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("xml", "text/xml"),
+    ("ics", "text/calendar"),
+    ("vtt", "text/vtt"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    ("rtf", "application/rtf"),
+    ("atom", "application/atom+xml"),
+    ("rss", "application/rss+xml"),
+    ("xhtml", "application/xhtml+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("bmp", "image/bmp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+];
+
+// Signatures checked at the start of the file, in order.
+const MAGIC_BYTES: [(&[u8], &str); 10] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"\0asm", "application/wasm"),
+    (b"OggS", "audio/ogg"),
+];
+
+#[derive(Clone, Debug)]
+pub struct MimeTypes {
+    by_extension: HashMap<String, String>,
+    sniffing: bool,
+}
+
+impl MimeTypes {
+    // A table holding only the built-in types.
+    pub fn new() -> MimeTypes {
+        let by_extension = BUILTIN_TYPES
+            .iter()
+            .map(|(extension, mime)| (extension.to_string(), mime.to_string()))
+            .collect();
+        MimeTypes { by_extension, sniffing: false }
+    }
+
+    // Map `extension` (without the dot) to `mime`, replacing any existing entry.
+    pub fn insert(&mut self, extension: &str, mime: &str) {
+        self.by_extension.insert(extension.trim_start_matches('.').to_ascii_lowercase(), mime.to_string());
+    }
+
+    // Look for a known file signature in files without an extension. Off by default,
+    // so such files are served as application/octet-stream.
+    pub fn set_sniffing(&mut self, enabled: bool) {
+        self.sniffing = enabled;
+    }
+
+    // Load overrides from a file in the mime.types format: a type followed by its
+    // extensions on each line, '#' starting a comment. Returns the number of
+    // extensions read.
+    pub fn load(&mut self, filename: &str) -> Result<usize, HttpError> {
+        let content = std::fs::read_to_string(filename)?;
+        let mut count = 0;
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let Some(mime) = fields.next() else {
+                continue;
+            };
+            if !mime.contains('/') {
+                println!("Ignoring mime.types line without a type: {}", line.trim());
+                continue;
+            }
+            for extension in fields {
+                self.insert(extension, mime);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn get(&self, extension: &str) -> Option<&str> {
+        self.by_extension.get(&extension.to_ascii_lowercase()).map(|mime| mime.as_str())
+    }
+
+    // The Content-Type value for the file at `path`, including the charset for text.
+    pub fn content_type(&self, path: &Path) -> String {
+        let mime = match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => self.get(extension).unwrap_or(DEFAULT_TYPE).to_string(),
+            None if self.sniffing => sniff_file(path).to_string(),
+            None => DEFAULT_TYPE.to_string(),
+        };
+        if mime.starts_with("text/") && !mime.contains(';') {
+            format!("{}; charset=utf-8", mime)
+        } else {
+            mime
+        }
+    }
+}
+
+impl Default for MimeTypes {
+    fn default() -> MimeTypes {
+        MimeTypes::new()
+    }
+}
+
+fn sniff_file(path: &Path) -> &'static str {
+    let mut prefix = Vec::new();
+    match std::fs::File::open(path).and_then(|file| file.take(SNIFF_LENGTH).read_to_end(&mut prefix)) {
+        Ok(_) => sniff(&prefix),
+        Err(_) => DEFAULT_TYPE,
+    }
+}
+
+// The type whose signature the leading bytes of a file start with. Anything else,
+// text included, is application/octet-stream.
+pub fn sniff(prefix: &[u8]) -> &'static str {
+    if let Some((_, mime)) = MAGIC_BYTES.iter().find(|(magic, _)| prefix.starts_with(magic)) {
+        return mime;
+    }
+    if prefix.len() >= 12 && &prefix[0..4] == b"RIFF" && &prefix[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    DEFAULT_TYPE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn extensions_are_matched_case_insensitively_with_a_charset_for_text() {
+        let types = MimeTypes::new();
+        assert_eq!(types.content_type(Path::new("/srv/INDEX.HTML")), "text/html; charset=utf-8");
+        assert_eq!(types.content_type(Path::new("/srv/logo.png")), "image/png");
+        assert_eq!(types.content_type(Path::new("/srv/data.json")), "application/json");
+        assert_eq!(types.content_type(Path::new("/srv/archive.unknown")), "application/octet-stream");
+    }
+
+    #[test]
+    fn inserted_types_replace_built_in_ones() {
+        let mut types = MimeTypes::new();
+        types.insert(".JS", "application/javascript");
+        types.insert("md", "text/markdown; charset=iso-8859-1");
+        assert_eq!(types.get("js"), Some("application/javascript"));
+        // An explicit charset is kept as it is
+        assert_eq!(types.content_type(Path::new("notes.md")), "text/markdown; charset=iso-8859-1");
+    }
+
+    #[test]
+    fn mime_types_files_are_loaded_with_comments_and_bad_lines_skipped() {
+        let dir = TempDir::new();
+        let file = dir.write("mime.types", "# comment\ntext/x-rust rs rlib # trailing\n\nnot-a-type foo\napplication/x-thing\n");
+        let mut types = MimeTypes::new();
+        assert_eq!(types.load(file.to_str().unwrap()).unwrap(), 2);
+        assert_eq!(types.get("rs"), Some("text/x-rust"));
+        assert_eq!(types.get("rlib"), Some("text/x-rust"));
+        assert_eq!(types.get("foo"), None);
+        assert!(types.load("/nonexistent/mime.types").is_err());
+    }
+
+    #[test]
+    fn sniff_recognizes_signatures_only() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\nrest"), "image/png");
+        assert_eq!(sniff(b"GIF89a..."), "image/gif");
+        assert_eq!(sniff(b"%PDF-1.7"), "application/pdf");
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVE"), "application/octet-stream");
+        // Text is never guessed, so uploads cannot be turned into HTML
+        assert_eq!(sniff(b"<html><script>"), "application/octet-stream");
+        assert_eq!(sniff(b""), "application/octet-stream");
+    }
+
+    #[test]
+    fn files_without_extension_are_sniffed_only_when_enabled() {
+        let dir = TempDir::new();
+        let image = dir.write("image", b"\x89PNG\r\n\x1a\n0000");
+        let page = dir.write("page", "<!DOCTYPE html>");
+        let mut types = MimeTypes::new();
+        assert_eq!(types.content_type(&image), "application/octet-stream");
+        types.set_sniffing(true);
+        assert_eq!(types.content_type(&image), "image/png");
+        assert_eq!(types.content_type(&page), "application/octet-stream");
+        assert_eq!(types.content_type(&dir.write("empty", "")), "application/octet-stream");
+    }
+}
@@ -0,0 +1,431 @@
+// Incremental multipart/form-data (RFC 7578) parsing. `MultipartParser` is fed the body
+// in pieces of any size and reports parts as a series of events, so file parts can be
+// written out as they arrive instead of being held in memory.
+use crate::{percent, HeaderMap, HttpError, HttpFrame};
+
+// Limits applied while parsing. Exceeding a size limit fails with 413.
+#[derive(Clone, Copy, Debug)]
+pub struct MultipartLimits {
+    // Bytes of content in a single part
+    pub max_part_size: usize,
+    // Bytes of the whole body, boundaries and part headers included
+    pub max_total_size: usize,
+    pub max_parts: usize,
+    // Bytes of the header block of a single part
+    pub max_header_size: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> MultipartLimits {
+        MultipartLimits {
+            max_part_size: 64 * 1024 * 1024,
+            max_total_size: 64 * 1024 * 1024,
+            max_parts: 128,
+            max_header_size: 8 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PartHeaders {
+    // The form field name from Content-Disposition
+    pub name: String,
+    // Present for file parts. Only the last path segment of what the client sent.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub headers: HeaderMap,
+}
+
+#[derive(Debug)]
+pub enum MultipartEvent {
+    PartStart(PartHeaders),
+    // Content of the current part; a part's content may come in any number of pieces
+    Data(Vec<u8>),
+    PartEnd,
+}
+
+// A complete part, as returned by `parse`.
+#[derive(Clone, Debug)]
+pub struct Part {
+    pub headers: PartHeaders,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    // Before the first delimiter
+    Preamble,
+    // After a delimiter, before the "--" or line break that follows it
+    Delimiter,
+    Headers,
+    Body,
+    // After the closing delimiter
+    Epilogue,
+}
+
+pub struct MultipartParser {
+    // "\r\n--" followed by the boundary
+    delimiter: Vec<u8>,
+    limits: MultipartLimits,
+    state: State,
+    buffer: Vec<u8>,
+    total_size: usize,
+    part_size: usize,
+    parts: usize,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn malformed(message: &str) -> HttpError {
+    HttpError::bad_request(message)
+}
+
+// Split a header value into its first item and its `; name=value` parameters, with
+// quoted values unquoted.
+fn parameters(value: &str) -> (String, Vec<(String, String)>) {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let (mut quoted, mut escaped) = (false, false);
+    for c in value.chars() {
+        match c {
+            _ if escaped => {
+                current.push(c);
+                escaped = false;
+            },
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => items.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    items.push(current);
+    let first = items.remove(0).trim().to_string();
+    let params = items
+        .iter()
+        .filter_map(|item| item.split_once('='))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    (first, params)
+}
+
+// Browsers used to send the full client-side path; keep the last segment only.
+fn base_name(filename: &str) -> String {
+    filename.rsplit(['/', '\\']).next().unwrap_or("").to_string()
+}
+
+// The boundary parameter of a multipart/form-data Content-Type. Other media types
+// fail with 415.
+pub fn boundary(content_type: &str) -> Result<String, HttpError> {
+    let (media_type, params) = parameters(content_type);
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(HttpError::from_status(415, "Expected a multipart/form-data body"));
+    }
+    match params.into_iter().find(|(name, _)| name == "boundary") {
+        Some((_, boundary)) if !boundary.is_empty() && boundary.len() <= 70 => Ok(boundary),
+        _ => Err(malformed("Missing or invalid multipart boundary")),
+    }
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str, limits: MultipartLimits) -> MultipartParser {
+        MultipartParser {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            limits,
+            state: State::Preamble,
+            // Lets the first delimiter match without a preceding line break
+            buffer: b"\r\n".to_vec(),
+            total_size: 0,
+            part_size: 0,
+            parts: 0,
+        }
+    }
+
+    // A parser for a request, from its Content-Type header.
+    pub fn from_request(request: &HttpFrame, limits: MultipartLimits) -> Result<MultipartParser, HttpError> {
+        // Boundaries may contain commas, which the header parser split on
+        let content_type = request.get_headers().get("Content-Type").map(|values| values.join(",")).unwrap_or_default();
+        Ok(MultipartParser::new(&boundary(&content_type)?, limits))
+    }
+
+    // Parse the next piece of the body.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<MultipartEvent>, HttpError> {
+        self.total_size += data.len();
+        if self.total_size > self.limits.max_total_size {
+            return Err(HttpError::from_status(413, "Multipart body is too large"));
+        }
+        if self.state == State::Epilogue {
+            return Ok(Vec::new());
+        }
+        self.buffer.extend_from_slice(data);
+
+        let mut events = Vec::new();
+        loop {
+            let progressed = match self.state {
+                State::Preamble => self.skip_preamble(),
+                State::Delimiter => self.after_delimiter()?,
+                State::Headers => self.read_headers(&mut events)?,
+                State::Body => self.read_body(&mut events)?,
+                State::Epilogue => {
+                    self.buffer.clear();
+                    false
+                },
+            };
+            if !progressed {
+                return Ok(events);
+            }
+        }
+    }
+
+    // Check that the body ended with the closing delimiter.
+    pub fn finish(&self) -> Result<(), HttpError> {
+        match self.state {
+            State::Epilogue => Ok(()),
+            _ => Err(malformed("Unexpected end of multipart body")),
+        }
+    }
+
+    fn skip_preamble(&mut self) -> bool {
+        match find(&self.buffer, &self.delimiter) {
+            Some(start) => {
+                self.buffer.drain(..start + self.delimiter.len());
+                self.state = State::Delimiter;
+                true
+            },
+            None => {
+                // Keep what could be the start of a delimiter
+                let keep = self.buffer.len().min(self.delimiter.len() - 1);
+                self.buffer.drain(..self.buffer.len() - keep);
+                false
+            },
+        }
+    }
+
+    fn after_delimiter(&mut self) -> Result<bool, HttpError> {
+        if self.buffer.starts_with(b"--") {
+            self.state = State::Epilogue;
+            return Ok(true);
+        }
+        // Transport padding may follow the delimiter before the line break
+        let Some(end) = find(&self.buffer, b"\r\n") else {
+            if self.buffer.len() > self.limits.max_header_size {
+                return Err(malformed("Malformed multipart delimiter"));
+            }
+            return Ok(false);
+        };
+        if !self.buffer[..end].iter().all(|byte| *byte == b' ' || *byte == b'\t') {
+            return Err(malformed("Malformed multipart delimiter"));
+        }
+        self.buffer.drain(..end + 2);
+        self.state = State::Headers;
+        Ok(true)
+    }
+
+    fn read_headers(&mut self, events: &mut Vec<MultipartEvent>) -> Result<bool, HttpError> {
+        // A part without headers starts with the blank line right away
+        let end = if self.buffer.starts_with(b"\r\n") {
+            Some(0)
+        } else {
+            find(&self.buffer, b"\r\n\r\n").map(|end| end + 2)
+        };
+        let Some(end) = end else {
+            if self.buffer.len() > self.limits.max_header_size {
+                return Err(HttpError::from_status(413, "Multipart part headers are too large"));
+            }
+            return Ok(false);
+        };
+        if end > self.limits.max_header_size {
+            return Err(HttpError::from_status(413, "Multipart part headers are too large"));
+        }
+        self.parts += 1;
+        if self.parts > self.limits.max_parts {
+            return Err(HttpError::from_status(413, "Too many multipart parts"));
+        }
+        let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+        let headers = MultipartParser::parse_headers(&block[..end])?;
+        events.push(MultipartEvent::PartStart(headers));
+        self.part_size = 0;
+        self.state = State::Body;
+        Ok(true)
+    }
+
+    fn parse_headers(block: &[u8]) -> Result<PartHeaders, HttpError> {
+        let text = std::str::from_utf8(block).map_err(|_| malformed("Multipart part headers are not UTF-8"))?;
+        let mut headers = HeaderMap::new();
+        for line in text.split("\r\n").filter(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(|| malformed("Malformed multipart part header"))?;
+            headers.map.entry(name.trim().to_string()).or_default().push(value.trim().to_string());
+        }
+
+        let disposition = headers.get_joined("Content-Disposition").ok_or_else(|| malformed("Multipart part without Content-Disposition"))?;
+        let (kind, params) = parameters(&disposition);
+        if !kind.eq_ignore_ascii_case("form-data") {
+            return Err(malformed("Multipart part is not form-data"));
+        }
+        let param = |key: &str| params.iter().find(|(name, _)| name == key).map(|(_, value)| value.clone());
+        let name = param("name").ok_or_else(|| malformed("Multipart part without a name"))?;
+        // filename* (RFC 5987) wins over the plain parameter
+        let extended = param("filename*").and_then(|value| {
+            let (charset, rest) = value.split_once('\'')?;
+            let (_, encoded) = rest.split_once('\'')?;
+            let decoded = percent::decode(encoded)?;
+            charset.eq_ignore_ascii_case("utf-8").then_some(())?;
+            String::from_utf8(decoded).ok()
+        });
+        let filename = extended.or_else(|| param("filename")).map(|filename| base_name(&filename));
+        let content_type = headers.get_joined("Content-Type");
+        Ok(PartHeaders { name, filename, content_type, headers })
+    }
+
+    fn read_body(&mut self, events: &mut Vec<MultipartEvent>) -> Result<bool, HttpError> {
+        let (content_end, next) = match find(&self.buffer, &self.delimiter) {
+            Some(start) => (start, Some(start + self.delimiter.len())),
+            // Hold back what could be the start of the delimiter
+            None => (self.buffer.len().saturating_sub(self.delimiter.len() - 1), None),
+        };
+        if content_end > 0 {
+            self.part_size += content_end;
+            if self.part_size > self.limits.max_part_size {
+                return Err(HttpError::from_status(413, "Multipart part is too large"));
+            }
+            events.push(MultipartEvent::Data(self.buffer.drain(..content_end).collect()));
+        }
+        match next {
+            Some(next) => {
+                self.buffer.drain(..next - content_end);
+                events.push(MultipartEvent::PartEnd);
+                self.state = State::Delimiter;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+// Parse a whole multipart/form-data request into its parts.
+pub fn parse(request: &[HttpFrame], limits: MultipartLimits) -> Result<Vec<Part>, HttpError> {
+    let mut parser = MultipartParser::from_request(&request[0], limits)?;
+    let body: &[u8] = match request.get(1) {
+        Some(HttpFrame::BodyChunk { chunk }) => chunk,
+        _ => &[],
+    };
+    let mut parts: Vec<Part> = Vec::new();
+    for event in parser.feed(body)? {
+        match event {
+            MultipartEvent::PartStart(headers) => parts.push(Part { headers, data: Vec::new() }),
+            MultipartEvent::Data(data) => {
+                if let Some(part) = parts.last_mut() {
+                    part.data.extend(data);
+                }
+            },
+            MultipartEvent::PartEnd => (),
+        }
+    }
+    parser.finish()?;
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    const BOUNDARY: &str = "XyZ";
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        Hello\r\n--XyZ  \r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"C:\\\\docs\\\\a.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        line one\r\n--X not the delimiter\r\n\r\n--XyZ--\r\nepilogue";
+
+    // Feed `body` in pieces of `size` bytes and collect the complete parts.
+    fn parse_in_pieces(body: &[u8], size: usize, limits: MultipartLimits) -> Result<Vec<Part>, HttpError> {
+        let mut parser = MultipartParser::new(BOUNDARY, limits);
+        let mut parts: Vec<Part> = Vec::new();
+        let mut ended = 0;
+        for piece in body.chunks(size) {
+            for event in parser.feed(piece)? {
+                match event {
+                    MultipartEvent::PartStart(headers) => parts.push(Part { headers, data: Vec::new() }),
+                    MultipartEvent::Data(data) => parts.last_mut().unwrap().data.extend(data),
+                    MultipartEvent::PartEnd => ended += 1,
+                }
+            }
+        }
+        parser.finish()?;
+        assert_eq!(ended, parts.len());
+        Ok(parts)
+    }
+
+    fn error_status(result: Result<Vec<Part>, HttpError>) -> u16 {
+        result.unwrap_err().status().unwrap().0
+    }
+
+    #[test]
+    fn parts_are_the_same_for_every_piece_size() {
+        for size in 1..=BODY.len() {
+            let parts = parse_in_pieces(BODY, size, MultipartLimits::default()).unwrap();
+            assert_eq!(parts.len(), 2, "piece size {}", size);
+            assert_eq!(parts[0].headers.name, "title");
+            assert_eq!(parts[0].headers.filename, None);
+            assert_eq!(parts[0].data, b"Hello");
+            assert_eq!(parts[1].headers.filename.as_deref(), Some("a.txt"));
+            assert_eq!(parts[1].headers.content_type.as_deref(), Some("text/plain"));
+            assert_eq!(parts[1].data, b"line one\r\n--X not the delimiter\r\n");
+        }
+    }
+
+    #[test]
+    fn extended_filenames_win_over_plain_ones() {
+        let body = "--XyZ\r\nContent-Disposition: form-data; name=f; filename=\"plain.txt\"; filename*=UTF-8''caf%C3%A9.txt\r\n\r\n\r\n--XyZ--";
+        let parts = parse_in_pieces(body.as_bytes(), body.len(), MultipartLimits::default()).unwrap();
+        assert_eq!(parts[0].headers.filename.as_deref(), Some("café.txt"));
+        assert_eq!(parts[0].data, b"");
+    }
+
+    #[test]
+    fn size_and_count_limits_are_413() {
+        let limits = MultipartLimits { max_part_size: 8, ..MultipartLimits::default() };
+        assert_eq!(error_status(parse_in_pieces(BODY, 7, limits)), 413);
+        let limits = MultipartLimits { max_total_size: BODY.len() - 1, ..MultipartLimits::default() };
+        assert_eq!(error_status(parse_in_pieces(BODY, 16, limits)), 413);
+        let limits = MultipartLimits { max_parts: 1, ..MultipartLimits::default() };
+        assert_eq!(error_status(parse_in_pieces(BODY, BODY.len(), limits)), 413);
+        let limits = MultipartLimits { max_header_size: 20, ..MultipartLimits::default() };
+        assert_eq!(error_status(parse_in_pieces(BODY, 3, limits)), 413);
+    }
+
+    #[test]
+    fn malformed_bodies_are_400() {
+        let parse = |body: &str| error_status(parse_in_pieces(body.as_bytes(), 5, MultipartLimits::default()));
+        assert_eq!(parse("--XyZ\r\nContent-Disposition: form-data; name=a\r\n\r\ntruncated"), 400);
+        assert_eq!(parse("--XyZ\r\nContent-Type: text/plain\r\n\r\nx\r\n--XyZ--"), 400);
+        assert_eq!(parse("--XyZ\r\nContent-Disposition: attachment; name=a\r\n\r\nx\r\n--XyZ--"), 400);
+        assert_eq!(parse("--XyZ\r\nContent-Disposition: form-data\r\n\r\nx\r\n--XyZ--"), 400);
+        assert_eq!(parse("--XyZ\r\nno colon here\r\n\r\nx\r\n--XyZ--"), 400);
+        assert_eq!(parse("--XyZjunk\r\n\r\nx\r\n--XyZ--"), 400);
+        assert_eq!(parse("no delimiter at all"), 400);
+    }
+
+    #[test]
+    fn boundary_comes_from_a_form_data_content_type() {
+        assert_eq!(boundary("multipart/form-data; boundary=\"a;b\"").unwrap(), "a;b");
+        assert_eq!(boundary("Multipart/Form-Data; charset=utf-8; Boundary=abc").unwrap(), "abc");
+        assert_eq!(boundary("multipart/mixed; boundary=abc").unwrap_err().status().unwrap().0, 415);
+        assert_eq!(boundary("multipart/form-data").unwrap_err().status().unwrap().0, 400);
+        let long = format!("multipart/form-data; boundary={}", "b".repeat(71));
+        assert_eq!(boundary(&long).unwrap_err().status().unwrap().0, 400);
+    }
+
+    #[test]
+    fn whole_requests_are_parsed_with_commas_in_the_boundary() {
+        let body = b"--a,b\r\nContent-Disposition: form-data; name=x\r\n\r\n1\r\n--a,b--\r\n";
+        let request = test_util::request("POST", "/", &[("Content-Type", "multipart/form-data; boundary=\"a,b\"")], body);
+        let parts = parse(&request, MultipartLimits::default()).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].data, b"1");
+        let request = test_util::request("POST", "/", &[("Content-Type", "text/plain")], body);
+        assert_eq!(parse(&request, MultipartLimits::default()).unwrap_err().status().unwrap().0, 415);
+    }
+}
@@ -0,0 +1,143 @@
+// Helpers for the proactive negotiation headers (Accept, Accept-Encoding, ...).
+// Header values reach us already split on commas, one list member per entry.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityItem {
+    pub value: String,
+    pub q: f32,
+}
+
+// Parse list members such as "text/html;level=1;q=0.5" into lowercased values with
+// their weight. Members with an unparsable weight are dropped.
+pub fn parse_quality_list(values: &[String]) -> Vec<QualityItem> {
+    let mut items = Vec::new();
+    for member in values.iter() {
+        let mut params = member.split(';').map(|param| param.trim());
+        let value = match params.next() {
+            Some(value) if !value.is_empty() => value.to_ascii_lowercase(),
+            _ => continue,
+        };
+        let mut q = Some(1.0);
+        for param in params {
+            if let Some((name, weight)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = weight.trim().parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q));
+                }
+            }
+        }
+        if let Some(q) = q {
+            items.push(QualityItem { value, q });
+        }
+    }
+    items
+}
+
+// Weight the client gives to a media type, taking the most specific matching range.
+fn media_type_quality(accepted: &[QualityItem], offer: &str) -> f32 {
+    let offer = offer.to_ascii_lowercase();
+    let main_type = offer.split('/').next().unwrap_or("");
+    let mut best: Option<(u8, f32)> = None;
+    for item in accepted.iter() {
+        let specificity = if item.value == offer {
+            3
+        } else if item.value.strip_suffix("/*") == Some(main_type) {
+            2
+        } else if item.value == "*/*" {
+            1
+        } else {
+            continue;
+        };
+        let better = match best {
+            Some((current, _)) => specificity > current,
+            None => true,
+        };
+        if better {
+            best = Some((specificity, item.q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+// Choose the offer the client prefers according to its Accept header. Ties go to
+// the earlier offer, and a missing header accepts anything.
+pub fn preferred_media_type<'a>(accept: Option<&Vec<String>>, offers: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = accept else {
+        return offers.first().copied();
+    };
+    let accepted = parse_quality_list(accept);
+    let mut best: Option<(&str, f32)> = None;
+    for offer in offers.iter() {
+        let q = media_type_quality(&accepted, offer);
+        let better = match best {
+            Some((_, current)) => q > current,
+            None => true,
+        };
+        if q > 0.0 && better {
+            best = Some((offer, q));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+// Whether the client names `media_type` explicitly and weighs nothing else higher, as
+// browsers do for navigations ("text/html,...,*/*;q=0.8"). A bare "*/*" does not count.
+pub fn prefers_media_type(accept: Option<&Vec<String>>, media_type: &str) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let accepted = parse_quality_list(accept);
+    let media_type = media_type.to_ascii_lowercase();
+    let Some(wanted) = accepted.iter().find(|item| item.value == media_type) else {
+        return false;
+    };
+    wanted.q > 0.0 && accepted.iter().all(|item| item.q <= wanted.q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::list;
+
+    #[test]
+    fn quality_list_is_lowercased_with_default_weight() {
+        let items = parse_quality_list(&list("Text/HTML;level=1, gzip;Q=0.5, br;q=0"));
+        assert_eq!(items, vec![
+            QualityItem { value: "text/html".to_string(), q: 1.0 },
+            QualityItem { value: "gzip".to_string(), q: 0.5 },
+            QualityItem { value: "br".to_string(), q: 0.0 },
+        ]);
+    }
+
+    #[test]
+    fn members_with_invalid_weights_or_no_value_are_dropped() {
+        let items = parse_quality_list(&list("gzip;q=2, br;q=abc, ;q=1, zstd;q=-0.1, deflate"));
+        assert_eq!(items, vec![QualityItem { value: "deflate".to_string(), q: 1.0 }]);
+    }
+
+    #[test]
+    fn most_specific_range_decides_the_weight() {
+        let accept = list("text/*;q=0.3, text/html;q=0.7, */*;q=0.1");
+        assert_eq!(preferred_media_type(Some(&accept), &["text/plain", "text/html"]), Some("text/html"));
+        assert_eq!(preferred_media_type(Some(&accept), &["image/png", "text/plain"]), Some("text/plain"));
+        // text/html;q=0 is refused even though text/* would accept it
+        let accept = list("text/html;q=0, text/*");
+        assert_eq!(preferred_media_type(Some(&accept), &["text/html"]), None);
+    }
+
+    #[test]
+    fn ties_go_to_the_earlier_offer_and_no_header_accepts_anything() {
+        let accept = list("application/json, text/plain");
+        assert_eq!(preferred_media_type(Some(&accept), &["text/plain", "application/json"]), Some("text/plain"));
+        assert_eq!(preferred_media_type(None, &["application/json", "text/plain"]), Some("application/json"));
+        assert_eq!(preferred_media_type(Some(&list("image/png")), &["text/plain"]), None);
+    }
+
+    #[test]
+    fn browsers_prefer_html_but_wildcards_do_not() {
+        let browser = list("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8");
+        assert!(prefers_media_type(Some(&browser), "text/html"));
+        assert!(!prefers_media_type(Some(&list("*/*")), "text/html"));
+        assert!(!prefers_media_type(Some(&list("application/json, text/html;q=0.5")), "text/html"));
+        assert!(!prefers_media_type(None, "text/html"));
+    }
+}
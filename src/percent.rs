@@ -0,0 +1,91 @@
+// Percent-encoding (RFC 3986, section 2.1).
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Decode %XX escapes. Returns None for a truncated or non-hex escape.
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let high = hex_value(*bytes.get(i + 1)?)?;
+            let low = hex_value(*bytes.get(i + 2)?)?;
+            decoded.push(high << 4 | low);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+// Encode a single path segment, keeping the characters RFC 3986 allows there
+// unescaped ("pchar") except for the few that would confuse relative references.
+pub fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~'
+            | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b'=' | b'@' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Encode a name or value for an application/x-www-form-urlencoded string, with spaces
+// as '+'.
+pub fn encode_form_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => encoded.push(byte as char),
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_handles_escapes_of_either_case() {
+        assert_eq!(decode("a%20b%2Fc%2fd").unwrap(), b"a b/c/d");
+        assert_eq!(decode("%E2%82%AC").unwrap(), "\u{20ac}".as_bytes());
+        assert_eq!(decode("plain+text").unwrap(), b"plain+text");
+        assert_eq!(decode("").unwrap(), b"");
+    }
+
+    #[test]
+    fn decode_refuses_truncated_or_invalid_escapes() {
+        for input in ["%", "%4", "abc%", "%zz", "%g0", "%0g"] {
+            assert!(decode(input).is_none(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn path_segments_escape_separators_and_specials() {
+        assert_eq!(encode_path_segment("a b/c?d#e%f"), "a%20b%2Fc%3Fd%23e%25f");
+        assert_eq!(encode_path_segment("report-2024_v1.0~(final)+x@y.pdf"), "report-2024_v1.0~(final)+x@y.pdf");
+        assert_eq!(encode_path_segment("\u{e9}"), "%C3%A9");
+        assert_eq!(decode(&encode_path_segment("a b/c?d")).unwrap(), b"a b/c?d");
+    }
+
+    #[test]
+    fn form_components_use_plus_for_spaces() {
+        assert_eq!(encode_form_component("a b&c=d+e"), "a+b%26c%3Dd%2Be");
+        assert_eq!(encode_form_component("*-._~"), "*-._%7E");
+    }
+}
@@ -0,0 +1,173 @@
+// RFC 9457 problem details for error responses generated by the server.
+use serde_json::{json, Map, Value};
+
+use crate::{negotiate, status_code, HttpError, HttpFrame};
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProblemFields {
+    pub(crate) problem_type: Option<String>,
+    pub(crate) extensions: Map<String, Value>,
+}
+
+const RESERVED_MEMBERS: [&str; 5] = ["type", "title", "status", "detail", "instance"];
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Render `error` as a problem document, or as HTML / plain text when the client's
+// Accept header prefers those. `instance` is the request target, when there is one.
+pub(crate) fn render(error: &HttpError, instance: Option<&str>, accept: Option<&Vec<String>>) -> Vec<HttpFrame> {
+    let (code, title) = error.status().cloned().unwrap_or_else(|| status_code(500));
+    // Errors without an explicit status are internal failures; their message is for the log only.
+    let detail = error.status()
+        .map(|_| error.message().to_string())
+        .filter(|detail| *detail != title);
+
+    let offers = ["application/problem+json", "application/json", "text/html", "text/plain"];
+    let (content_type, body) = match negotiate::preferred_media_type(accept, &offers) {
+        Some("text/html") => {
+            let mut page = format!("<!DOCTYPE html>\n<html>\n<head><title>{} {}</title></head>\n<body>\n<h1>{} {}</h1>\n",
+                                   code, escape_html(&title), code, escape_html(&title));
+            if let Some(detail) = detail.as_ref() {
+                page.push_str(&format!("<p>{}</p>\n", escape_html(detail)));
+            }
+            page.push_str("</body>\n</html>\n");
+            ("text/html; charset=utf-8", page.into_bytes())
+        },
+        Some("text/plain") => {
+            let mut text = format!("{} {}\n", code, title);
+            if let Some(detail) = detail.as_ref() {
+                text.push_str(&format!("{}\n", detail));
+            }
+            ("text/plain; charset=utf-8", text.into_bytes())
+        },
+        _ => {
+            let mut document = Map::new();
+            if let Some(fields) = error.problem() {
+                for (name, value) in fields.extensions.iter() {
+                    if !RESERVED_MEMBERS.contains(&name.as_str()) {
+                        document.insert(name.clone(), value.clone());
+                    }
+                }
+            }
+            let problem_type = error.problem().and_then(|fields| fields.problem_type.clone());
+            document.insert("type".to_string(), json!(problem_type.unwrap_or_else(|| "about:blank".to_string())));
+            document.insert("title".to_string(), json!(title));
+            document.insert("status".to_string(), json!(code));
+            if let Some(detail) = detail {
+                document.insert("detail".to_string(), json!(detail));
+            }
+            if let Some(instance) = instance {
+                document.insert("instance".to_string(), json!(instance));
+            }
+            ("application/problem+json", Value::Object(document).to_string().into_bytes())
+        },
+    };
+
+    error.clone()
+        .with_status(code)
+        .with_header("Content-Type", content_type)
+        .with_body(body)
+        .to_frames()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::list;
+
+    // (status, Content-Type, body) of a rendered error.
+    fn rendered(error: &HttpError, instance: Option<&str>, accept: Option<&Vec<String>>) -> (u16, String, String) {
+        let frames = render(error, instance, accept);
+        let HttpFrame::ResponseHead { status, headers, .. } = &frames[0] else {
+            panic!("expected a response head");
+        };
+        let HttpFrame::BodyChunk { chunk } = &frames[1] else {
+            panic!("expected a body");
+        };
+        (status.0, headers.get_joined("Content-Type").unwrap(), String::from_utf8(chunk.clone()).unwrap())
+    }
+
+    #[test]
+    fn problem_document_has_the_standard_members() {
+        let (code, content_type, body) = rendered(&HttpError::from_status(404, "No such user"), Some("/users/7"), None);
+        assert_eq!((code, content_type.as_str()), (404, "application/problem+json"));
+        let document: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(document, json!({
+            "type": "about:blank",
+            "title": "Not Found",
+            "status": 404,
+            "detail": "No such user",
+            "instance": "/users/7",
+        }));
+    }
+
+    #[test]
+    fn detail_is_left_out_when_it_repeats_the_title() {
+        let (_, _, body) = rendered(&HttpError::not_found(), None, None);
+        let document: Value = serde_json::from_str(&body).unwrap();
+        assert!(document.get("detail").is_none());
+        assert!(document.get("instance").is_none());
+    }
+
+    #[test]
+    fn internal_messages_are_not_disclosed() {
+        let error = HttpError::new(crate::HttpErrorKind::IOError, "password file unreadable", None);
+        let (code, _, body) = rendered(&error, None, None);
+        assert_eq!(code, 500);
+        assert!(!body.contains("password"), "{}", body);
+    }
+
+    #[test]
+    fn extensions_and_type_are_added_but_cannot_replace_standard_members() {
+        let error = HttpError::from_status(403, "Out of credit")
+            .with_problem_type("https://example.test/probs/out-of-credit")
+            .with_extension("balance", 30)
+            .with_extension("status", 200);
+        let (_, _, body) = rendered(&error, None, None);
+        let document: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(document["type"], "https://example.test/probs/out-of-credit");
+        assert_eq!(document["balance"], 30);
+        assert_eq!(document["status"], 403);
+    }
+
+    #[test]
+    fn html_and_text_follow_the_accept_header() {
+        let error = HttpError::bad_request("Bad <input> & more");
+        let (_, content_type, body) = rendered(&error, None, Some(&list("text/html")));
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert!(body.contains("<h1>400 Bad Request</h1>") && body.contains("Bad &lt;input&gt; &amp; more"), "{}", body);
+
+        let (_, content_type, body) = rendered(&error, None, Some(&list("text/plain;q=0.9, application/json;q=0.1")));
+        assert_eq!(content_type, "text/plain; charset=utf-8");
+        assert_eq!(body, "400 Bad Request\nBad <input> & more\n");
+
+        // Nothing acceptable still gets the problem document
+        let (_, content_type, _) = rendered(&error, None, Some(&list("image/png")));
+        assert_eq!(content_type, "application/problem+json");
+    }
+
+    #[test]
+    fn error_headers_are_kept() {
+        let error = HttpError::method_not_allowed().with_header("Allow", "GET, HEAD");
+        let frames = render(&error, None, None);
+        assert_eq!(frames[0].get_headers().get_joined("Allow").as_deref(), Some("GET, HEAD"));
+    }
+
+    #[test]
+    fn escape_html_covers_markup_and_quotes() {
+        assert_eq!(escape_html("<a href=\"x\">'&'</a>"), "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;");
+    }
+}
@@ -0,0 +1,221 @@
+// Byte-range requests (RFC 9110, section 14) for files.
+use std::{fs::File, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{conditional::{self, Validators}, status_code, HeaderMap, HttpError, HttpFrame, Method, Version};
+
+// More ranges than this in one request are answered with the whole file; a long list
+// of tiny or overlapping ranges is more likely abuse than a real client.
+const MAX_RANGES: usize = 16;
+
+// First and last byte position, both inclusive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ByteRange {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, length)
+    }
+}
+
+// Parse a "bytes=..." Range value against a representation of `length` bytes.
+// Returns None when the header is malformed or uses another unit, in which case it is
+// ignored, and an empty list when no range is satisfiable.
+fn parse(value: &str, length: u64) -> Option<Vec<ByteRange>> {
+    let (unit, specs) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()) {
+        let (first, last) = spec.split_once('-')?;
+        let range = if first.is_empty() {
+            // Suffix range: the last N bytes
+            let suffix: u64 = last.parse().ok()?;
+            if suffix == 0 || length == 0 {
+                continue;
+            }
+            ByteRange { start: length.saturating_sub(suffix), end: length - 1 }
+        } else {
+            let start: u64 = first.parse().ok()?;
+            let end = match last {
+                "" => u64::MAX,
+                last => last.parse().ok()?,
+            };
+            if end < start {
+                return None;
+            }
+            if start >= length {
+                continue;
+            }
+            ByteRange { start, end: end.min(length - 1) }
+        };
+        ranges.push(range);
+    }
+    Some(ranges)
+}
+
+// The ranges to answer a GET with, or None to send the whole representation. Fails
+// with 416 when none of the requested ranges overlaps the file.
+pub(crate) fn requested(request: &HttpFrame, current: &Validators, length: u64) -> Result<Option<Vec<ByteRange>>, HttpError> {
+    if request.get_method() != Method::GET {
+        return Ok(None);
+    }
+    let Some(value) = request.get_headers().get_joined("Range") else {
+        return Ok(None);
+    };
+    if !conditional::if_range_matches(request, current) {
+        return Ok(None);
+    }
+    match parse(&value, length) {
+        Some(ranges) if ranges.is_empty() => Err(HttpError::from_status(416, "None of the requested ranges is satisfiable")
+            .with_header("Content-Range", &format!("bytes */{}", length))
+            .with_header("Accept-Ranges", "bytes")),
+        Some(ranges) if ranges.len() <= MAX_RANGES => Ok(Some(ranges)),
+        _ => Ok(None),
+    }
+}
+
+fn boundary(current: &Validators) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+    let tag: u64 = current.etag.bytes().fold(0, |hash, byte| hash.wrapping_mul(31).wrapping_add(byte as u64));
+    format!("{:016x}{:08x}", tag, nanos as u32)
+}
+
+// Build the 206 response for `ranges` of `file`. `headers` are those of the full
+// response; a single range keeps its Content-Type, several are sent as
+// multipart/byteranges with the type repeated in each part.
+pub(crate) fn partial_response(file: Arc<File>, mut headers: HeaderMap, ranges: &[ByteRange], length: u64, current: &Validators) -> Vec<HttpFrame> {
+    let mut frames = Vec::new();
+    if let [range] = ranges {
+        headers.map.insert("Content-Range".to_string(), vec![range.content_range(length)]);
+        frames.push(HttpFrame::FileBody { file, offset: range.start, length: range.len() });
+    } else {
+        let boundary = boundary(current);
+        let content_type = headers.map.remove("Content-Type").and_then(|values| values.first().cloned());
+        for (index, range) in ranges.iter().enumerate() {
+            // The CRLF ending the previous part belongs to this delimiter
+            let mut part = if index == 0 { format!("--{}\r\n", boundary) } else { format!("\r\n--{}\r\n", boundary) };
+            if let Some(content_type) = content_type.as_ref() {
+                part.push_str(&format!("Content-Type: {}\r\n", content_type));
+            }
+            part.push_str(&format!("Content-Range: {}\r\n\r\n", range.content_range(length)));
+            frames.push(HttpFrame::BodyChunk { chunk: part.into_bytes() });
+            frames.push(HttpFrame::FileBody { file: file.clone(), offset: range.start, length: range.len() });
+        }
+        frames.push(HttpFrame::BodyChunk { chunk: format!("\r\n--{}--\r\n", boundary).into_bytes() });
+        headers.map.insert("Content-Type".to_string(), vec![format!("multipart/byteranges; boundary={}", boundary)]);
+    }
+    frames.insert(0, HttpFrame::ResponseHead { status: status_code(206), version: Version::Http1_1, headers });
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::{self, body, body_text, call, header, status, TempDir}, StaticFiles};
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    fn get(dir: &TempDir, headers: &[(&str, &str)]) -> Result<Vec<HttpFrame>, HttpError> {
+        call(&StaticFiles::new(dir.root()).router(), test_util::request("GET", "/data.txt", headers, b""))
+    }
+
+    fn site() -> TempDir {
+        let dir = TempDir::new();
+        dir.write("data.txt", "0123456789");
+        dir
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_length() {
+        assert_eq!(parse("bytes=0-4", 10), Some(vec![range(0, 4)]));
+        assert_eq!(parse("bytes=5-", 10), Some(vec![range(5, 9)]));
+        assert_eq!(parse("bytes=-3", 10), Some(vec![range(7, 9)]));
+        assert_eq!(parse("bytes=-30", 10), Some(vec![range(0, 9)]));
+        assert_eq!(parse("Bytes=8-100, 2-3", 10), Some(vec![range(8, 9), range(2, 3)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_dropped_and_malformed_headers_ignored() {
+        assert_eq!(parse("bytes=10-20, -0", 10), Some(vec![]));
+        assert_eq!(parse("bytes=-5", 0), Some(vec![]));
+        assert_eq!(parse("bytes=5-2", 10), None);
+        assert_eq!(parse("bytes=a-2", 10), None);
+        assert_eq!(parse("bytes=3", 10), None);
+        assert_eq!(parse("items=0-1", 10), None);
+        assert_eq!(parse("0-1", 10), None);
+    }
+
+    #[test]
+    fn single_range_gives_206_with_content_range() {
+        let dir = site();
+        let response = get(&dir, &[("Range", "bytes=2-5")]).unwrap();
+        assert_eq!(status(&response), 206);
+        assert_eq!(header(&response, "Content-Range").as_deref(), Some("bytes 2-5/10"));
+        assert_eq!(header(&response, "Content-Type").as_deref(), Some("text/plain; charset=utf-8"));
+        assert_eq!(body_text(&response), "2345");
+        let response = get(&dir, &[("Range", "bytes=-2")]).unwrap();
+        assert_eq!(body_text(&response), "89");
+    }
+
+    #[test]
+    fn unsatisfiable_range_gives_416_with_the_length() {
+        let error = get(&site(), &[("Range", "bytes=10-")]).unwrap_err();
+        assert_eq!(error.status().unwrap().0, 416);
+        assert_eq!(error.headers().get_joined("Content-Range").as_deref(), Some("bytes */10"));
+    }
+
+    #[test]
+    fn malformed_or_too_many_ranges_give_the_whole_file() {
+        let dir = site();
+        let response = get(&dir, &[("Range", "bytes=5-2")]).unwrap();
+        assert_eq!(status(&response), 200);
+        assert_eq!(body_text(&response), "0123456789");
+        let many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        let response = get(&dir, &[("Range", &format!("bytes={}", many))]).unwrap();
+        assert_eq!(status(&response), 200);
+    }
+
+    #[test]
+    fn several_ranges_are_sent_as_multipart_byteranges() {
+        let response = get(&site(), &[("Range", "bytes=0-1, 8-")]).unwrap();
+        assert_eq!(status(&response), 206);
+        let content_type = header(&response, "Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(body(&response)).unwrap(), expected);
+        assert_eq!(header(&response, "Content-Range"), None);
+    }
+
+    #[test]
+    fn stale_if_range_gives_the_whole_file() {
+        let dir = site();
+        let etag = header(&get(&dir, &[]).unwrap(), "ETag").unwrap();
+        let response = get(&dir, &[("Range", "bytes=0-1"), ("If-Range", &etag)]).unwrap();
+        assert_eq!(status(&response), 206);
+        let response = get(&dir, &[("Range", "bytes=0-1"), ("If-Range", "\"stale\"")]).unwrap();
+        assert_eq!(status(&response), 200);
+        assert_eq!(body_text(&response), "0123456789");
+    }
+
+    #[test]
+    fn range_is_ignored_for_head() {
+        let dir = site();
+        let request = test_util::request("HEAD", "/data.txt", &[("Range", "bytes=0-1")], b"");
+        let response = call(&StaticFiles::new(dir.root()).router(), request).unwrap();
+        assert_eq!(status(&response), 200);
+    }
+}
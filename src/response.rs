@@ -0,0 +1,54 @@
+// Shorthands for building common responses as frames.
+use serde::Serialize;
+
+use crate::{status_code, HeaderMap, HttpError, HttpFrame, Version};
+
+pub struct Response;
+
+impl Response {
+    // A 200 response with `value` serialized as the JSON body.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<HttpFrame>, HttpError> {
+        Response::json_with_status(200, value)
+    }
+
+    pub fn json_with_status<T: Serialize + ?Sized>(status: u16, value: &T) -> Result<Vec<HttpFrame>, HttpError> {
+        let body = serde_json::to_vec(value)
+            .map_err(|e| HttpError::from_status(500, "Could not serialize the response body").with_source(e))?;
+        let mut headers = HeaderMap::new();
+        headers.map.insert("Content-Type".to_string(), vec!["application/json".to_string()]);
+        Ok(vec![
+            HttpFrame::ResponseHead { status: status_code(status), version: Version::Http1_1, headers },
+            HttpFrame::BodyChunk { chunk: body },
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::{body_text, header, status};
+
+    #[test]
+    fn values_are_serialized_as_json() {
+        let response = Response::json(&json!({ "ok": true })).unwrap();
+        assert_eq!(status(&response), 200);
+        assert_eq!(header(&response, "Content-Type").as_deref(), Some("application/json"));
+        assert_eq!(body_text(&response), r#"{"ok":true}"#);
+        // Content-Length is left to the server, which knows about compression
+        assert_eq!(header(&response, "Content-Length"), None);
+        let response = Response::json_with_status(201, &[1, 2]).unwrap();
+        assert_eq!(status(&response), 201);
+        assert_eq!(body_text(&response), "[1,2]");
+    }
+
+    #[test]
+    fn values_that_cannot_be_serialized_are_500() {
+        let value: HashMap<(u8, u8), u8> = HashMap::from([((1, 2), 3)]);
+        let error = Response::json(&value).unwrap_err();
+        assert_eq!(error.status().unwrap().0, 500);
+    }
+}
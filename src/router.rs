@@ -0,0 +1,300 @@
+use std::sync::Arc;
+
+use crate::{HttpError, HttpFrame, Method, RequestContext};
+
+pub type Handler = Arc<dyn Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> + Send + Sync>;
+
+// A middleware receives the request frames, the request context and the next handler
+// in the chain. It may rewrite the request, fill in context extensions, short-circuit
+// with its own response, or post-process whatever `next` returns.
+pub type Next<'a> = &'a dyn Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError>;
+pub type Middleware = Arc<dyn Fn(Vec<HttpFrame>, &mut RequestContext, Next) -> Result<Vec<HttpFrame>, HttpError> + Send + Sync>;
+
+// The request target as received, before any mount prefix was stripped. Mounted
+// handlers find it in the context extensions.
+#[derive(Clone, Debug)]
+pub struct OriginalUri(pub String);
+
+// The prefix the handling router is mounted under, without a trailing slash, recovered
+// by comparing the original request target with the rewritten one. Empty when unmounted.
+pub(crate) fn mount_prefix(request: &HttpFrame, ctx: &RequestContext) -> String {
+    let uri = request.get_uri();
+    let original = ctx.extensions().get::<OriginalUri>().map_or(uri.clone(), |original| original.0.clone());
+    let path = |uri: &str| uri.split(['?', '#']).next().unwrap_or("").to_string();
+    let (rewritten, original) = (path(&uri), path(&original));
+    original.strip_suffix(rewritten.as_str()).unwrap_or(&original).trim_end_matches('/').to_string()
+}
+
+#[derive(Clone)]
+struct Route {
+    method: Method,
+    uri: String,
+    handler: Handler,
+}
+
+#[derive(Clone)]
+struct Mount {
+    prefix: String,
+    router: Router,
+}
+
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    mounts: Vec<Mount>,
+    middleware: Vec<Middleware>,
+    fallback: Option<Handler>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn add_route<F>(&mut self, method: Method, uri: String, handler: F)
+        where F: Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> + 'static + Send + Sync
+    {
+        self.routes.push(Route { method, uri, handler: Arc::new(handler) });
+        // Sort the resultant vector by uri length
+        self.routes.sort_by_key(|route| std::cmp::Reverse(route.uri.len()));
+    }
+
+    // Middleware runs in the order it was added, for every request handled by this
+    // router, including the ones dispatched to nested routers.
+    pub fn add_middleware<F>(&mut self, middleware: F)
+        where F: Fn(Vec<HttpFrame>, &mut RequestContext, Next) -> Result<Vec<HttpFrame>, HttpError> + 'static + Send + Sync
+    {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    // Handler used when a request falls under this router but no route matches.
+    pub fn set_fallback<F>(&mut self, handler: F)
+        where F: Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> + 'static + Send + Sync
+    {
+        self.fallback = Some(Arc::new(handler));
+    }
+
+    // Nest `router` under `prefix`. Handlers of the nested router see the request uri
+    // with the prefix stripped, so "/api/v1/users" reaches them as "/users".
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        let prefix = format!("/{}", prefix.trim_matches('/'));
+        let prefix = if prefix == "/" { String::new() } else { prefix };
+        self.mounts.push(Mount { prefix, router });
+        self.mounts.sort_by_key(|mount| std::cmp::Reverse(mount.prefix.len()));
+    }
+
+    // Resolve the handler for a request, with the middleware of every router on the
+    // way wrapped around it.
+    pub(crate) fn lookup(&self, method: &Method, uri: &str) -> Option<Handler> {
+        for mount in self.mounts.iter() {
+            let Some(rest) = Router::strip_mount_prefix(&mount.prefix, uri) else {
+                continue;
+            };
+            if let Some(inner) = mount.router.lookup(method, &rest) {
+                let prefix = mount.prefix.clone();
+                let handler: Handler = Arc::new(move |mut frames: Vec<HttpFrame>, ctx: &mut RequestContext| {
+                    let uri = frames[0].get_uri();
+                    if !ctx.extensions().contains::<OriginalUri>() {
+                        ctx.extensions_mut().insert(OriginalUri(uri.clone()));
+                    }
+                    if let Some(rest) = Router::strip_mount_prefix(&prefix, &uri) {
+                        frames[0].set_uri(rest);
+                    }
+                    inner(frames, ctx)
+                });
+                return Some(self.wrap(handler));
+            }
+        }
+
+        let find_route = |method: &Method| {
+            self.routes.iter().find(|route| route.method == *method && uri.starts_with(route.uri.as_str()))
+        };
+        // GET routes also answer HEAD requests, unless a HEAD route is registered
+        let route = match find_route(method) {
+            None if *method == Method::HEAD => find_route(&Method::GET),
+            route => route,
+        };
+        if let Some(route) = route {
+            return Some(self.wrap(route.handler.clone()));
+        }
+
+        self.fallback.clone().map(|handler| self.wrap(handler))
+    }
+
+    // Methods of the routes matching `uri`, for the Allow header when `lookup` finds
+    // nothing for the method of the request.
+    pub(crate) fn allowed_methods(&self, uri: &str) -> Vec<Method> {
+        let mut methods = Vec::new();
+        for mount in self.mounts.iter() {
+            if let Some(rest) = Router::strip_mount_prefix(&mount.prefix, uri) {
+                methods.extend(mount.router.allowed_methods(&rest));
+            }
+        }
+        methods.extend(self.routes.iter().filter(|route| uri.starts_with(route.uri.as_str())).map(|route| route.method.clone()));
+        if methods.contains(&Method::GET) {
+            methods.push(Method::HEAD);
+        }
+        let mut unique: Vec<Method> = Vec::new();
+        for method in methods {
+            if !unique.contains(&method) {
+                unique.push(method);
+            }
+        }
+        unique
+    }
+
+    fn wrap(&self, handler: Handler) -> Handler {
+        self.middleware.iter().rev().fold(handler, |next, middleware| {
+            let middleware = middleware.clone();
+            Arc::new(move |frames: Vec<HttpFrame>, ctx: &mut RequestContext| middleware(frames, ctx, &*next))
+        })
+    }
+
+    fn strip_mount_prefix(prefix: &str, uri: &str) -> Option<String> {
+        let rest = uri.strip_prefix(prefix)?;
+        if rest.is_empty() || rest.starts_with('?') {
+            Some(format!("/{}", rest))
+        } else if rest.starts_with('/') {
+            Some(rest.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::context, HeaderMap, Version};
+
+    fn request(method: Method, uri: &str) -> Vec<HttpFrame> {
+        vec![HttpFrame::RequestHead { method, uri: uri.to_string(), version: Version::Http1_1, headers: HeaderMap::new() }]
+    }
+
+    // Answers with "<name> <uri>", the uri being the one the handler saw.
+    fn named(name: &'static str) -> impl Fn(Vec<HttpFrame>, &mut RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        move |request: Vec<HttpFrame>, _ctx: &mut RequestContext| {
+            Ok(vec![HttpFrame::BodyChunk { chunk: format!("{} {}", name, request[0].get_uri()).into_bytes() }])
+        }
+    }
+
+    fn dispatch(router: &Router, method: Method, uri: &str) -> Option<String> {
+        let handler = router.lookup(&method, uri)?;
+        let response = handler(request(method, uri), &mut context()).unwrap();
+        match &response[0] {
+            HttpFrame::BodyChunk { chunk } => Some(String::from_utf8(chunk.clone()).unwrap()),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn longest_route_prefix_wins() {
+        let mut router = Router::new();
+        router.add_route(Method::GET, "/".to_string(), named("root"));
+        router.add_route(Method::GET, "/echo/".to_string(), named("echo"));
+        assert_eq!(dispatch(&router, Method::GET, "/echo/abc").as_deref(), Some("echo /echo/abc"));
+        assert_eq!(dispatch(&router, Method::GET, "/other").as_deref(), Some("root /other"));
+        assert!(dispatch(&router, Method::POST, "/echo/abc").is_none());
+    }
+
+    #[test]
+    fn get_routes_answer_head_unless_a_head_route_exists() {
+        let mut router = Router::new();
+        router.add_route(Method::GET, "/a".to_string(), named("get"));
+        router.add_route(Method::GET, "/b".to_string(), named("get"));
+        router.add_route(Method::HEAD, "/b".to_string(), named("head"));
+        assert_eq!(dispatch(&router, Method::HEAD, "/a").as_deref(), Some("get /a"));
+        assert_eq!(dispatch(&router, Method::HEAD, "/b").as_deref(), Some("head /b"));
+    }
+
+    #[test]
+    fn mounted_routers_see_the_uri_without_their_prefix() {
+        let mut inner = Router::new();
+        inner.add_route(Method::GET, "/users".to_string(), named("users"));
+        inner.add_route(Method::GET, "/".to_string(), named("index"));
+        let mut router = Router::new();
+        router.mount("/api/", inner);
+        assert_eq!(dispatch(&router, Method::GET, "/api/users/7").as_deref(), Some("users /users/7"));
+        assert_eq!(dispatch(&router, Method::GET, "/api").as_deref(), Some("index /"));
+        assert_eq!(dispatch(&router, Method::GET, "/api?page=2").as_deref(), Some("index /?page=2"));
+        // A prefix only matches whole path segments
+        assert!(dispatch(&router, Method::GET, "/apiv2/users").is_none());
+    }
+
+    #[test]
+    fn nested_mounts_strip_every_prefix_and_keep_the_original_uri() {
+        let mut users = Router::new();
+        users.add_route(Method::GET, "/".to_string(), |request: Vec<HttpFrame>, ctx: &mut RequestContext| {
+            let original = ctx.extensions().get::<OriginalUri>().unwrap().0.clone();
+            let prefix = mount_prefix(&request[0], ctx);
+            Ok(vec![HttpFrame::BodyChunk { chunk: format!("{} {} {}", request[0].get_uri(), original, prefix).into_bytes() }])
+        });
+        let mut v1 = Router::new();
+        v1.mount("/users", users);
+        let mut router = Router::new();
+        router.mount("/v1", v1);
+        assert_eq!(dispatch(&router, Method::GET, "/v1/users/7?full=1").as_deref(), Some("/7?full=1 /v1/users/7?full=1 /v1/users"));
+    }
+
+    #[test]
+    fn mount_prefix_is_empty_outside_of_mounts() {
+        let frames = request(Method::GET, "/files/a.txt");
+        assert_eq!(mount_prefix(&frames[0], &context()), "");
+    }
+
+    #[test]
+    fn middleware_runs_outside_in_across_nested_routers() {
+        fn tag(name: &'static str) -> impl Fn(Vec<HttpFrame>, &mut RequestContext, Next) -> Result<Vec<HttpFrame>, HttpError> {
+            move |request: Vec<HttpFrame>, ctx: &mut RequestContext, next: Next| {
+                let mut response = next(request, ctx)?;
+                if let HttpFrame::BodyChunk { chunk } = &mut response[0] {
+                    chunk.extend(format!(" <{}", name).as_bytes());
+                }
+                Ok(response)
+            }
+        }
+        let mut inner = Router::new();
+        inner.add_route(Method::GET, "/".to_string(), named("inner"));
+        inner.add_middleware(tag("inner"));
+        let mut router = Router::new();
+        router.add_middleware(tag("first"));
+        router.add_middleware(tag("second"));
+        router.mount("/in", inner);
+        assert_eq!(dispatch(&router, Method::GET, "/in/x").as_deref(), Some("inner /x <inner <second <first"));
+    }
+
+    #[test]
+    fn middleware_can_short_circuit() {
+        let mut router = Router::new();
+        router.add_route(Method::GET, "/".to_string(), |_request: Vec<HttpFrame>, _ctx: &mut RequestContext| -> Result<Vec<HttpFrame>, HttpError> {
+            panic!("the handler must not run")
+        });
+        router.add_middleware(|_request: Vec<HttpFrame>, _ctx: &mut RequestContext, _next: Next| Err(HttpError::forbidden()));
+        let handler = router.lookup(&Method::GET, "/").unwrap();
+        let error = handler(request(Method::GET, "/"), &mut context()).unwrap_err();
+        assert_eq!(error.status().map(|status| status.0), Some(403));
+    }
+
+    #[test]
+    fn fallback_answers_unmatched_requests_under_its_router() {
+        let mut inner = Router::new();
+        inner.add_route(Method::GET, "/known".to_string(), named("known"));
+        inner.set_fallback(named("fallback"));
+        let mut router = Router::new();
+        router.mount("/app", inner);
+        assert_eq!(dispatch(&router, Method::DELETE, "/app/known").as_deref(), Some("fallback /known"));
+        assert!(dispatch(&router, Method::GET, "/elsewhere").is_none());
+    }
+
+    #[test]
+    fn allowed_methods_lists_matching_routes_once_with_head_for_get() {
+        let mut files = Router::new();
+        files.add_route(Method::GET, "/".to_string(), named("get"));
+        files.add_route(Method::PUT, "/".to_string(), named("put"));
+        let mut router = Router::new();
+        router.add_route(Method::GET, "/files/".to_string(), named("get"));
+        router.mount("/files", files);
+        assert_eq!(router.allowed_methods("/files/a.txt"), vec![Method::GET, Method::PUT, Method::HEAD]);
+        assert!(router.allowed_methods("/nothing").is_empty());
+    }
+}
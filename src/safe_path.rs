@@ -0,0 +1,161 @@
+// Maps request paths onto files below a root directory without letting them escape it.
+use std::path::{Component, Path, PathBuf};
+
+use crate::{percent, HttpError};
+
+#[derive(Clone, Debug)]
+pub struct PathResolver {
+    root: PathBuf,
+    follow_symlinks: bool,
+}
+
+impl PathResolver {
+    pub fn new(root: &str) -> PathResolver {
+        PathResolver {
+            root: PathBuf::from(root),
+            follow_symlinks: false,
+        }
+    }
+
+    // Allow symlinks inside the root to point outside of it.
+    pub fn set_follow_symlinks(&mut self, follow: bool) {
+        self.follow_symlinks = follow;
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Resolve the path part of a request target ("/docs/a%20b.txt?x=1") to a file path
+    // below the root. Escapes are decoded and "." / ".." segments applied; anything
+    // that would leave the root is refused with 403. The file itself need not exist.
+    pub fn resolve(&self, uri_path: &str) -> Result<PathBuf, HttpError> {
+        let raw = uri_path.split(['?', '#']).next().unwrap_or("");
+        let decoded = percent::decode(raw).ok_or_else(|| HttpError::bad_request("Malformed percent-encoding in path"))?;
+        if decoded.contains(&0) {
+            return Err(HttpError::forbidden());
+        }
+        let decoded = String::from_utf8(decoded).map_err(|_| HttpError::not_found())?;
+        // "//etc/passwd" is an absolute path smuggled behind the leading slash
+        if decoded.trim_start_matches('/').len() + 1 < decoded.len() {
+            return Err(HttpError::forbidden());
+        }
+
+        let mut segments: Vec<&str> = Vec::new();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => (),
+                ".." => {
+                    if segments.pop().is_none() {
+                        return Err(HttpError::forbidden());
+                    }
+                },
+                _ => {
+                    // Refuse anything the platform would not treat as a plain name,
+                    // e.g. "C:" prefixes or embedded separators on Windows.
+                    let mut components = Path::new(segment).components();
+                    if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+                        return Err(HttpError::forbidden());
+                    }
+                    segments.push(segment);
+                },
+            }
+        }
+
+        let path = segments.iter().fold(self.root.clone(), |path, segment| path.join(segment));
+        if !self.follow_symlinks {
+            self.check_inside_root(&path)?;
+        }
+        Ok(path)
+    }
+
+    // Whether `path`, e.g. a directory entry found while listing, stays inside the root.
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        self.follow_symlinks || self.check_inside_root(path).is_ok()
+    }
+
+    // Symlinks may still lead out of the root, so compare the canonical form of the
+    // deepest existing ancestor with the canonical root.
+    fn check_inside_root(&self, path: &Path) -> Result<(), HttpError> {
+        let root = self.root.canonicalize()?;
+        let existing = path.ancestors().find(|ancestor| ancestor.symlink_metadata().is_ok()).unwrap_or(&self.root);
+        let real = match existing.canonicalize() {
+            Ok(real) => real,
+            // A dangling symlink; it may be created later pointing anywhere
+            Err(_) => return Err(HttpError::forbidden()),
+        };
+        if real.starts_with(&root) {
+            Ok(())
+        } else {
+            println!("Refusing {}: resolves outside of {}", path.display(), root.display());
+            Err(HttpError::forbidden())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn status(result: Result<PathBuf, HttpError>) -> Option<u16> {
+        result.err().and_then(|error| error.status().map(|status| status.0))
+    }
+
+    #[test]
+    fn paths_are_decoded_and_normalized_below_the_root() {
+        let dir = TempDir::new();
+        let resolver = PathResolver::new(dir.root());
+        let root = Path::new(dir.root());
+        assert_eq!(resolver.resolve("/").unwrap(), root);
+        assert_eq!(resolver.resolve("/docs/a%20b.txt?x=1#top").unwrap(), root.join("docs/a b.txt"));
+        assert_eq!(resolver.resolve("/docs/./old/../new.txt").unwrap(), root.join("docs/new.txt"));
+        assert_eq!(resolver.resolve("/a//b/").unwrap(), root.join("a/b"));
+    }
+
+    #[test]
+    fn traversal_out_of_the_root_is_403() {
+        let dir = TempDir::new();
+        let resolver = PathResolver::new(dir.root());
+        for uri in ["/..", "/../etc/passwd", "/docs/../../etc/passwd", "/%2e%2e/etc/passwd", "/%2E%2E%2Fetc", "//etc/passwd", "/a%00.txt"] {
+            assert_eq!(status(resolver.resolve(uri)), Some(403), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn malformed_paths_are_refused() {
+        let resolver = PathResolver::new("/srv");
+        assert_eq!(status(resolver.resolve("/a%zz")), Some(400));
+        assert_eq!(status(resolver.resolve("/a%2")), Some(400));
+        assert_eq!(status(resolver.resolve("/%ff%fe")), Some(404));
+    }
+
+    #[test]
+    fn symlinks_may_not_lead_out_of_the_root_unless_allowed() {
+        let outside = TempDir::new();
+        outside.write("secret.txt", "secret");
+        let dir = TempDir::new();
+        dir.write("inside.txt", "inside");
+        std::os::unix::fs::symlink(outside.root(), Path::new(dir.root()).join("escape")).unwrap();
+        std::os::unix::fs::symlink("inside.txt", Path::new(dir.root()).join("alias.txt")).unwrap();
+        std::os::unix::fs::symlink("/nonexistent/target", Path::new(dir.root()).join("dangling")).unwrap();
+
+        let mut resolver = PathResolver::new(dir.root());
+        assert_eq!(status(resolver.resolve("/escape/secret.txt")), Some(403));
+        assert_eq!(status(resolver.resolve("/escape/not-there-yet.txt")), Some(403));
+        assert_eq!(status(resolver.resolve("/dangling")), Some(403));
+        assert!(resolver.resolve("/alias.txt").is_ok());
+        assert!(!resolver.contains(&Path::new(dir.root()).join("escape")));
+
+        resolver.set_follow_symlinks(true);
+        assert_eq!(resolver.resolve("/escape/secret.txt").unwrap(), Path::new(dir.root()).join("escape/secret.txt"));
+        assert!(resolver.contains(&Path::new(dir.root()).join("escape")));
+    }
+
+    #[test]
+    fn missing_files_still_resolve() {
+        let dir = TempDir::new();
+        let resolver = PathResolver::new(dir.root());
+        assert_eq!(resolver.resolve("/new/file.txt").unwrap(), Path::new(dir.root()).join("new/file.txt"));
+    }
+}
@@ -0,0 +1,759 @@
+// Serves files below a root directory. Mount the router it builds under a prefix:
+// `server.mount("/files", StaticFiles::new(dirname).router())`.
+use std::{fmt, path::{Path, PathBuf}, sync::Arc};
+
+use serde_json::json;
+
+use crate::{atomic_write::{self, AtomicFile}, compression::{self, decode_request_body}, multipart::MultipartEvent, conditional::{self, Precondition, Validators}, listing, negotiate, range, status_code, HeaderMap, HttpError, HttpFrame, Method, MimeTypes, MultipartLimits, MultipartParser, OriginalUri, PathResolver, RequestContext, Router, Version};
+
+// Default limit for uploaded bodies, after undoing their Content-Encoding.
+const DEFAULT_MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+// Form uploads are parsed in pieces of this size, so file parts are written out as
+// they are found.
+const FORM_UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+// Precompressed siblings we look for, as (content coding, file suffix).
+const PRECOMPRESSED_VARIANTS: [(&str, &str); 3] = [("br", ".br"), ("zstd", ".zst"), ("gzip", ".gz")];
+
+// Checked with the request and the file's path before StaticFiles or TusUploads create,
+// replace or remove a file; an error refuses the write. `WebDav::write_guard` gives one
+// that honours WebDAV locks on the same directory.
+#[derive(Clone)]
+pub struct WriteGuard(Arc<WriteCheck>);
+
+type WriteCheck = dyn Fn(&HttpFrame, &Path) -> Result<(), HttpError> + Send + Sync;
+
+impl WriteGuard {
+    pub fn new<F>(check: F) -> WriteGuard
+        where F: Fn(&HttpFrame, &Path) -> Result<(), HttpError> + 'static + Send + Sync
+    {
+        WriteGuard(Arc::new(check))
+    }
+
+    pub(crate) fn check(&self, request: &HttpFrame, path: &Path) -> Result<(), HttpError> {
+        (self.0)(request, path)
+    }
+}
+
+impl fmt::Debug for WriteGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WriteGuard { .. }")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    resolver: PathResolver,
+    mime_types: MimeTypes,
+    precompressed: bool,
+    listing: bool,
+    show_hidden: bool,
+    index_files: Vec<String>,
+    spa_fallback: Option<String>,
+    writable: bool,
+    create_parent_dirs: bool,
+    max_upload_size: usize,
+    multipart_limits: MultipartLimits,
+    write_guard: Option<WriteGuard>,
+}
+
+impl StaticFiles {
+    pub fn new(root: &str) -> StaticFiles {
+        StaticFiles {
+            resolver: PathResolver::new(root),
+            mime_types: MimeTypes::default(),
+            precompressed: true,
+            listing: false,
+            show_hidden: false,
+            index_files: vec!["index.html".to_string(), "index.htm".to_string()],
+            spa_fallback: None,
+            writable: false,
+            create_parent_dirs: false,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            multipart_limits: MultipartLimits::default(),
+            write_guard: None,
+        }
+    }
+
+    // Replace the extension table used for Content-Type, e.g. with one extended by
+    // `MimeTypes::load("/etc/mime.types")`.
+    pub fn set_mime_types(&mut self, mime_types: MimeTypes) {
+        self.mime_types = mime_types;
+    }
+
+    // Serve "app.js.br", "app.js.zst" or "app.js.gz" in place of "app.js" when the client
+    // accepts that coding, instead of compressing the file on every request.
+    pub fn set_precompressed(&mut self, enabled: bool) {
+        self.precompressed = enabled;
+    }
+
+    // Answer requests for directories with an index of their entries.
+    pub fn set_listing(&mut self, enabled: bool) {
+        self.listing = enabled;
+    }
+
+    // Files served for a directory, tried in order before falling back to a listing.
+    pub fn set_index_files(&mut self, names: &[&str]) {
+        self.index_files = names.iter().map(|name| name.to_string()).collect();
+    }
+
+    // Serve `file` (relative to the root) for paths that do not exist when the client
+    // prefers HTML, so a single-page app can route them itself.
+    pub fn set_spa_fallback(&mut self, file: &str) {
+        self.spa_fallback = Some(file.to_string());
+    }
+
+    // Include entries whose name starts with a dot in directory listings.
+    pub fn set_show_hidden(&mut self, show: bool) {
+        self.show_hidden = show;
+    }
+
+    // Accept POST and PUT to create or replace files and DELETE to remove them.
+    pub fn set_writable(&mut self, enabled: bool) {
+        self.writable = enabled;
+    }
+
+    // Create missing directories on the way to an uploaded file instead of failing with 409.
+    pub fn set_create_parent_dirs(&mut self, enabled: bool) {
+        self.create_parent_dirs = enabled;
+    }
+
+    // Uploads larger than `max_size` bytes once decoded are refused with 413.
+    pub fn set_max_upload_size(&mut self, max_size: usize) {
+        self.max_upload_size = max_size;
+    }
+
+    // Limits for multipart/form-data uploads, on top of the overall upload size limit.
+    pub fn set_multipart_limits(&mut self, limits: MultipartLimits) {
+        self.multipart_limits = limits;
+    }
+
+    // Check every write against `guard` first.
+    pub fn set_write_guard(&mut self, guard: WriteGuard) {
+        self.write_guard = Some(guard);
+    }
+
+    // Serve files through symlinks that point outside of the root.
+    pub fn set_follow_symlinks(&mut self, follow: bool) {
+        self.resolver.set_follow_symlinks(follow);
+    }
+
+    pub(crate) fn resolver(&self) -> &PathResolver {
+        &self.resolver
+    }
+
+    pub(crate) fn mime_types(&self) -> &MimeTypes {
+        &self.mime_types
+    }
+
+    pub(crate) fn max_upload_size(&self) -> usize {
+        self.max_upload_size
+    }
+
+    fn check_write(&self, request: &HttpFrame, path: &Path) -> Result<(), HttpError> {
+        match self.write_guard.as_ref() {
+            Some(guard) => guard.check(request, path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn router(&self) -> Router {
+        let files = Arc::new(self.clone());
+        let mut router = Router::new();
+        let reader = files.clone();
+        router.add_route(Method::GET, "/".to_string(), move |request: Vec<HttpFrame>, ctx: &mut RequestContext| {
+            reader.handle_read(&request[0], ctx)
+        });
+        if self.writable {
+            for method in [Method::POST, Method::PUT] {
+                let files = files.clone();
+                router.add_route(method, "/".to_string(), decode_request_body(self.max_upload_size, move |request: Vec<HttpFrame>, _ctx: &mut RequestContext| {
+                    if StaticFiles::is_form_upload(&request[0]) {
+                        files.handle_form_upload(&request)
+                    } else {
+                        files.handle_write(&request)
+                    }
+                }));
+            }
+            router.add_route(Method::DELETE, "/".to_string(), move |request: Vec<HttpFrame>, _ctx: &mut RequestContext| {
+                files.handle_delete(&request[0])
+            });
+        }
+        router
+    }
+
+    // Pick the precompressed variant of `path` the client prefers, if any exists.
+    // Returns the coding, the variant path and whether variants exist at all.
+    fn precompressed_variant(&self, request: &HttpFrame, path: &Path) -> (Option<(String, PathBuf)>, bool) {
+        let available: Vec<(&str, PathBuf)> = PRECOMPRESSED_VARIANTS
+            .iter()
+            .map(|(coding, suffix)| {
+                let mut variant = path.as_os_str().to_owned();
+                variant.push(suffix);
+                (*coding, PathBuf::from(variant))
+            })
+            .filter(|(_, variant)| variant.is_file())
+            // is_file follows symlinks, which may lead out of the root
+            .filter(|(_, variant)| self.resolver.contains(variant))
+            .collect();
+        if available.is_empty() {
+            return (None, false);
+        }
+        let names: Vec<&str> = available.iter().map(|(coding, _)| *coding).collect();
+        // A client refusing everything is answered by the normal compression path
+        let chosen = match compression::negotiate_coding(request.get_headers().get("Accept-Encoding"), &names) {
+            Ok(Some(coding)) => available.into_iter().find(|(name, _)| *name == coding),
+            _ => None,
+        };
+        (chosen.map(|(coding, variant)| (coding.to_string(), variant)), true)
+    }
+
+    // Redirect to the same target with a trailing slash on the path, so relative links
+    // in the index resolve inside the directory.
+    fn redirect_to_directory(target: &str) -> Vec<HttpFrame> {
+        let (path, query) = match target.find(['?', '#']) {
+            Some(index) => target.split_at(index),
+            None => (target, ""),
+        };
+        let mut headers = HeaderMap::new();
+        headers.map.insert("Location".to_string(), vec![format!("{}/{}", path, query)]);
+        vec![HttpFrame::ResponseHead { status: status_code(301), version: Version::Http1_1, headers }]
+    }
+
+    pub(crate) fn handle_read(&self, request: &HttpFrame, ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let uri = request.get_uri();
+        let mut path = self.resolver.resolve(&uri)?;
+        if path.is_dir() {
+            let target = ctx.extensions().get::<OriginalUri>().map_or(uri.clone(), |original| original.0.clone());
+            if !target.split(['?', '#']).next().unwrap_or("").ends_with('/') {
+                return Ok(StaticFiles::redirect_to_directory(&target));
+            }
+            let index = self.index_files.iter().map(|name| path.join(name)).find(|index| index.is_file() && self.resolver.contains(index));
+            match index {
+                Some(index) => path = index,
+                None if self.listing => {
+                    return listing::render(&self.resolver, &path, &uri, request.get_headers().get("Accept"), self.show_hidden);
+                },
+                None => return Err(HttpError::not_found()),
+            }
+        } else if !path.exists() {
+            match self.spa_fallback.as_ref() {
+                Some(fallback) if negotiate::prefers_media_type(request.get_headers().get("Accept"), "text/html") => {
+                    path = self.resolver.resolve(fallback)?;
+                },
+                _ => return Err(HttpError::not_found()),
+            }
+        }
+        if !path.is_file() {
+            return Err(HttpError::not_found());
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.map.insert("Content-Type".to_string(), vec![self.mime_types.content_type(&path)]);
+        headers.map.insert("Accept-Ranges".to_string(), vec!["bytes".to_string()]);
+
+        let mut source = path.clone();
+        if self.precompressed {
+            let (variant, has_variants) = self.precompressed_variant(request, &path);
+            if has_variants {
+                headers.map.insert("Vary".to_string(), vec!["Accept-Encoding".to_string()]);
+            }
+            if let Some((coding, variant)) = variant {
+                headers.map.insert("Content-Encoding".to_string(), vec![coding]);
+                source = variant;
+            }
+        }
+
+        // Validators and content come from the same open file, even if it is replaced meanwhile
+        let file = Arc::new(std::fs::File::open(&source)?);
+        let metadata = file.metadata()?;
+        let validators = Validators::from_metadata(&metadata);
+        validators.add_headers(&mut headers);
+        if conditional::evaluate(request, Some(&validators))? == Precondition::NotModified {
+            headers.map.remove("Content-Type");
+            headers.map.remove("Content-Encoding");
+            return Ok(vec![HttpFrame::ResponseHead { status: status_code(304), version: Version::Http1_1, headers }]);
+        }
+
+        if let Some(ranges) = range::requested(request, &validators, metadata.len())? {
+            return Ok(range::partial_response(file, headers, &ranges, metadata.len(), &validators));
+        }
+
+        Ok(vec![
+            HttpFrame::ResponseHead { status: status_code(200), version: Version::Http1_1, headers },
+            HttpFrame::FileBody { file, offset: 0, length: metadata.len() },
+        ])
+    }
+
+    // Validators of the file at `path`, if there is one, for If-Match and friends.
+    pub(crate) fn current_validators(path: &Path) -> Result<Option<Validators>, HttpError> {
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Err(HttpError::from_status(409, "The target is a directory")),
+            Ok(metadata) => Ok(Some(Validators::from_metadata(&metadata))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // POST and PUT store the body at the target. PUT answers 201 when it created the
+    // file and 204 when it replaced one; POST always answers 201.
+    pub(crate) fn handle_write(&self, request: &[HttpFrame]) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.resolver.resolve(&request[0].get_uri())?;
+        if path == self.resolver.root() {
+            return Err(HttpError::from_status(409, "The target is a directory"));
+        }
+        self.check_write(&request[0], &path)?;
+        let current = StaticFiles::current_validators(&path)?;
+        conditional::evaluate(&request[0], current.as_ref())?;
+
+        if let Some(parent) = path.parent() {
+            if !parent.is_dir() {
+                if !self.create_parent_dirs {
+                    return Err(HttpError::from_status(409, "The parent directory does not exist"));
+                }
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        let body = match request.get(1) {
+            Some(HttpFrame::BodyChunk { chunk }) => chunk.as_slice(),
+            _ => &[],
+        };
+        atomic_write::write_file(&path, body)?;
+
+        let replaced = current.is_some() && request[0].get_method() == Method::PUT;
+        let mut headers = HeaderMap::new();
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            headers.map.insert("ETag".to_string(), vec![Validators::from_metadata(&metadata).etag]);
+        }
+        let status = if replaced { status_code(204) } else { status_code(201) };
+        Ok(vec![HttpFrame::ResponseHead { status, version: Version::Http1_1, headers }])
+    }
+
+    // Browser forms POST their files as multipart/form-data to a directory.
+    fn is_form_upload(request: &HttpFrame) -> bool {
+        request.get_method() == Method::POST && request.get_headers().get_joined("Content-Type")
+            .is_some_and(|content_type| content_type.trim_start().to_ascii_lowercase().starts_with("multipart/form-data"))
+    }
+
+    // Where a form part named `filename` is saved in `dir`. Names must be a single
+    // path segment and may not replace a directory.
+    fn form_upload_path(&self, request: &HttpFrame, dir: &Path, filename: &str) -> Result<PathBuf, HttpError> {
+        if filename == "." || filename == ".." || filename.contains(['/', '\\']) || filename.chars().any(char::is_control) {
+            return Err(HttpError::bad_request("Invalid filename in form upload"));
+        }
+        let path = dir.join(filename);
+        if !self.resolver.contains(&path) {
+            return Err(HttpError::forbidden());
+        }
+        if path.is_dir() {
+            return Err(HttpError::from_status(409, "A form upload would replace a directory"));
+        }
+        self.check_write(request, &path)?;
+        Ok(path)
+    }
+
+    // Save every file part of a multipart/form-data body into the target directory.
+    // Other fields are ignored. Files only replace existing ones once the whole body
+    // parsed, so a rejected upload leaves the directory as it was.
+    fn handle_form_upload(&self, request: &[HttpFrame]) -> Result<Vec<HttpFrame>, HttpError> {
+        let dir = self.resolver.resolve(&request[0].get_uri())?;
+        if !dir.is_dir() {
+            return Err(HttpError::from_status(409, "Form uploads must target a directory"));
+        }
+        let mut parser = MultipartParser::from_request(&request[0], self.multipart_limits)?;
+        let body = match request.get(1) {
+            Some(HttpFrame::BodyChunk { chunk }) => chunk.as_slice(),
+            _ => &[],
+        };
+
+        // (field name, file name, size, file) for each file part
+        let mut current: Option<(String, String, u64, AtomicFile)> = None;
+        let mut saved = Vec::new();
+        for piece in body.chunks(FORM_UPLOAD_CHUNK_SIZE) {
+            for event in parser.feed(piece)? {
+                match event {
+                    // Browsers send an empty filename for a file input left empty
+                    MultipartEvent::PartStart(headers) => match headers.filename.filter(|filename| !filename.is_empty()) {
+                        Some(filename) => {
+                            let file = AtomicFile::create(&self.form_upload_path(&request[0], &dir, &filename)?)?;
+                            current = Some((headers.name, filename, 0, file));
+                        },
+                        None => current = None,
+                    },
+                    MultipartEvent::Data(data) => {
+                        if let Some((_, _, size, file)) = current.as_mut() {
+                            file.write_all(&data)?;
+                            *size += data.len() as u64;
+                        }
+                    },
+                    MultipartEvent::PartEnd => saved.extend(current.take()),
+                }
+            }
+        }
+        parser.finish()?;
+
+        let mut files = Vec::new();
+        for (field, name, size, file) in saved {
+            file.commit()?;
+            files.push(json!({ "field": field, "name": name, "size": size }));
+        }
+        let mut headers = HeaderMap::new();
+        headers.map.insert("Content-Type".to_string(), vec!["application/json".to_string()]);
+        Ok(vec![
+            HttpFrame::ResponseHead { status: status_code(201), version: Version::Http1_1, headers },
+            HttpFrame::BodyChunk { chunk: json!({ "files": files }).to_string().into_bytes() },
+        ])
+    }
+
+    fn handle_delete(&self, request: &HttpFrame) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.resolver.resolve(&request.get_uri())?;
+        let current = StaticFiles::current_validators(&path)?;
+        if current.is_none() {
+            return Err(HttpError::not_found());
+        }
+        self.check_write(request, &path)?;
+        conditional::evaluate(request, current.as_ref())?;
+        std::fs::remove_file(&path)?;
+        Ok(vec![HttpFrame::ResponseHead { status: status_code(204), version: Version::Http1_1, headers: HeaderMap::new() }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, body_text, call, header, status, TempDir};
+
+    fn get(files: &StaticFiles, uri: &str, headers: &[(&str, &str)]) -> Result<Vec<HttpFrame>, HttpError> {
+        call(&files.router(), test_util::request("GET", uri, headers, b""))
+    }
+
+    fn precompressed_site() -> TempDir {
+        let dir = TempDir::new();
+        dir.write("app.js", "plain");
+        dir.write("app.js.gz", "gzip bytes");
+        dir.write("app.js.br", "br bytes");
+        dir
+    }
+
+    #[test]
+    fn precompressed_variant_is_served_with_the_original_type() {
+        let dir = precompressed_site();
+        let response = get(&StaticFiles::new(dir.root()), "/app.js", &[("Accept-Encoding", "gzip")]).unwrap();
+        assert_eq!(body_text(&response), "gzip bytes");
+        assert_eq!(header(&response, "Content-Encoding").as_deref(), Some("gzip"));
+        assert_eq!(header(&response, "Content-Type").as_deref(), Some("text/javascript; charset=utf-8"));
+        assert_eq!(header(&response, "Vary").as_deref(), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn preferred_variant_follows_weights_then_server_order() {
+        let dir = precompressed_site();
+        let files = StaticFiles::new(dir.root());
+        let response = get(&files, "/app.js", &[("Accept-Encoding", "gzip, br")]).unwrap();
+        assert_eq!(body_text(&response), "br bytes");
+        let response = get(&files, "/app.js", &[("Accept-Encoding", "gzip, br;q=0.5")]).unwrap();
+        assert_eq!(body_text(&response), "gzip bytes");
+    }
+
+    #[test]
+    fn original_is_served_when_no_variant_is_acceptable() {
+        let dir = precompressed_site();
+        let files = StaticFiles::new(dir.root());
+        for headers in [vec![], vec![("Accept-Encoding", "zstd")], vec![("Accept-Encoding", "identity")]] {
+            let response = get(&files, "/app.js", &headers).unwrap();
+            assert_eq!(body_text(&response), "plain");
+            assert!(header(&response, "Content-Encoding").is_none());
+            // Caches still need to know the answer depends on the header
+            assert_eq!(header(&response, "Vary").as_deref(), Some("Accept-Encoding"));
+        }
+    }
+
+    #[test]
+    fn variants_are_ignored_when_disabled_or_missing() {
+        let dir = precompressed_site();
+        dir.write("other.js", "other");
+        let mut files = StaticFiles::new(dir.root());
+        let response = get(&files, "/other.js", &[("Accept-Encoding", "gzip")]).unwrap();
+        assert!(header(&response, "Vary").is_none());
+        files.set_precompressed(false);
+        let response = get(&files, "/app.js", &[("Accept-Encoding", "gzip")]).unwrap();
+        assert_eq!(body_text(&response), "plain");
+        assert!(header(&response, "Vary").is_none());
+        assert_eq!(status(&response), 200);
+    }
+
+    #[test]
+    fn requests_outside_the_root_are_forbidden() {
+        let outside = TempDir::new();
+        let dir = TempDir::new();
+        dir.write("public.txt", "public");
+        std::os::unix::fs::symlink(outside.root(), std::path::Path::new(dir.root()).join("escape")).unwrap();
+        outside.write("secret.txt", "secret");
+        let files = StaticFiles::new(dir.root());
+        for uri in ["/../secret.txt", "/%2e%2e/secret.txt", "/escape/secret.txt"] {
+            assert_eq!(get(&files, uri, &[]).unwrap_err().status().map(|status| status.0), Some(403), "{}", uri);
+        }
+        assert_eq!(body_text(&get(&files, "/public.txt", &[]).unwrap()), "public");
+    }
+
+    #[test]
+    fn revalidation_with_the_etag_gives_304_without_a_body() {
+        let dir = TempDir::new();
+        dir.write("page.html", "<p>hi</p>");
+        let files = StaticFiles::new(dir.root());
+        let response = get(&files, "/page.html", &[]).unwrap();
+        let etag = header(&response, "ETag").unwrap();
+        let last_modified = header(&response, "Last-Modified").unwrap();
+
+        let response = get(&files, "/page.html", &[("If-None-Match", &etag)]).unwrap();
+        assert_eq!(status(&response), 304);
+        assert_eq!(header(&response, "ETag"), Some(etag));
+        assert_eq!(response.len(), 1);
+        let response = get(&files, "/page.html", &[("If-Modified-Since", &last_modified)]).unwrap();
+        assert_eq!(status(&response), 304);
+        let response = get(&files, "/page.html", &[("If-None-Match", "\"stale\"")]).unwrap();
+        assert_eq!(status(&response), 200);
+        assert_eq!(body_text(&response), "<p>hi</p>");
+    }
+
+    #[test]
+    fn failed_if_match_on_a_read_gives_412() {
+        let dir = TempDir::new();
+        dir.write("page.html", "<p>hi</p>");
+        let error = get(&StaticFiles::new(dir.root()), "/page.html", &[("If-Match", "\"stale\"")]).unwrap_err();
+        assert_eq!(error.status().unwrap().0, 412);
+    }
+
+    fn site_with_directories() -> TempDir {
+        let dir = TempDir::new();
+        dir.write("docs/index.htm", "htm index");
+        dir.write("docs/index.html", "html index");
+        dir.write("empty/.keep", "");
+        dir.write("app/index.html", "<div id=app></div>");
+        dir
+    }
+
+    #[test]
+    fn directories_are_answered_with_the_first_index_file() {
+        let dir = site_with_directories();
+        let mut files = StaticFiles::new(dir.root());
+        assert_eq!(body_text(&get(&files, "/docs/", &[]).unwrap()), "html index");
+        files.set_index_files(&["index.htm", "index.html"]);
+        assert_eq!(body_text(&get(&files, "/docs/", &[]).unwrap()), "htm index");
+    }
+
+    #[test]
+    fn directories_without_a_slash_are_redirected_with_the_query() {
+        let dir = site_with_directories();
+        let files = StaticFiles::new(dir.root());
+        let response = get(&files, "/docs?page=2", &[]).unwrap();
+        assert_eq!(status(&response), 301);
+        assert_eq!(header(&response, "Location").as_deref(), Some("/docs/?page=2"));
+        // Mounted routers redirect to the path the client asked for
+        let mut router = Router::new();
+        router.mount("/static", files.router());
+        let response = call(&router, test_util::request("GET", "/static/docs", &[], b"")).unwrap();
+        assert_eq!(header(&response, "Location").as_deref(), Some("/static/docs/"));
+    }
+
+    #[test]
+    fn directories_without_an_index_are_404_unless_listing_is_on() {
+        let dir = site_with_directories();
+        let mut files = StaticFiles::new(dir.root());
+        assert_eq!(get(&files, "/empty/", &[]).unwrap_err().status().unwrap().0, 404);
+        files.set_listing(true);
+        assert_eq!(status(&get(&files, "/empty/", &[]).unwrap()), 200);
+    }
+
+    #[test]
+    fn spa_fallback_is_only_served_to_clients_preferring_html() {
+        let dir = site_with_directories();
+        let mut files = StaticFiles::new(dir.root());
+        let browser = [("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")];
+        assert_eq!(get(&files, "/app/settings", &browser).unwrap_err().status().unwrap().0, 404);
+        files.set_spa_fallback("/app/index.html");
+        let response = get(&files, "/app/settings", &browser).unwrap();
+        assert_eq!(body_text(&response), "<div id=app></div>");
+        assert_eq!(header(&response, "Content-Type").as_deref(), Some("text/html; charset=utf-8"));
+        // Missing assets and API calls keep their 404
+        let error = get(&files, "/app/missing.js", &[("Accept", "*/*")]).unwrap_err();
+        assert_eq!(error.status().unwrap().0, 404);
+        assert_eq!(get(&files, "/app/settings", &[]).unwrap_err().status().unwrap().0, 404);
+    }
+
+    fn writable(dir: &TempDir) -> StaticFiles {
+        let mut files = StaticFiles::new(dir.root());
+        files.set_writable(true);
+        files
+    }
+
+    fn send(files: &StaticFiles, method: &str, uri: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Vec<HttpFrame>, HttpError> {
+        call(&files.router(), test_util::request(method, uri, headers, body))
+    }
+
+    fn error_status(result: Result<Vec<HttpFrame>, HttpError>) -> u16 {
+        result.unwrap_err().status().unwrap().0
+    }
+
+    #[test]
+    fn writes_are_refused_unless_enabled() {
+        let dir = TempDir::new();
+        assert_eq!(error_status(send(&StaticFiles::new(dir.root()), "PUT", "/new.txt", &[], b"data")), 404);
+        assert!(!Path::new(dir.root()).join("new.txt").exists());
+    }
+
+    #[test]
+    fn put_answers_201_when_creating_and_204_when_replacing() {
+        let dir = TempDir::new();
+        let files = writable(&dir);
+        let response = send(&files, "PUT", "/new.txt", &[], b"first").unwrap();
+        assert_eq!(status(&response), 201);
+        let etag = header(&response, "ETag").unwrap();
+        let response = send(&files, "PUT", "/new.txt", &[], b"second").unwrap();
+        assert_eq!(status(&response), 204);
+        assert_ne!(header(&response, "ETag").unwrap(), etag);
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("new.txt")).unwrap(), b"second");
+        // POST always answers 201
+        assert_eq!(status(&send(&files, "POST", "/new.txt", &[], b"third").unwrap()), 201);
+    }
+
+    #[test]
+    fn missing_parents_are_409_unless_created() {
+        let dir = TempDir::new();
+        let mut files = writable(&dir);
+        assert_eq!(error_status(send(&files, "PUT", "/a/b/new.txt", &[], b"data")), 409);
+        files.set_create_parent_dirs(true);
+        assert_eq!(status(&send(&files, "PUT", "/a/b/new.txt", &[], b"data").unwrap()), 201);
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("a/b/new.txt")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn directories_cannot_be_overwritten() {
+        let dir = TempDir::new();
+        dir.write("sub/file.txt", "");
+        let files = writable(&dir);
+        assert_eq!(error_status(send(&files, "PUT", "/sub", &[], b"data")), 409);
+        assert_eq!(error_status(send(&files, "PUT", "/", &[], b"data")), 409);
+        assert_eq!(error_status(send(&files, "PUT", "/../outside.txt", &[], b"data")), 403);
+    }
+
+    #[test]
+    fn writes_honour_preconditions() {
+        let dir = TempDir::new();
+        dir.write("data.txt", "old");
+        let files = writable(&dir);
+        assert_eq!(error_status(send(&files, "PUT", "/data.txt", &[("If-None-Match", "*")], b"new")), 412);
+        assert_eq!(error_status(send(&files, "PUT", "/data.txt", &[("If-Match", "\"stale\"")], b"new")), 412);
+        assert_eq!(error_status(send(&files, "DELETE", "/data.txt", &[("If-Match", "\"stale\"")], b"")), 412);
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("data.txt")).unwrap(), b"old");
+        let etag = header(&get(&files, "/data.txt", &[]).unwrap(), "ETag").unwrap();
+        assert_eq!(status(&send(&files, "PUT", "/data.txt", &[("If-Match", &etag)], b"new").unwrap()), 204);
+    }
+
+    #[test]
+    fn uploads_over_the_size_limit_are_413() {
+        let dir = TempDir::new();
+        let mut files = writable(&dir);
+        files.set_max_upload_size(4);
+        assert_eq!(error_status(send(&files, "PUT", "/big.txt", &[], b"12345")), 413);
+        assert!(!Path::new(dir.root()).join("big.txt").exists());
+    }
+
+    #[test]
+    fn delete_removes_files_and_is_404_for_missing_ones() {
+        let dir = TempDir::new();
+        dir.write("data.txt", "old");
+        let files = writable(&dir);
+        assert_eq!(status(&send(&files, "DELETE", "/data.txt", &[], b"").unwrap()), 204);
+        assert!(!Path::new(dir.root()).join("data.txt").exists());
+        assert_eq!(error_status(send(&files, "DELETE", "/data.txt", &[], b"")), 404);
+    }
+
+    fn form(parts: &[(&str, Option<&str>, &str)]) -> Vec<u8> {
+        let mut body = String::new();
+        for (name, filename, content) in parts {
+            body.push_str("--form\r\nContent-Disposition: form-data; name=\"");
+            body.push_str(name);
+            if let Some(filename) = filename {
+                body.push_str(&format!("\"; filename=\"{}", filename));
+            }
+            body.push_str(&format!("\"\r\n\r\n{}\r\n", content));
+        }
+        body.push_str("--form--\r\n");
+        body.into_bytes()
+    }
+
+    fn upload_form(files: &StaticFiles, uri: &str, body: &[u8]) -> Result<Vec<HttpFrame>, HttpError> {
+        send(files, "POST", uri, &[("Content-Type", "multipart/form-data; boundary=form")], body)
+    }
+
+    #[test]
+    fn form_uploads_save_file_parts_into_the_directory() {
+        let dir = TempDir::new();
+        dir.write("inbox/.keep", "");
+        let files = writable(&dir);
+        let body = form(&[("note", None, "ignored"), ("file", Some("a.txt"), "first"), ("file", Some(""), ""), ("more", Some("b.txt"), "second")]);
+        let response = upload_form(&files, "/inbox/", &body).unwrap();
+        assert_eq!(status(&response), 201);
+        let listed: serde_json::Value = serde_json::from_str(&body_text(&response)).unwrap();
+        assert_eq!(listed, json!({ "files": [
+            { "field": "file", "name": "a.txt", "size": 5 },
+            { "field": "more", "name": "b.txt", "size": 6 },
+        ] }));
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("inbox/a.txt")).unwrap(), b"first");
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("inbox/b.txt")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn rejected_form_uploads_leave_the_directory_unchanged() {
+        let dir = TempDir::new();
+        dir.write("inbox/a.txt", "old");
+        dir.write("inbox/sub/.keep", "");
+        let mut files = writable(&dir);
+        files.set_multipart_limits(MultipartLimits { max_part_size: 4, ..MultipartLimits::default() });
+        let body = form(&[("file", Some("a.txt"), "new"), ("file", Some("c.txt"), "too long")]);
+        assert_eq!(error_status(upload_form(&files, "/inbox/", &body)), 413);
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("inbox/a.txt")).unwrap(), b"old");
+
+        let names = |dir: &TempDir| std::fs::read_dir(Path::new(dir.root()).join("inbox")).unwrap().count();
+        assert_eq!(names(&dir), 2);
+        assert_eq!(error_status(upload_form(&files, "/inbox/", &form(&[("file", Some(".."), "x")]))), 400);
+        assert_eq!(error_status(upload_form(&files, "/inbox/", &form(&[("file", Some("sub"), "x")]))), 409);
+        assert_eq!(error_status(upload_form(&files, "/inbox/a.txt", &form(&[("file", Some("x"), "x")]))), 409);
+        let truncated = b"--form\r\nContent-Disposition: form-data; name=file; filename=x\r\n\r\npartial";
+        assert_eq!(error_status(upload_form(&files, "/inbox/", truncated)), 400);
+        assert_eq!(names(&dir), 2);
+    }
+
+    #[test]
+    fn variants_linking_outside_the_root_are_ignored() {
+        let outside = TempDir::new();
+        let dir = TempDir::new();
+        dir.write("notes.txt", "public");
+        let secret = outside.write("shadow", "secret");
+        std::os::unix::fs::symlink(secret, std::path::Path::new(dir.root()).join("notes.txt.gz")).unwrap();
+        let mut files = StaticFiles::new(dir.root());
+        let response = get(&files, "/notes.txt", &[("Accept-Encoding", "gzip")]).unwrap();
+        assert_eq!(body_text(&response), "public");
+        assert!(header(&response, "Content-Encoding").is_none());
+        // Unless symlinks out of the root are allowed
+        files.set_follow_symlinks(true);
+        let response = get(&files, "/notes.txt", &[("Accept-Encoding", "gzip")]).unwrap();
+        assert_eq!(body_text(&response), "secret");
+    }
+
+    #[test]
+    fn index_files_linking_outside_the_root_are_skipped() {
+        let outside = TempDir::new();
+        let dir = TempDir::new();
+        let secret = outside.write("shadow", "secret");
+        dir.write("docs/index.htm", "fallback index");
+        std::os::unix::fs::symlink(secret, std::path::Path::new(dir.root()).join("docs/index.html")).unwrap();
+        let mut files = StaticFiles::new(dir.root());
+        files.set_index_files(&["index.html"]);
+        assert_eq!(error_status(get(&files, "/docs/", &[])), 404);
+        files.set_index_files(&["index.html", "index.htm"]);
+        assert_eq!(body_text(&get(&files, "/docs/", &[]).unwrap()), "fallback index");
+    }
+}
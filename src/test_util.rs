@@ -0,0 +1,113 @@
+// Fixtures shared by the unit tests.
+use std::{path::PathBuf, sync::atomic::{AtomicUsize, Ordering}};
+
+use crate::{file_body, CodingRegistry, HttpError, HttpFrame, RequestContext, Router};
+
+// A directory removed again when the test is done with it.
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new() -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("http-server-test-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path: path.canonicalize().unwrap() }
+    }
+
+    // The directory as the &str that StaticFiles and friends are built from.
+    pub(crate) fn root(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    // Write `contents` to `relative`, creating the directories on the way.
+    pub(crate) fn write(&self, relative: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.path.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+// Parse a request the way the server does, so header values are split like theirs.
+pub(crate) fn request(method: &str, uri: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<HttpFrame> {
+    let mut raw = format!("{} {} HTTP/1.1\r\nHost: test\r\n", method, uri).into_bytes();
+    for (name, value) in headers {
+        raw.extend(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    if !body.is_empty() {
+        raw.extend(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    raw.extend(b"\r\n");
+    raw.extend(body);
+    HttpFrame::from_stream(&mut raw.into_iter()).unwrap()
+}
+
+// Header values the way the server splits them, e.g. for Accept.
+pub(crate) fn list(value: &str) -> Vec<String> {
+    value.split(',').map(|item| item.trim().to_string()).collect()
+}
+
+pub(crate) fn context() -> RequestContext {
+    RequestContext::new(None, None, 0, Default::default(), CodingRegistry::default())
+}
+
+// Dispatch `request` through `router` like the server does, 404 when nothing matches.
+pub(crate) fn call(router: &Router, request: Vec<HttpFrame>) -> Result<Vec<HttpFrame>, HttpError> {
+    let handler = router.lookup(&request[0].get_method(), &request[0].get_uri()).ok_or_else(HttpError::not_found)?;
+    handler(request, &mut context())
+}
+
+pub(crate) fn status(response: &[HttpFrame]) -> u16 {
+    match &response[0] {
+        HttpFrame::ResponseHead { status, .. } => status.0,
+        frame => panic!("expected a response head, got {:?}", frame),
+    }
+}
+
+pub(crate) fn header(response: &[HttpFrame], name: &str) -> Option<String> {
+    response[0].get_headers().get_joined(name)
+}
+
+// The body frames of a response, file bodies read back from their file.
+pub(crate) fn body(response: &[HttpFrame]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for frame in response[1..].iter() {
+        match frame {
+            HttpFrame::BodyChunk { chunk } => body.extend(chunk),
+            HttpFrame::FileBody { file, offset, length } => body.extend(file_body::read(file, *offset, *length).unwrap()),
+            frame => panic!("expected a body frame, got {:?}", frame),
+        }
+    }
+    body
+}
+
+pub(crate) fn body_text(response: &[HttpFrame]) -> String {
+    String::from_utf8(body(response)).unwrap()
+}
+
+// Join the chunks of a chunked body, which has to end with the last chunk.
+pub(crate) fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n").expect("chunk size line");
+        let size = usize::from_str_radix(std::str::from_utf8(&body[..line_end]).unwrap(), 16).unwrap();
+        body = &body[line_end + 2..];
+        if size == 0 {
+            assert_eq!(body, b"\r\n");
+            return data;
+        }
+        data.extend(&body[..size]);
+        assert_eq!(&body[size..size + 2], b"\r\n");
+        body = &body[size + 2..];
+    }
+}
@@ -0,0 +1,591 @@
+// Resumable uploads following the tus 1.0 core protocol, with the creation, expiration
+// and termination extensions. Uploads are created with POST on the mount point and
+// named by an Upload-Metadata "filename" (a path relative to the serving directory).
+// Their bytes collect in a directory next to the serving directory and the file is
+// moved into place once the last PATCH completes it.
+//
+// Each PATCH body is read whole before it is appended, so a connection dropped
+// mid-request loses that request's bytes; clients should send bounded chunks.
+use std::{collections::{hash_map::RandomState, HashSet}, fs::OpenOptions, hash::{BuildHasher, Hasher}, io::Write, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use base64::Engine;
+
+use crate::{atomic_write, percent, router, status_code, HeaderMap, HttpError, HttpFrame, Method, PathResolver, RequestContext, Router, Version, WriteGuard};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+const DEFAULT_MAX_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+const DEFAULT_EXPIRATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// State of one upload, kept in "<id>.info" next to the "<id>.part" data file.
+struct Upload {
+    length: u64,
+    destination: PathBuf,
+    // The Upload-Metadata header as the client sent it
+    metadata: String,
+    expires: SystemTime,
+    complete: bool,
+}
+
+impl Upload {
+    fn to_info(&self) -> String {
+        let expires = self.expires.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        format!("length={}\ndestination={}\nexpires={}\ncomplete={}\nmetadata={}\n",
+                self.length, self.destination.display(), expires, self.complete, self.metadata)
+    }
+
+    fn from_info(info: &str) -> Option<Upload> {
+        let mut upload = Upload { length: 0, destination: PathBuf::new(), metadata: String::new(), expires: UNIX_EPOCH, complete: false };
+        for line in info.lines() {
+            let (key, value) = line.split_once('=')?;
+            match key {
+                "length" => upload.length = value.parse().ok()?,
+                "destination" => upload.destination = PathBuf::from(value),
+                "expires" => upload.expires = UNIX_EPOCH + Duration::from_secs(value.parse().ok()?),
+                "complete" => upload.complete = value.parse().ok()?,
+                "metadata" => upload.metadata = value.to_string(),
+                _ => (),
+            }
+        }
+        Some(upload)
+    }
+}
+
+#[derive(Clone)]
+pub struct TusUploads {
+    resolver: PathResolver,
+    upload_dir: PathBuf,
+    max_size: u64,
+    expiration: Duration,
+    // Uploads a PATCH is currently appending to
+    busy: Arc<Mutex<HashSet<String>>>,
+    write_guard: Option<WriteGuard>,
+}
+
+fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.map.insert("Tus-Resumable".to_string(), vec![TUS_VERSION.to_string()]);
+    headers
+}
+
+fn response(code: u16, headers: HeaderMap) -> Vec<HttpFrame> {
+    vec![HttpFrame::ResponseHead { status: status_code(code), version: Version::Http1_1, headers }]
+}
+
+fn header_u64(request: &HttpFrame, name: &str) -> Result<Option<u64>, HttpError> {
+    match request.get_headers().get_joined(name) {
+        None => Ok(None),
+        Some(value) => value.trim().parse().map(Some).map_err(|_| HttpError::bad_request(&format!("Invalid {} header", name))),
+    }
+}
+
+// Value of `key` in an Upload-Metadata header: comma separated "key base64-value" pairs.
+fn metadata_value(metadata: &str, key: &str) -> Result<Option<String>, HttpError> {
+    for pair in metadata.split(',') {
+        let mut parts = pair.trim().splitn(2, ' ');
+        if parts.next() != Some(key) {
+            continue;
+        }
+        let encoded = parts.next().unwrap_or("").trim();
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded)
+            .ok()
+            .and_then(|value| String::from_utf8(value).ok())
+            .ok_or_else(|| HttpError::bad_request("Invalid Upload-Metadata value"))?;
+        return Ok(Some(decoded));
+    }
+    Ok(None)
+}
+
+// 32 random hex digits.
+fn new_upload_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+    (0..2usize)
+        .map(|index| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(nanos);
+            hasher.write_usize(index);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect()
+}
+
+fn is_upload_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+impl TusUploads {
+    // Serve uploads into `root`, keeping partial uploads in a hidden sibling directory,
+    // "/srv/.files.uploads" for "/srv/files".
+    pub fn new(root: &str) -> TusUploads {
+        let root_path = std::fs::canonicalize(root).unwrap_or_else(|_| PathBuf::from(root));
+        let name = root_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let upload_dir = root_path.with_file_name(format!(".{}.uploads", name));
+        TusUploads {
+            resolver: PathResolver::new(root),
+            upload_dir,
+            max_size: DEFAULT_MAX_SIZE,
+            expiration: DEFAULT_EXPIRATION,
+            busy: Arc::new(Mutex::new(HashSet::new())),
+            write_guard: None,
+        }
+    }
+
+    // Keep partial uploads in `dirname` instead. It should be on the same filesystem as
+    // the serving directory so completed uploads can be renamed into place.
+    pub fn set_upload_dir(&mut self, dirname: &str) {
+        self.upload_dir = PathBuf::from(dirname);
+    }
+
+    // Largest Upload-Length accepted, advertised as Tus-Max-Size.
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = max_size;
+    }
+
+    // Uploads not written to for this long are removed.
+    pub fn set_expiration(&mut self, expiration: Duration) {
+        self.expiration = expiration;
+    }
+
+    // Check the destination against `guard` when an upload is created and again before
+    // the request that completes it.
+    pub fn set_write_guard(&mut self, guard: WriteGuard) {
+        self.write_guard = Some(guard);
+    }
+
+    pub fn router(&self) -> Router {
+        type TusHandler = fn(&TusUploads, &[HttpFrame], &RequestContext) -> Result<Vec<HttpFrame>, HttpError>;
+        let handlers: [(Method, TusHandler); 5] = [
+            (Method::OPTIONS, TusUploads::handle_options),
+            (Method::POST, TusUploads::handle_create),
+            (Method::HEAD, TusUploads::handle_head),
+            (Method::PATCH, TusUploads::handle_patch),
+            (Method::DELETE, TusUploads::handle_delete),
+        ];
+        let uploads = Arc::new(self.clone());
+        let mut router = Router::new();
+        for (method, handler) in handlers {
+            let uploads = uploads.clone();
+            router.add_route(method, "/".to_string(), move |request: Vec<HttpFrame>, ctx: &mut RequestContext| {
+                // Every response carries Tus-Resumable, errors included
+                handler(&uploads, &request, ctx).map_err(|e| e.with_header("Tus-Resumable", TUS_VERSION))
+            });
+        }
+        router
+    }
+
+    fn check_version(request: &HttpFrame) -> Result<(), HttpError> {
+        match request.get_headers().get_joined("Tus-Resumable") {
+            Some(version) if version.trim() == TUS_VERSION => Ok(()),
+            _ => Err(HttpError::from_status(412, "Unsupported or missing Tus-Resumable version").with_header("Tus-Version", TUS_VERSION)),
+        }
+    }
+
+    fn check_write(&self, request: &HttpFrame, path: &Path) -> Result<(), HttpError> {
+        match self.write_guard.as_ref() {
+            Some(guard) => guard.check(request, path),
+            None => Ok(()),
+        }
+    }
+
+    fn part_path(&self, id: &str) -> PathBuf {
+        self.upload_dir.join(format!("{}.part", id))
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.upload_dir.join(format!("{}.info", id))
+    }
+
+    // The upload id from a request target "/<id>".
+    fn upload_id(request: &HttpFrame) -> Result<String, HttpError> {
+        let uri = request.get_uri();
+        let id = uri.split(['?', '#']).next().unwrap_or("").trim_start_matches('/');
+        if !is_upload_id(id) {
+            return Err(HttpError::not_found());
+        }
+        Ok(id.to_string())
+    }
+
+    fn remove_upload(&self, id: &str) {
+        let _ = std::fs::remove_file(self.part_path(id));
+        let _ = std::fs::remove_file(self.info_path(id));
+    }
+
+    // Load an upload, treating expired ones as gone.
+    fn load(&self, id: &str) -> Result<Upload, HttpError> {
+        let info = std::fs::read_to_string(self.info_path(id)).map_err(|_| HttpError::not_found())?;
+        let upload = Upload::from_info(&info).ok_or_else(|| HttpError::from_status(500, "Corrupt upload state"))?;
+        if upload.expires <= SystemTime::now() {
+            self.remove_upload(id);
+            return Err(HttpError::from_status(410, "The upload has expired"));
+        }
+        Ok(upload)
+    }
+
+    fn save(&self, id: &str, upload: &Upload) -> Result<(), HttpError> {
+        Ok(atomic_write::write_file(&self.info_path(id), upload.to_info().as_bytes())?)
+    }
+
+    fn remove_expired(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.upload_dir) else {
+            return;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(id) = name.strip_suffix(".info").filter(|id| is_upload_id(id)) {
+                let live = std::fs::read_to_string(entry.path()).ok()
+                    .and_then(|info| Upload::from_info(&info))
+                    .is_some_and(|upload| upload.expires > SystemTime::now());
+                if !live && !self.busy.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).contains(id) {
+                    self.remove_upload(id);
+                }
+            }
+        }
+    }
+
+    fn expires_header(headers: &mut HeaderMap, upload: &Upload) {
+        if !upload.complete {
+            headers.map.insert("Upload-Expires".to_string(), vec![httpdate::fmt_http_date(upload.expires)]);
+        }
+    }
+
+    fn handle_options(&self, _request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let mut headers = tus_headers();
+        headers.map.insert("Tus-Version".to_string(), vec![TUS_VERSION.to_string()]);
+        headers.map.insert("Tus-Extension".to_string(), vec![TUS_EXTENSIONS.to_string()]);
+        headers.map.insert("Tus-Max-Size".to_string(), vec![self.max_size.to_string()]);
+        Ok(response(204, headers))
+    }
+
+    fn handle_create(&self, request: &[HttpFrame], ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        TusUploads::check_version(&request[0])?;
+        let length = header_u64(&request[0], "Upload-Length")?
+            .ok_or_else(|| HttpError::bad_request("Missing Upload-Length header"))?;
+        if length > self.max_size {
+            return Err(HttpError::from_status(413, "Upload-Length exceeds Tus-Max-Size"));
+        }
+        // The header parser split the pairs on their commas
+        let metadata = request[0].get_headers().get("Upload-Metadata").map(|values| values.join(",")).unwrap_or_default();
+        let filename = metadata_value(&metadata, "filename")?
+            .ok_or_else(|| HttpError::bad_request("Upload-Metadata needs a filename"))?;
+        if filename.chars().any(char::is_control) {
+            return Err(HttpError::bad_request("Invalid filename"));
+        }
+        let target: Vec<String> = filename.split('/').map(percent::encode_path_segment).collect();
+        let destination = self.resolver.resolve(&format!("/{}", target.join("/")))?;
+        if destination == self.resolver.root() || destination.is_dir() {
+            return Err(HttpError::from_status(409, "The filename names a directory"));
+        }
+        if !destination.parent().is_some_and(|parent| parent.is_dir()) {
+            return Err(HttpError::from_status(409, "The destination directory does not exist"));
+        }
+        self.check_write(&request[0], &destination)?;
+
+        self.remove_expired();
+        std::fs::create_dir_all(&self.upload_dir)?;
+        let id = new_upload_id();
+        let upload = Upload { length, destination, metadata, expires: SystemTime::now() + self.expiration, complete: false };
+        OpenOptions::new().write(true).create_new(true).open(self.part_path(&id))?;
+        self.save(&id, &upload)?;
+
+        let mut headers = tus_headers();
+        headers.map.insert("Location".to_string(), vec![format!("{}/{}", router::mount_prefix(&request[0], ctx), id)]);
+        TusUploads::expires_header(&mut headers, &upload);
+        if length == 0 {
+            self.finish(&id, upload)?;
+        }
+        Ok(response(201, headers))
+    }
+
+    fn handle_head(&self, request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        TusUploads::check_version(&request[0])?;
+        let id = TusUploads::upload_id(&request[0])?;
+        let upload = self.load(&id)?;
+        let offset = if upload.complete { upload.length } else { std::fs::metadata(self.part_path(&id))?.len() };
+
+        let mut headers = tus_headers();
+        headers.map.insert("Upload-Offset".to_string(), vec![offset.to_string()]);
+        headers.map.insert("Upload-Length".to_string(), vec![upload.length.to_string()]);
+        if !upload.metadata.is_empty() {
+            headers.map.insert("Upload-Metadata".to_string(), vec![upload.metadata.clone()]);
+        }
+        headers.map.insert("Cache-Control".to_string(), vec!["no-store".to_string()]);
+        TusUploads::expires_header(&mut headers, &upload);
+        Ok(response(200, headers))
+    }
+
+    fn handle_patch(&self, request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        TusUploads::check_version(&request[0])?;
+        let id = TusUploads::upload_id(&request[0])?;
+        let content_type = request[0].get_headers().get_joined("Content-Type").unwrap_or_default();
+        if !content_type.trim().eq_ignore_ascii_case(OFFSET_CONTENT_TYPE) {
+            return Err(HttpError::from_status(415, "PATCH requests need Content-Type: application/offset+octet-stream"));
+        }
+        let offset = header_u64(&request[0], "Upload-Offset")?
+            .ok_or_else(|| HttpError::bad_request("Missing Upload-Offset header"))?;
+
+        if !self.busy.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(id.clone()) {
+            return Err(HttpError::from_status(423, "Another request is writing to this upload"));
+        }
+        let result = self.append(&id, offset, request);
+        self.busy.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&id);
+        result
+    }
+
+    fn append(&self, id: &str, offset: u64, request: &[HttpFrame]) -> Result<Vec<HttpFrame>, HttpError> {
+        let mut upload = self.load(id)?;
+        let current = if upload.complete { upload.length } else { std::fs::metadata(self.part_path(id))?.len() };
+        if offset != current {
+            return Err(HttpError::from_status(409, "Upload-Offset does not match the upload's offset")
+                .with_header("Upload-Offset", &current.to_string()));
+        }
+        let body: &[u8] = match request.get(1) {
+            Some(HttpFrame::BodyChunk { chunk }) => chunk,
+            _ => &[],
+        };
+        let new_offset = offset + body.len() as u64;
+        if new_offset > upload.length {
+            return Err(HttpError::from_status(413, "The request body goes past Upload-Length"));
+        }
+        // Refuse the last chunk rather than move the file over a resource locked meanwhile
+        if new_offset == upload.length && !upload.complete {
+            self.check_write(&request[0], &upload.destination)?;
+        }
+
+        if !body.is_empty() {
+            let mut part = OpenOptions::new().append(true).open(self.part_path(id))?;
+            part.write_all(body)?;
+            part.sync_data()?;
+        }
+        upload.expires = SystemTime::now() + self.expiration;
+        let mut headers = tus_headers();
+        headers.map.insert("Upload-Offset".to_string(), vec![new_offset.to_string()]);
+        TusUploads::expires_header(&mut headers, &upload);
+        if new_offset == upload.length {
+            self.finish(id, upload)?;
+        } else {
+            self.save(id, &upload)?;
+        }
+        Ok(response(204, headers))
+    }
+
+    // Move the completed data into place. The info file stays until expiry so HEAD
+    // keeps reporting the upload as complete to clients that resume after a failure.
+    fn finish(&self, id: &str, mut upload: Upload) -> Result<(), HttpError> {
+        if upload.complete {
+            return Ok(());
+        }
+        let part = self.part_path(id);
+        if std::fs::rename(&part, &upload.destination).is_err() {
+            // Different filesystems: copy next to the destination, then rename over it
+            let temp = upload.destination.with_file_name(format!(".{}.part", id));
+            std::fs::copy(&part, &temp)?;
+            std::fs::File::open(&temp)?.sync_all()?;
+            if let Err(e) = std::fs::rename(&temp, &upload.destination) {
+                let _ = std::fs::remove_file(&temp);
+                return Err(e.into());
+            }
+            std::fs::remove_file(&part)?;
+        }
+        upload.complete = true;
+        self.save(id, &upload)
+    }
+
+    fn handle_delete(&self, request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        TusUploads::check_version(&request[0])?;
+        let id = TusUploads::upload_id(&request[0])?;
+        self.load(&id)?;
+        if self.busy.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).contains(&id) {
+            return Err(HttpError::from_status(423, "Another request is writing to this upload"));
+        }
+        self.remove_upload(&id);
+        Ok(response(204, tus_headers()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, call, header, status, TempDir};
+
+    const TUS: (&str, &str) = ("Tus-Resumable", TUS_VERSION);
+    const CHUNK: (&str, &str) = ("Content-Type", OFFSET_CONTENT_TYPE);
+
+    // Uploads into "<dir>/files", with partial uploads in "<dir>/uploads".
+    fn uploads(dir: &TempDir) -> TusUploads {
+        dir.write("files/docs/.keep", "");
+        let mut uploads = TusUploads::new(&format!("{}/files", dir.root()));
+        uploads.set_upload_dir(&format!("{}/uploads", dir.root()));
+        uploads
+    }
+
+    fn mounted(uploads: &TusUploads) -> Router {
+        let mut router = Router::new();
+        router.mount("/uploads", uploads.router());
+        router
+    }
+
+    fn send(router: &Router, method: &str, uri: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Vec<HttpFrame>, HttpError> {
+        call(router, test_util::request(method, uri, headers, body))
+    }
+
+    fn error_status(result: Result<Vec<HttpFrame>, HttpError>) -> u16 {
+        result.unwrap_err().status().unwrap().0
+    }
+
+    fn filename(name: &str) -> String {
+        format!("filename {}", base64::engine::general_purpose::STANDARD.encode(name))
+    }
+
+    // Create an upload and return its URL.
+    fn create(router: &Router, name: &str, length: u64) -> String {
+        let metadata = filename(name);
+        let length = length.to_string();
+        let response = send(router, "POST", "/uploads", &[TUS, ("Upload-Length", &length), ("Upload-Metadata", &metadata)], b"").unwrap();
+        assert_eq!(status(&response), 201);
+        header(&response, "Location").unwrap()
+    }
+
+    fn patch(router: &Router, location: &str, offset: u64, body: &[u8]) -> Result<Vec<HttpFrame>, HttpError> {
+        send(router, "PATCH", location, &[TUS, CHUNK, ("Upload-Offset", &offset.to_string())], body)
+    }
+
+    #[test]
+    fn options_advertises_version_extensions_and_size() {
+        let dir = TempDir::new();
+        let mut uploads = uploads(&dir);
+        uploads.set_max_size(1000);
+        let response = send(&mounted(&uploads), "OPTIONS", "/uploads", &[], b"").unwrap();
+        assert_eq!(status(&response), 204);
+        assert_eq!(header(&response, "Tus-Extension").as_deref(), Some(TUS_EXTENSIONS));
+        assert_eq!(header(&response, "Tus-Max-Size").as_deref(), Some("1000"));
+    }
+
+    #[test]
+    fn chunks_are_appended_and_the_file_moved_into_place() {
+        let dir = TempDir::new();
+        let router = mounted(&uploads(&dir));
+        let location = create(&router, "docs/report.txt", 11);
+        assert!(location.starts_with("/uploads/"));
+
+        let response = patch(&router, &location, 0, b"hello ").unwrap();
+        assert_eq!(header(&response, "Upload-Offset").as_deref(), Some("6"));
+        assert!(header(&response, "Upload-Expires").is_some());
+        let response = send(&router, "HEAD", &location, &[TUS], b"").unwrap();
+        assert_eq!(header(&response, "Upload-Offset").as_deref(), Some("6"));
+        assert_eq!(header(&response, "Upload-Length").as_deref(), Some("11"));
+        assert_eq!(header(&response, "Upload-Metadata"), Some(filename("docs/report.txt")));
+        assert!(!Path::new(dir.root()).join("files/docs/report.txt").exists());
+
+        patch(&router, &location, 6, b"world").unwrap();
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("files/docs/report.txt")).unwrap(), b"hello world");
+        // A completed upload keeps reporting its full offset
+        let response = send(&router, "HEAD", &location, &[TUS], b"").unwrap();
+        assert_eq!(header(&response, "Upload-Offset").as_deref(), Some("11"));
+        assert!(header(&response, "Upload-Expires").is_none());
+    }
+
+    #[test]
+    fn empty_uploads_complete_on_creation() {
+        let dir = TempDir::new();
+        create(&mounted(&uploads(&dir)), "empty.txt", 0);
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("files/empty.txt")).unwrap(), b"");
+    }
+
+    #[test]
+    fn wrong_offsets_and_overlong_chunks_are_refused() {
+        let dir = TempDir::new();
+        let router = mounted(&uploads(&dir));
+        let location = create(&router, "a.txt", 4);
+        let error = patch(&router, &location, 2, b"ab").unwrap_err();
+        assert_eq!(error.status().unwrap().0, 409);
+        assert_eq!(error.headers().get_joined("Upload-Offset").as_deref(), Some("0"));
+        assert_eq!(error_status(patch(&router, &location, 0, b"abcde")), 413);
+        let headers = [TUS, ("Content-Type", "application/octet-stream"), ("Upload-Offset", "0")];
+        assert_eq!(error_status(send(&router, "PATCH", &location, &headers, b"ab")), 415);
+        assert_eq!(error_status(send(&router, "PATCH", &location, &[TUS, CHUNK], b"ab")), 400);
+    }
+
+    #[test]
+    fn creation_is_validated() {
+        let dir = TempDir::new();
+        let mut uploads = uploads(&dir);
+        uploads.set_max_size(10);
+        let router = mounted(&uploads);
+        let create = |headers: &[(&str, &str)]| error_status(send(&router, "POST", "/uploads", headers, b""));
+        let name = filename("a.txt");
+        assert_eq!(create(&[("Upload-Length", "5"), ("Upload-Metadata", &name)]), 412);
+        assert_eq!(create(&[TUS, ("Upload-Length", "five"), ("Upload-Metadata", &name)]), 400);
+        assert_eq!(create(&[TUS, ("Upload-Metadata", &name)]), 400);
+        assert_eq!(create(&[TUS, ("Upload-Length", "11"), ("Upload-Metadata", &name)]), 413);
+        assert_eq!(create(&[TUS, ("Upload-Length", "5")]), 400);
+        assert_eq!(create(&[TUS, ("Upload-Length", "5"), ("Upload-Metadata", "filename !!!")]), 400);
+        assert_eq!(create(&[TUS, ("Upload-Length", "5"), ("Upload-Metadata", &filename("docs"))]), 409);
+        assert_eq!(create(&[TUS, ("Upload-Length", "5"), ("Upload-Metadata", &filename("missing/a.txt"))]), 409);
+        assert_eq!(create(&[TUS, ("Upload-Length", "5"), ("Upload-Metadata", &filename("../a.txt"))]), 403);
+    }
+
+    #[test]
+    fn errors_carry_the_tus_version() {
+        let dir = TempDir::new();
+        let router = mounted(&uploads(&dir));
+        let error = send(&router, "HEAD", "/uploads/0123", &[("Tus-Resumable", "0.2.0")], b"").unwrap_err();
+        assert_eq!(error.status().unwrap().0, 412);
+        assert_eq!(error.headers().get_joined("Tus-Version").as_deref(), Some(TUS_VERSION));
+        assert_eq!(error.headers().get_joined("Tus-Resumable").as_deref(), Some(TUS_VERSION));
+        let missing = format!("/uploads/{}", "0".repeat(32));
+        assert_eq!(error_status(send(&router, "HEAD", &missing, &[TUS], b"")), 404);
+        assert_eq!(error_status(send(&router, "HEAD", "/uploads/not-an-id", &[TUS], b"")), 404);
+    }
+
+    #[test]
+    fn expired_uploads_are_gone() {
+        let dir = TempDir::new();
+        let mut uploads = uploads(&dir);
+        uploads.set_expiration(Duration::ZERO);
+        let router = mounted(&uploads);
+        let location = create(&router, "a.txt", 4);
+        assert_eq!(error_status(send(&router, "HEAD", &location, &[TUS], b"")), 410);
+        assert_eq!(error_status(send(&router, "HEAD", &location, &[TUS], b"")), 404);
+    }
+
+    #[test]
+    fn terminated_uploads_are_removed() {
+        let dir = TempDir::new();
+        let router = mounted(&uploads(&dir));
+        let location = create(&router, "a.txt", 4);
+        patch(&router, &location, 0, b"ab").unwrap();
+        assert_eq!(status(&send(&router, "DELETE", &location, &[TUS], b"").unwrap()), 204);
+        assert_eq!(error_status(send(&router, "HEAD", &location, &[TUS], b"")), 404);
+        assert_eq!(std::fs::read_dir(Path::new(dir.root()).join("uploads")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn write_guard_is_checked_on_creation_and_completion() {
+        let dir = TempDir::new();
+        let locked = Arc::new(Mutex::new(false));
+        let mut uploads = uploads(&dir);
+        let guard_locked = locked.clone();
+        uploads.set_write_guard(WriteGuard::new(move |_request: &HttpFrame, _path: &Path| {
+            match *guard_locked.lock().unwrap() {
+                true => Err(HttpError::from_status(423, "Locked")),
+                false => Ok(()),
+            }
+        }));
+        let router = mounted(&uploads);
+        let location = create(&router, "a.txt", 4);
+        patch(&router, &location, 0, b"ab").unwrap();
+
+        *locked.lock().unwrap() = true;
+        assert_eq!(error_status(patch(&router, &location, 2, b"cd")), 423);
+        assert!(!Path::new(dir.root()).join("files/a.txt").exists());
+        let headers = [TUS, ("Upload-Length", "4"), ("Upload-Metadata", &filename("b.txt"))];
+        assert_eq!(error_status(send(&router, "POST", "/uploads", &headers, b"")), 423);
+
+        *locked.lock().unwrap() = false;
+        patch(&router, &location, 2, b"cd").unwrap();
+        assert_eq!(std::fs::read(Path::new(dir.root()).join("files/a.txt")).unwrap(), b"abcd");
+    }
+}
@@ -0,0 +1,159 @@
+use crate::Router;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum HostPattern {
+    Exact(String),
+    // Stored as the suffix including the leading dot, e.g. ".example.test"
+    Wildcard(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> HostPattern {
+        let pattern = pattern.trim().trim_end_matches('.').to_ascii_lowercase();
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostPattern::Wildcard(suffix.to_string()),
+            _ => HostPattern::Exact(pattern),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => name == host,
+            HostPattern::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        }
+    }
+
+    // Exact names win over wildcards, and longer wildcards over shorter ones.
+    fn specificity(&self) -> (bool, usize) {
+        match self {
+            HostPattern::Exact(name) => (true, name.len()),
+            HostPattern::Wildcard(suffix) => (false, suffix.len()),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct VirtualHost {
+    pattern: HostPattern,
+    router: Router,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct VirtualHosts {
+    pub(crate) default: Router,
+    hosts: Vec<VirtualHost>,
+}
+
+impl VirtualHosts {
+    pub(crate) fn add(&mut self, pattern: &str, router: Router) {
+        let pattern = HostPattern::parse(pattern);
+        self.hosts.retain(|host| host.pattern != pattern);
+        self.hosts.push(VirtualHost { pattern, router });
+        self.hosts.sort_by_key(|host| std::cmp::Reverse(host.pattern.specificity()));
+    }
+
+    // Pick the router for the value of a Host header, falling back to the default host.
+    pub(crate) fn select(&self, host: Option<&str>) -> &Router {
+        let Some(host) = host.map(VirtualHosts::normalize) else {
+            return &self.default;
+        };
+        self.hosts
+            .iter()
+            .find(|vhost| vhost.pattern.matches(&host))
+            .map(|vhost| &vhost.router)
+            .unwrap_or(&self.default)
+    }
+
+    // Lowercase the name and drop the port, keeping IPv6 literals intact.
+    fn normalize(host: &str) -> String {
+        let host = host.trim();
+        let name = if host.starts_with('[') {
+            match host.find(']') {
+                Some(end) => &host[..=end],
+                None => host,
+            }
+        } else {
+            host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host)
+        };
+        name.trim_end_matches('.').to_ascii_lowercase()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpError, HttpFrame, Method, RequestContext};
+
+    // A router whose only route answers with `name`, to tell routers apart.
+    fn router(name: &'static str) -> Router {
+        let mut router = Router::new();
+        router.add_route(Method::GET, "/".to_string(), move |_request: Vec<HttpFrame>, _ctx: &mut RequestContext| -> Result<Vec<HttpFrame>, HttpError> {
+            Ok(vec![HttpFrame::BodyChunk { chunk: name.as_bytes().to_vec() }])
+        });
+        router
+    }
+
+    fn selected(hosts: &VirtualHosts, host: Option<&str>) -> String {
+        let handler = hosts.select(host).lookup(&Method::GET, "/").unwrap();
+        let ctx = &mut RequestContext::new(None, None, 0, Default::default(), Default::default());
+        let request = vec![HttpFrame::RequestHead { method: Method::GET, uri: "/".to_string(), version: crate::Version::Http1_1, headers: Default::default() }];
+        match &handler(request, ctx).unwrap()[0] {
+            HttpFrame::BodyChunk { chunk } => String::from_utf8(chunk.clone()).unwrap(),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    fn hosts() -> VirtualHosts {
+        let mut hosts = VirtualHosts { default: router("default"), hosts: Vec::new() };
+        hosts.add("*.example.test", router("wildcard"));
+        hosts.add("*.api.example.test", router("api wildcard"));
+        hosts.add("www.example.test", router("www"));
+        hosts
+    }
+
+    #[test]
+    fn exact_names_win_over_wildcards() {
+        let hosts = hosts();
+        assert_eq!(selected(&hosts, Some("www.example.test")), "www");
+        assert_eq!(selected(&hosts, Some("shop.example.test")), "wildcard");
+    }
+
+    #[test]
+    fn longer_wildcards_win_over_shorter_ones() {
+        assert_eq!(selected(&hosts(), Some("v1.api.example.test")), "api wildcard");
+    }
+
+    #[test]
+    fn wildcards_do_not_match_the_bare_domain() {
+        assert_eq!(selected(&hosts(), Some("example.test")), "default");
+    }
+
+    #[test]
+    fn host_is_matched_without_case_port_or_trailing_dot() {
+        let hosts = hosts();
+        assert_eq!(selected(&hosts, Some("WWW.Example.Test:8080")), "www");
+        assert_eq!(selected(&hosts, Some("www.example.test.")), "www");
+    }
+
+    #[test]
+    fn unknown_or_missing_hosts_use_the_default_router() {
+        let hosts = hosts();
+        assert_eq!(selected(&hosts, Some("other.test")), "default");
+        assert_eq!(selected(&hosts, None), "default");
+    }
+
+    #[test]
+    fn adding_a_pattern_again_replaces_its_router() {
+        let mut hosts = hosts();
+        hosts.add("WWW.example.test", router("replaced"));
+        assert_eq!(selected(&hosts, Some("www.example.test")), "replaced");
+        assert_eq!(hosts.hosts.len(), 3);
+    }
+
+    #[test]
+    fn ipv6_literals_keep_their_colons() {
+        assert_eq!(VirtualHosts::normalize("[::1]:8080"), "[::1]");
+        assert_eq!(VirtualHosts::normalize("[::1]"), "[::1]");
+        assert_eq!(VirtualHosts::normalize("localhost:4221"), "localhost");
+    }
+}
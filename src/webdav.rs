@@ -0,0 +1,926 @@
+// WebDAV class 1 and 2 (RFC 4918) on top of `StaticFiles`: properties, collections,
+// COPY / MOVE and write locks. Dead properties and locks are kept in memory.
+// Mount the router it builds under a prefix: `server.mount("/dav", WebDav::new(dirname).router())`.
+use std::{collections::{hash_map::RandomState, BTreeMap, HashMap}, fs::Metadata, hash::{BuildHasher, Hasher}, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use crate::compression::decode_request_body;
+use crate::{conditional::{self, Validators}, percent, reason_phrase, router, status_code, xml::{self, Element}, HeaderMap, HttpError, HttpFrame, Method, RequestContext, Router, StaticFiles, Version, WriteGuard};
+
+const DAV: &str = "DAV:";
+
+// Locks last at most this many seconds unless refreshed, "Infinite" included.
+const MAX_LOCK_TIMEOUT: u64 = 3600;
+
+const LIVE_PROPERTIES: [&str; 9] = [
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "resourcetype",
+    "supportedlock",
+    "lockdiscovery",
+];
+
+const ALLOWED_METHODS: [&str; 12] = [
+    "OPTIONS", "GET", "HEAD", "PUT", "DELETE", "PROPFIND", "PROPPATCH", "MKCOL", "COPY", "MOVE", "LOCK", "UNLOCK",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+impl Depth {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Depth::Zero => "0",
+            Depth::One => "1",
+            Depth::Infinity => "infinity",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Lock {
+    token: String,
+    root: PathBuf,
+    href: String,
+    depth: Depth,
+    exclusive: bool,
+    // The <owner> content from the LOCK request, as sent.
+    owner: Option<String>,
+    timeout: u64,
+    expires: Instant,
+}
+
+impl Lock {
+    fn covers(&self, path: &Path) -> bool {
+        path == self.root || (self.depth == Depth::Infinity && path.starts_with(&self.root))
+    }
+}
+
+// (namespace, local name) of a property.
+type PropertyName = (String, String);
+
+#[derive(Default)]
+struct DavState {
+    locks: Vec<Lock>,
+    // Dead properties by resource, values kept as the XML they were set with
+    properties: HashMap<PathBuf, BTreeMap<PropertyName, String>>,
+}
+
+impl DavState {
+    fn expire_locks(&mut self) {
+        let now = Instant::now();
+        self.locks.retain(|lock| lock.expires > now);
+    }
+
+    // Forget properties and locks of `path` and everything below it.
+    fn forget(&mut self, path: &Path) {
+        self.properties.retain(|resource, _| !resource.starts_with(path));
+        self.locks.retain(|lock| !lock.root.starts_with(path));
+    }
+
+    fn copy_properties(&mut self, from: &Path, to: &Path) {
+        let copied: Vec<(PathBuf, BTreeMap<PropertyName, String>)> = self.properties
+            .iter()
+            .filter(|(resource, _)| resource.starts_with(from))
+            .map(|(resource, properties)| (to.join(resource.strip_prefix(from).unwrap_or(Path::new(""))), properties.clone()))
+            .collect();
+        self.properties.extend(copied);
+    }
+}
+
+enum PropfindRequest {
+    // allprop, with the extra properties named in <include>
+    AllProp(Vec<PropertyName>),
+    PropName,
+    Prop(Vec<PropertyName>),
+}
+
+enum PropertyUpdate {
+    Set(PropertyName, String),
+    Remove(PropertyName),
+}
+
+#[derive(Clone)]
+pub struct WebDav {
+    files: StaticFiles,
+    state: Arc<Mutex<DavState>>,
+}
+
+fn property_name(element: &Element) -> PropertyName {
+    (element.namespace.clone(), element.name.clone())
+}
+
+// Render a property element. DAV: properties use the "D" prefix declared on the
+// multistatus root, others declare their namespace as the default on the element.
+fn property_xml(name: &PropertyName, value: Option<&str>) -> String {
+    let (namespace, local) = name;
+    let (open, close) = if namespace == DAV {
+        (format!("D:{}", local), format!("D:{}", local))
+    } else {
+        (format!("{} xmlns=\"{}\"", local, xml::escape(namespace)), local.to_string())
+    };
+    match value {
+        Some(value) if !value.is_empty() => format!("<{}>{}</{}>", open, value, close),
+        _ => format!("<{}/>", open),
+    }
+}
+
+fn status_line(code: u16) -> String {
+    format!("HTTP/1.1 {} {}", code, reason_phrase(code))
+}
+
+fn propstat(properties: &[String], code: u16) -> String {
+    format!("<D:propstat><D:prop>{}</D:prop><D:status>{}</D:status></D:propstat>", properties.concat(), status_line(code))
+}
+
+fn xml_response(status: u16, mut headers: HeaderMap, body: String) -> Vec<HttpFrame> {
+    headers.map.insert("Content-Type".to_string(), vec!["application/xml; charset=utf-8".to_string()]);
+    vec![
+        HttpFrame::ResponseHead { status: status_code(status), version: Version::Http1_1, headers },
+        HttpFrame::BodyChunk { chunk: format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n{}\n", body).into_bytes() },
+    ]
+}
+
+fn multistatus(responses: &[String]) -> Vec<HttpFrame> {
+    xml_response(207, HeaderMap::new(), format!("<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>", responses.concat()))
+}
+
+fn empty_response(code: u16) -> Vec<HttpFrame> {
+    vec![HttpFrame::ResponseHead { status: status_code(code), version: Version::Http1_1, headers: HeaderMap::new() }]
+}
+
+fn request_body(request: &[HttpFrame]) -> &[u8] {
+    match request.get(1) {
+        Some(HttpFrame::BodyChunk { chunk }) => chunk,
+        _ => &[],
+    }
+}
+
+fn depth(request: &HttpFrame, default: Depth) -> Result<Depth, HttpError> {
+    match request.get_headers().get_joined("Depth").as_deref().map(str::trim) {
+        None => Ok(default),
+        Some("0") => Ok(Depth::Zero),
+        Some("1") => Ok(Depth::One),
+        Some(value) if value.eq_ignore_ascii_case("infinity") => Ok(Depth::Infinity),
+        Some(_) => Err(HttpError::bad_request("Invalid Depth header")),
+    }
+}
+
+// Lock tokens submitted in the If header. Tagged lists and entity tags are not
+// evaluated; a token anywhere in the header counts as submitted.
+fn submitted_tokens(request: &HttpFrame) -> Vec<String> {
+    let Some(value) = request.get_headers().get("If").map(|values| values.join(",")) else {
+        return Vec::new();
+    };
+    value
+        .split('<')
+        .filter_map(|part| part.split_once('>'))
+        .map(|(token, _)| token.trim().to_string())
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .collect()
+}
+
+fn lock_timeout(request: &HttpFrame) -> u64 {
+    let Some(values) = request.get_headers().get("Timeout") else {
+        return MAX_LOCK_TIMEOUT;
+    };
+    for value in values.iter().map(|value| value.trim()) {
+        if value.eq_ignore_ascii_case("Infinite") {
+            return MAX_LOCK_TIMEOUT;
+        }
+        if let Some(seconds) = value.strip_prefix("Second-").and_then(|seconds| seconds.parse::<u64>().ok()) {
+            return seconds.clamp(1, MAX_LOCK_TIMEOUT);
+        }
+    }
+    MAX_LOCK_TIMEOUT
+}
+
+// A random token in UUID version 4 form.
+fn new_lock_token() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+    let mut bits = [0u64; 2];
+    for (index, part) in bits.iter_mut().enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_usize(index);
+        *part = hasher.finish();
+    }
+    let high = (bits[0] & 0xffff_ffff_ffff_0fff) | 0x4000;
+    let low = (bits[1] & 0x3fff_ffff_ffff_ffff) | 0x8000_0000_0000_0000;
+    format!("opaquelocktoken:{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            high >> 32, (high >> 16) & 0xffff, high & 0xffff, low >> 48, low & 0xffff_ffff_ffff)
+}
+
+// ISO 8601 / RFC 3339 timestamp in UTC, as creationdate wants it.
+fn rfc3339(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, rest) = ((seconds / 86400) as i64, seconds % 86400);
+    // Days since the epoch to a civil date (H. Hinnant's algorithm)
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+impl WebDav {
+    pub fn new(root: &str) -> WebDav {
+        let mut files = StaticFiles::new(root);
+        files.set_listing(true);
+        WebDav { files, state: Arc::new(Mutex::new(DavState::default())) }
+    }
+
+    // The StaticFiles used for GET, HEAD and PUT, to adjust MIME types, limits and the like.
+    pub fn files_mut(&mut self) -> &mut StaticFiles {
+        &mut self.files
+    }
+
+    // A guard for StaticFiles or TusUploads serving the same directory, so that writes
+    // through them are refused with 423 like PUT and DELETE here.
+    pub fn write_guard(&self) -> WriteGuard {
+        let dav = self.clone();
+        WriteGuard::new(move |request: &HttpFrame, path: &Path| dav.check_locks(request, path, false))
+    }
+
+    pub fn router(&self) -> Router {
+        type DavHandler = fn(&WebDav, &[HttpFrame], &RequestContext) -> Result<Vec<HttpFrame>, HttpError>;
+        let handlers: [(Method, DavHandler); 10] = [
+            (Method::GET, WebDav::handle_get),
+            (Method::OPTIONS, WebDav::handle_options),
+            (Method::DELETE, WebDav::handle_delete),
+            (Method::PROPFIND, WebDav::handle_propfind),
+            (Method::PROPPATCH, WebDav::handle_proppatch),
+            (Method::MKCOL, WebDav::handle_mkcol),
+            (Method::COPY, WebDav::handle_copy_move),
+            (Method::MOVE, WebDav::handle_copy_move),
+            (Method::LOCK, WebDav::handle_lock),
+            (Method::UNLOCK, WebDav::handle_unlock),
+        ];
+        let dav = Arc::new(self.clone());
+        let mut router = Router::new();
+        for (method, handler) in handlers {
+            let dav = dav.clone();
+            router.add_route(method, "/".to_string(), move |request: Vec<HttpFrame>, ctx: &mut RequestContext| handler(&dav, &request, ctx));
+        }
+        router.add_route(Method::PUT, "/".to_string(), decode_request_body(self.files.max_upload_size(), move |request: Vec<HttpFrame>, ctx: &mut RequestContext| {
+            dav.handle_put(&request, ctx)
+        }));
+        router
+    }
+
+    fn state(&self) -> MutexGuard<'_, DavState> {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.expire_locks();
+        state
+    }
+
+    fn root(&self) -> &Path {
+        self.files.resolver().root()
+    }
+
+    fn target(&self, request: &[HttpFrame]) -> Result<PathBuf, HttpError> {
+        self.files.resolver().resolve(&request[0].get_uri())
+    }
+
+    fn href(&self, prefix: &str, path: &Path, is_dir: bool) -> String {
+        let relative = path.strip_prefix(self.root()).unwrap_or(Path::new(""));
+        let mut href = prefix.to_string();
+        for component in relative.components() {
+            href.push('/');
+            href.push_str(&percent::encode_path_segment(&component.as_os_str().to_string_lossy()));
+        }
+        if is_dir || href.is_empty() {
+            href.push('/');
+        }
+        href
+    }
+
+    // Map the Destination header of COPY and MOVE to a path below the root.
+    fn destination(&self, request: &HttpFrame, prefix: &str) -> Result<PathBuf, HttpError> {
+        // URLs may contain commas, which the header parser split on
+        let value = request.get_headers().get("Destination").map(|values| values.join(","))
+            .ok_or_else(|| HttpError::bad_request("Missing Destination header"))?;
+        let path = match value.strip_prefix("http://").or_else(|| value.strip_prefix("https://")) {
+            Some(rest) => rest.find('/').map_or("/", |start| &rest[start..]),
+            None => value.as_str(),
+        };
+        match path.strip_prefix(prefix) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => self.files.resolver().resolve(rest),
+            _ => Err(HttpError::from_status(502, "Destination is outside of this WebDAV share")),
+        }
+    }
+
+    // Fail with 423 unless the request submits the tokens of the locks on `path`; with
+    // `recursive`, of the locks anywhere below it as well.
+    fn check_locks(&self, request: &HttpFrame, path: &Path, recursive: bool) -> Result<(), HttpError> {
+        let tokens = submitted_tokens(request);
+        let state = self.state();
+        let relevant: Vec<&Lock> = state.locks
+            .iter()
+            .filter(|lock| lock.covers(path) || (recursive && lock.root.starts_with(path)))
+            .collect();
+        let exclusive_missing = relevant.iter().any(|lock| lock.exclusive && !tokens.contains(&lock.token));
+        let shared: Vec<&&Lock> = relevant.iter().filter(|lock| !lock.exclusive).collect();
+        let shared_missing = !shared.is_empty() && !shared.iter().any(|lock| tokens.contains(&lock.token));
+        if exclusive_missing || shared_missing {
+            return Err(HttpError::from_status(423, "The resource is locked"));
+        }
+        Ok(())
+    }
+
+    fn active_lock_xml(lock: &Lock) -> String {
+        let scope = if lock.exclusive { "<D:exclusive/>" } else { "<D:shared/>" };
+        let owner = lock.owner.as_ref().map(|owner| format!("<D:owner>{}</D:owner>", owner)).unwrap_or_default();
+        format!("<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope>{}</D:lockscope><D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+                scope, lock.depth.as_str(), owner, lock.timeout, lock.token, xml::escape(&lock.href))
+    }
+
+    // Value of a live property, or None when the resource does not have it.
+    fn live_property(&self, name: &str, path: &Path, metadata: &Metadata) -> Option<String> {
+        let is_dir = metadata.is_dir();
+        match name {
+            "creationdate" => metadata.created().or_else(|_| metadata.modified()).ok().map(rfc3339),
+            "displayname" => path.file_name().map(|name| xml::escape(&name.to_string_lossy())),
+            "getcontentlength" if !is_dir => Some(metadata.len().to_string()),
+            "getcontenttype" if !is_dir => Some(xml::escape(&self.files.mime_types().content_type(path))),
+            "getetag" if !is_dir => Some(xml::escape(&Validators::from_metadata(metadata).etag)),
+            "getlastmodified" => metadata.modified().ok().map(httpdate::fmt_http_date),
+            "resourcetype" => Some(if is_dir { "<D:collection/>".to_string() } else { String::new() }),
+            "supportedlock" => Some("<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry><D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>".to_string()),
+            "lockdiscovery" => Some(self.state().locks.iter().filter(|lock| lock.covers(path)).map(WebDav::active_lock_xml).collect()),
+            _ => None,
+        }
+    }
+
+    // Resources PROPFIND reports on: the target and, depending on `depth`, what is below it.
+    fn collect_resources(&self, path: &Path, metadata: Metadata, depth: Depth, resources: &mut Vec<(PathBuf, Metadata)>) -> Result<(), HttpError> {
+        let is_dir = metadata.is_dir();
+        resources.push((path.to_path_buf(), metadata));
+        if !is_dir || depth == Depth::Zero {
+            return Ok(());
+        }
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|entry| entry.file_name().and_then(|name| name.to_str()).is_some() && self.files.resolver().contains(entry))
+            .collect();
+        entries.sort();
+        let next = if depth == Depth::One { Depth::Zero } else { Depth::Infinity };
+        for entry in entries {
+            if let Ok(metadata) = std::fs::metadata(&entry) {
+                self.collect_resources(&entry, metadata, next, resources)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_propfind(body: &[u8]) -> Result<PropfindRequest, HttpError> {
+        if body.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return Ok(PropfindRequest::AllProp(Vec::new()));
+        }
+        let root = xml::parse(body)?;
+        if !root.is(DAV, "propfind") {
+            return Err(HttpError::bad_request("Expected a DAV:propfind element"));
+        }
+        let names = |element: Option<&Element>| element.map_or(Vec::new(), |element| element.children.iter().map(property_name).collect());
+        if root.child(DAV, "propname").is_some() {
+            Ok(PropfindRequest::PropName)
+        } else if root.child(DAV, "allprop").is_some() {
+            Ok(PropfindRequest::AllProp(names(root.child(DAV, "include"))))
+        } else if let Some(prop) = root.child(DAV, "prop") {
+            Ok(PropfindRequest::Prop(names(Some(prop))))
+        } else {
+            Err(HttpError::bad_request("DAV:propfind needs allprop, propname or prop"))
+        }
+    }
+
+    fn propfind_response(&self, href: String, path: &Path, metadata: &Metadata, request: &PropfindRequest) -> String {
+        let state_properties = self.state().properties.get(path).cloned().unwrap_or_default();
+        let live_names = LIVE_PROPERTIES.iter().filter(|name| self.live_property(name, path, metadata).is_some());
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+        match request {
+            PropfindRequest::PropName => {
+                found.extend(live_names.map(|name| property_xml(&(DAV.to_string(), name.to_string()), None)));
+                found.extend(state_properties.keys().map(|name| property_xml(name, None)));
+            },
+            PropfindRequest::AllProp(include) => {
+                for name in live_names {
+                    let value = self.live_property(name, path, metadata);
+                    found.push(property_xml(&(DAV.to_string(), name.to_string()), value.as_deref()));
+                }
+                found.extend(state_properties.iter().map(|(name, value)| property_xml(name, Some(value))));
+                // Live properties are all listed already, only unknown names can be missing
+                missing.extend(include.iter()
+                    .filter(|name| !(state_properties.contains_key(*name) || (name.0 == DAV && LIVE_PROPERTIES.contains(&name.1.as_str()))))
+                    .map(|name| property_xml(name, None)));
+            },
+            PropfindRequest::Prop(names) => {
+                for name in names.iter() {
+                    let value = if name.0 == DAV { self.live_property(&name.1, path, metadata) } else { None };
+                    match value.or_else(|| state_properties.get(name).cloned()) {
+                        Some(value) => found.push(property_xml(name, Some(&value))),
+                        None => missing.push(property_xml(name, None)),
+                    }
+                }
+            },
+        }
+        let mut response = format!("<D:response><D:href>{}</D:href>", xml::escape(&href));
+        if !found.is_empty() {
+            response.push_str(&propstat(&found, 200));
+        }
+        if !missing.is_empty() {
+            response.push_str(&propstat(&missing, 404));
+        }
+        response.push_str("</D:response>");
+        response
+    }
+
+    fn handle_propfind(&self, request: &[HttpFrame], ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.target(request)?;
+        let depth = depth(&request[0], Depth::Infinity)?;
+        let propfind = WebDav::parse_propfind(request_body(request))?;
+        let metadata = std::fs::metadata(&path)?;
+
+        let mut resources = Vec::new();
+        self.collect_resources(&path, metadata, depth, &mut resources)?;
+        let prefix = router::mount_prefix(&request[0], ctx);
+        let responses: Vec<String> = resources
+            .iter()
+            .map(|(path, metadata)| self.propfind_response(self.href(&prefix, path, metadata.is_dir()), path, metadata, &propfind))
+            .collect();
+        Ok(multistatus(&responses))
+    }
+
+    fn handle_proppatch(&self, request: &[HttpFrame], ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.target(request)?;
+        let metadata = std::fs::metadata(&path)?;
+        self.check_locks(&request[0], &path, false)?;
+
+        let root = xml::parse(request_body(request))?;
+        if !root.is(DAV, "propertyupdate") {
+            return Err(HttpError::bad_request("Expected a DAV:propertyupdate element"));
+        }
+        let mut updates = Vec::new();
+        for instruction in root.children.iter() {
+            let properties = instruction.child(DAV, "prop").map_or(&[][..], |prop| &prop.children[..]);
+            for property in properties.iter() {
+                match instruction.name.as_str() {
+                    "set" if instruction.namespace == DAV => updates.push(PropertyUpdate::Set(property_name(property), property.inner.clone())),
+                    "remove" if instruction.namespace == DAV => updates.push(PropertyUpdate::Remove(property_name(property))),
+                    _ => (),
+                }
+            }
+        }
+
+        // Live properties are computed from the file and cannot be changed. Updates are
+        // atomic, so one refused property fails all the others with 424.
+        let is_protected = |name: &PropertyName| name.0 == DAV && LIVE_PROPERTIES.contains(&name.1.as_str());
+        let names: Vec<&PropertyName> = updates.iter().map(|update| match update {
+            PropertyUpdate::Set(name, _) | PropertyUpdate::Remove(name) => name,
+        }).collect();
+        let refused = names.iter().any(|name| is_protected(name));
+        let mut statuses: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+        for name in names.iter() {
+            let code = if is_protected(name) { 403 } else if refused { 424 } else { 200 };
+            statuses.entry(code).or_default().push(property_xml(name, None));
+        }
+        if !refused {
+            let mut state = self.state();
+            let properties = state.properties.entry(path.clone()).or_default();
+            for update in updates {
+                match update {
+                    PropertyUpdate::Set(name, value) => properties.insert(name, value),
+                    PropertyUpdate::Remove(name) => properties.remove(&name),
+                };
+            }
+        }
+
+        let href = self.href(&router::mount_prefix(&request[0], ctx), &path, metadata.is_dir());
+        let propstats: String = statuses.iter().map(|(code, properties)| propstat(properties, *code)).collect();
+        Ok(multistatus(&[format!("<D:response><D:href>{}</D:href>{}</D:response>", xml::escape(&href), propstats)]))
+    }
+
+    fn handle_get(&self, request: &[HttpFrame], ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        self.files.handle_read(&request[0], ctx)
+    }
+
+    fn handle_put(&self, request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.target(request)?;
+        self.check_locks(&request[0], &path, false)?;
+        self.files.handle_write(request)
+    }
+
+    fn handle_options(&self, _request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let mut headers = HeaderMap::new();
+        headers.map.insert("DAV".to_string(), vec!["1".to_string(), "2".to_string()]);
+        headers.map.insert("Allow".to_string(), ALLOWED_METHODS.iter().map(|method| method.to_string()).collect());
+        // Makes Microsoft clients use WebDAV rather than FrontPage extensions
+        headers.map.insert("MS-Author-Via".to_string(), vec!["DAV".to_string()]);
+        Ok(vec![HttpFrame::ResponseHead { status: status_code(200), version: Version::Http1_1, headers }])
+    }
+
+    fn handle_delete(&self, request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.target(request)?;
+        if path == self.root() {
+            return Err(HttpError::forbidden());
+        }
+        let metadata = std::fs::metadata(&path)?;
+        self.check_locks(&request[0], &path, true)?;
+        if metadata.is_dir() {
+            std::fs::remove_dir_all(&path)?;
+        } else {
+            conditional::evaluate(&request[0], Some(&Validators::from_metadata(&metadata)))?;
+            std::fs::remove_file(&path)?;
+        }
+        self.state().forget(&path);
+        Ok(empty_response(204))
+    }
+
+    fn handle_mkcol(&self, request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.target(request)?;
+        if !request_body(request).is_empty() {
+            return Err(HttpError::from_status(415, "MKCOL does not take a request body"));
+        }
+        if path.exists() {
+            return Err(HttpError::from_status(405, "The resource already exists"));
+        }
+        if !path.parent().is_some_and(|parent| parent.is_dir()) {
+            return Err(HttpError::from_status(409, "The parent collection does not exist"));
+        }
+        self.check_locks(&request[0], &path, false)?;
+        std::fs::create_dir(&path)?;
+        Ok(empty_response(201))
+    }
+
+    // Copy `from` to `to`, with the members of collections when `recursive`.
+    fn copy_tree(&self, from: &Path, to: &Path, recursive: bool) -> Result<(), HttpError> {
+        if !from.is_dir() {
+            std::fs::copy(from, to)?;
+            return Ok(());
+        }
+        std::fs::create_dir(to)?;
+        if recursive {
+            for entry in std::fs::read_dir(from)? {
+                let entry = entry?.path();
+                // Symlinks leading out of the root must not pull outside files in
+                if !self.files.resolver().contains(&entry) {
+                    continue;
+                }
+                if let Some(name) = entry.file_name() {
+                    self.copy_tree(&entry, &to.join(name), true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_copy_move(&self, request: &[HttpFrame], ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let is_move = request[0].get_method() == Method::MOVE;
+        let source = self.target(request)?;
+        let destination = self.destination(&request[0], &router::mount_prefix(&request[0], ctx))?;
+        let source_metadata = std::fs::metadata(&source)?;
+        let depth = depth(&request[0], Depth::Infinity)?;
+        if depth == Depth::One || (is_move && depth != Depth::Infinity) {
+            return Err(HttpError::bad_request("Invalid Depth for COPY or MOVE"));
+        }
+        if source == destination || (source_metadata.is_dir() && destination.starts_with(&source)) || source == self.root() || destination == self.root() {
+            return Err(HttpError::forbidden());
+        }
+        let overwrite = !matches!(request[0].get_headers().get_joined("Overwrite").as_deref().map(str::trim), Some("F") | Some("f"));
+        if !destination.parent().is_some_and(|parent| parent.is_dir()) {
+            return Err(HttpError::from_status(409, "The destination's parent collection does not exist"));
+        }
+        if is_move {
+            self.check_locks(&request[0], &source, true)?;
+        }
+        self.check_locks(&request[0], &destination, true)?;
+
+        let existed = destination.exists();
+        if existed {
+            if !overwrite {
+                return Err(HttpError::from_status(412, "The destination exists and Overwrite is F"));
+            }
+            if destination.is_dir() {
+                std::fs::remove_dir_all(&destination)?;
+            } else {
+                std::fs::remove_file(&destination)?;
+            }
+            self.state().forget(&destination);
+        }
+
+        if is_move {
+            std::fs::rename(&source, &destination)?;
+            let mut state = self.state();
+            state.copy_properties(&source, &destination);
+            state.forget(&source);
+        } else {
+            self.copy_tree(&source, &destination, depth == Depth::Infinity)?;
+            self.state().copy_properties(&source, &destination);
+        }
+        Ok(empty_response(if existed { 204 } else { 201 }))
+    }
+
+    fn lock_response(status: u16, lock: &Lock) -> Vec<HttpFrame> {
+        let mut headers = HeaderMap::new();
+        headers.map.insert("Lock-Token".to_string(), vec![format!("<{}>", lock.token)]);
+        xml_response(status, headers, format!("<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>", WebDav::active_lock_xml(lock)))
+    }
+
+    fn handle_lock(&self, request: &[HttpFrame], ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.target(request)?;
+        let timeout = lock_timeout(&request[0]);
+        let body = request_body(request);
+
+        // Without a body, LOCK refreshes the lock named in the If header
+        if body.iter().all(|byte| byte.is_ascii_whitespace()) {
+            let tokens = submitted_tokens(&request[0]);
+            let mut state = self.state();
+            let Some(lock) = state.locks.iter_mut().find(|lock| tokens.contains(&lock.token) && lock.covers(&path)) else {
+                return Err(HttpError::from_status(412, "No lock token for this resource was submitted"));
+            };
+            lock.timeout = timeout;
+            lock.expires = Instant::now() + Duration::from_secs(timeout);
+            return Ok(WebDav::lock_response(200, lock));
+        }
+
+        let depth = depth(&request[0], Depth::Infinity)?;
+        if depth == Depth::One {
+            return Err(HttpError::bad_request("Locks have Depth 0 or infinity"));
+        }
+        let root = xml::parse(body)?;
+        if !root.is(DAV, "lockinfo") {
+            return Err(HttpError::bad_request("Expected a DAV:lockinfo element"));
+        }
+        let exclusive = match root.child(DAV, "lockscope") {
+            Some(scope) if scope.child(DAV, "exclusive").is_some() => true,
+            Some(scope) if scope.child(DAV, "shared").is_some() => false,
+            _ => return Err(HttpError::bad_request("DAV:lockinfo needs a lockscope")),
+        };
+        if root.child(DAV, "locktype").and_then(|locktype| locktype.child(DAV, "write")).is_none() {
+            return Err(HttpError::from_status(422, "Only write locks are supported"));
+        }
+
+        let mut state = self.state();
+        let conflict = state.locks.iter().any(|lock| {
+            (lock.covers(&path) || (depth == Depth::Infinity && lock.root.starts_with(&path))) && (lock.exclusive || exclusive)
+        });
+        if conflict {
+            return Err(HttpError::from_status(423, "The resource is already locked"));
+        }
+
+        // Locking an unmapped URL creates an empty resource
+        let created = !path.exists();
+        if created {
+            if !path.parent().is_some_and(|parent| parent.is_dir()) {
+                return Err(HttpError::from_status(409, "The parent collection does not exist"));
+            }
+            std::fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+        }
+        let lock = Lock {
+            token: new_lock_token(),
+            href: self.href(&router::mount_prefix(&request[0], ctx), &path, path.is_dir()),
+            root: path,
+            depth,
+            exclusive,
+            owner: root.child(DAV, "owner").map(|owner| owner.inner.clone()),
+            timeout,
+            expires: Instant::now() + Duration::from_secs(timeout),
+        };
+        let response = WebDav::lock_response(if created { 201 } else { 200 }, &lock);
+        state.locks.push(lock);
+        Ok(response)
+    }
+
+    fn handle_unlock(&self, request: &[HttpFrame], _ctx: &RequestContext) -> Result<Vec<HttpFrame>, HttpError> {
+        let path = self.target(request)?;
+        let token = request[0].get_headers().get_joined("Lock-Token")
+            .ok_or_else(|| HttpError::bad_request("Missing Lock-Token header"))?;
+        let token = token.trim().trim_start_matches('<').trim_end_matches('>');
+        let mut state = self.state();
+        match state.locks.iter().position(|lock| lock.token == token && lock.covers(&path)) {
+            Some(index) => {
+                state.locks.remove(index);
+                Ok(empty_response(204))
+            },
+            None => Err(HttpError::from_status(409, "The lock token does not match a lock on this resource")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, body_text, call, header, status, TempDir};
+
+    const LOCKINFO: &str = r#"<?xml version="1.0"?><D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype><D:owner>me</D:owner></D:lockinfo>"#;
+
+    // The share mounted at /dav, as main.rs does.
+    fn share(dav: &WebDav) -> Router {
+        let mut router = Router::new();
+        router.mount("/dav", dav.router());
+        router
+    }
+
+    fn send(router: &Router, method: &str, uri: &str, headers: &[(&str, &str)], body: &str) -> Result<Vec<HttpFrame>, HttpError> {
+        call(router, test_util::request(method, uri, headers, body.as_bytes()))
+    }
+
+    fn error_status(result: Result<Vec<HttpFrame>, HttpError>) -> u16 {
+        result.unwrap_err().status().unwrap().0
+    }
+
+    fn lock(router: &Router, uri: &str) -> String {
+        let response = send(router, "LOCK", uri, &[], LOCKINFO).unwrap();
+        let token = header(&response, "Lock-Token").unwrap();
+        token.trim_start_matches('<').trim_end_matches('>').to_string()
+    }
+
+    #[test]
+    fn options_advertises_classes_and_methods() {
+        let dir = TempDir::new();
+        let response = send(&share(&WebDav::new(dir.root())), "OPTIONS", "/dav/", &[], "").unwrap();
+        assert_eq!(header(&response, "DAV").as_deref(), Some("1, 2"));
+        let allow = header(&response, "Allow").unwrap();
+        assert!(allow.contains("PROPFIND") && allow.contains("LOCK"));
+        assert!(!allow.contains("POST"));
+    }
+
+    #[test]
+    fn propfind_lists_members_with_mounted_hrefs() {
+        let dir = TempDir::new();
+        dir.write("docs/a b.txt", "hello");
+        let router = share(&WebDav::new(dir.root()));
+        let response = send(&router, "PROPFIND", "/dav/docs/", &[("Depth", "1")], "").unwrap();
+        assert_eq!(status(&response), 207);
+        let body = body_text(&response);
+        assert!(body.contains("<D:href>/dav/docs/</D:href>"));
+        assert!(body.contains("<D:href>/dav/docs/a%20b.txt</D:href>"));
+        assert!(body.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(body.contains("<D:resourcetype><D:collection/></D:resourcetype>"));
+
+        let response = send(&router, "PROPFIND", "/dav/docs/", &[("Depth", "0")], "").unwrap();
+        assert!(!body_text(&response).contains("a%20b.txt"));
+        assert_eq!(error_status(send(&router, "PROPFIND", "/dav/docs/", &[("Depth", "2")], "")), 400);
+        assert_eq!(error_status(send(&router, "PROPFIND", "/dav/missing", &[], "")), 404);
+    }
+
+    #[test]
+    fn propfind_reports_unknown_properties_as_404() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "hello");
+        let router = share(&WebDav::new(dir.root()));
+        let body = r#"<D:propfind xmlns:D="DAV:"><D:prop><D:getcontentlength/><x:color xmlns:x="urn:x"/></D:prop></D:propfind>"#;
+        let response = body_text(&send(&router, "PROPFIND", "/dav/a.txt", &[], body).unwrap());
+        let (found, missing) = response.split_once("HTTP/1.1 200 OK").unwrap();
+        assert!(found.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(missing.contains("<color xmlns=\"urn:x\"/>") && missing.contains("HTTP/1.1 404 Not Found"));
+        assert_eq!(error_status(send(&router, "PROPFIND", "/dav/a.txt", &[], "<D:other xmlns:D=\"DAV:\"/>")), 400);
+    }
+
+    #[test]
+    fn proppatch_sets_dead_properties_and_refuses_live_ones() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "hello");
+        let router = share(&WebDav::new(dir.root()));
+        let set = r#"<D:propertyupdate xmlns:D="DAV:"><D:set><D:prop><x:color xmlns:x="urn:x">red</x:color></D:prop></D:set></D:propertyupdate>"#;
+        assert!(body_text(&send(&router, "PROPPATCH", "/dav/a.txt", &[], set).unwrap()).contains("HTTP/1.1 200 OK"));
+        let find = r#"<D:propfind xmlns:D="DAV:"><D:prop><x:color xmlns:x="urn:x"/></D:prop></D:propfind>"#;
+        assert!(body_text(&send(&router, "PROPFIND", "/dav/a.txt", &[], find).unwrap()).contains(">red</color>"));
+
+        // One protected property fails the whole update
+        let update = r#"<D:propertyupdate xmlns:D="DAV:"><D:set><D:prop><D:getetag>x</D:getetag><x:size xmlns:x="urn:x">1</x:size></D:prop></D:set></D:propertyupdate>"#;
+        let response = body_text(&send(&router, "PROPPATCH", "/dav/a.txt", &[], update).unwrap());
+        assert!(response.contains("HTTP/1.1 403 Forbidden") && response.contains("HTTP/1.1 424 Failed Dependency"));
+        let find = r#"<D:propfind xmlns:D="DAV:"><D:prop><x:size xmlns:x="urn:x"/></D:prop></D:propfind>"#;
+        assert!(body_text(&send(&router, "PROPFIND", "/dav/a.txt", &[], find).unwrap()).contains("404 Not Found"));
+    }
+
+    #[test]
+    fn mkcol_creates_collections_once() {
+        let dir = TempDir::new();
+        let router = share(&WebDav::new(dir.root()));
+        assert_eq!(status(&send(&router, "MKCOL", "/dav/new", &[], "").unwrap()), 201);
+        assert!(Path::new(dir.root()).join("new").is_dir());
+        assert_eq!(error_status(send(&router, "MKCOL", "/dav/new", &[], "")), 405);
+        assert_eq!(error_status(send(&router, "MKCOL", "/dav/a/b", &[], "")), 409);
+        assert_eq!(error_status(send(&router, "MKCOL", "/dav/other", &[], "<x/>")), 415);
+    }
+
+    #[test]
+    fn copy_and_move_follow_destination_and_overwrite() {
+        let dir = TempDir::new();
+        dir.write("src/a.txt", "a");
+        dir.write("other.txt", "other");
+        let router = share(&WebDav::new(dir.root()));
+        let root = Path::new(dir.root());
+
+        let response = send(&router, "COPY", "/dav/src", &[("Destination", "http://test/dav/copy")], "").unwrap();
+        assert_eq!(status(&response), 201);
+        assert_eq!(std::fs::read(root.join("copy/a.txt")).unwrap(), b"a");
+
+        let no_overwrite = [("Destination", "/dav/copy/a.txt"), ("Overwrite", "F")];
+        assert_eq!(error_status(send(&router, "MOVE", "/dav/other.txt", &no_overwrite, "")), 412);
+        let response = send(&router, "MOVE", "/dav/other.txt", &[("Destination", "/dav/copy/a.txt")], "").unwrap();
+        assert_eq!(status(&response), 204);
+        assert!(!root.join("other.txt").exists());
+        assert_eq!(std::fs::read(root.join("copy/a.txt")).unwrap(), b"other");
+    }
+
+    #[test]
+    fn invalid_copy_targets_are_refused() {
+        let dir = TempDir::new();
+        dir.write("src/a.txt", "a");
+        let router = share(&WebDav::new(dir.root()));
+        assert_eq!(error_status(send(&router, "COPY", "/dav/src", &[], "")), 400);
+        assert_eq!(error_status(send(&router, "COPY", "/dav/src", &[("Destination", "/dav/src/inner")], "")), 403);
+        assert_eq!(error_status(send(&router, "COPY", "/dav/src", &[("Destination", "/elsewhere/x")], "")), 502);
+        assert_eq!(error_status(send(&router, "COPY", "/dav/src", &[("Destination", "/dav/a/b")], "")), 409);
+        assert_eq!(error_status(send(&router, "MOVE", "/dav/src", &[("Destination", "/dav/x"), ("Depth", "0")], "")), 400);
+    }
+
+    #[test]
+    fn locked_resources_need_the_token() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a");
+        let router = share(&WebDav::new(dir.root()));
+        let token = lock(&router, "/dav/a.txt");
+        assert!(token.starts_with("opaquelocktoken:"));
+
+        assert_eq!(error_status(send(&router, "PUT", "/dav/a.txt", &[], "new")), 423);
+        assert_eq!(error_status(send(&router, "DELETE", "/dav/a.txt", &[], "")), 423);
+        assert_eq!(error_status(send(&router, "LOCK", "/dav/a.txt", &[], LOCKINFO)), 423);
+        let submitted = format!("(<{}>)", token);
+        assert_eq!(status(&send(&router, "PUT", "/dav/a.txt", &[("If", &submitted)], "new").unwrap()), 204);
+
+        // Refreshing needs the token as well
+        assert_eq!(error_status(send(&router, "LOCK", "/dav/a.txt", &[], "")), 412);
+        assert_eq!(status(&send(&router, "LOCK", "/dav/a.txt", &[("If", &submitted), ("Timeout", "Second-60")], "").unwrap()), 200);
+
+        let lock_token = format!("<{}>", token);
+        assert_eq!(error_status(send(&router, "UNLOCK", "/dav/a.txt", &[("Lock-Token", "<opaquelocktoken:other>")], "")), 409);
+        assert_eq!(status(&send(&router, "UNLOCK", "/dav/a.txt", &[("Lock-Token", &lock_token)], "").unwrap()), 204);
+        assert_eq!(status(&send(&router, "DELETE", "/dav/a.txt", &[], "").unwrap()), 204);
+    }
+
+    #[test]
+    fn depth_infinity_locks_cover_members_and_lock_null_urls_are_created() {
+        let dir = TempDir::new();
+        dir.write("docs/a.txt", "a");
+        let router = share(&WebDav::new(dir.root()));
+        lock(&router, "/dav/docs/");
+        assert_eq!(error_status(send(&router, "PUT", "/dav/docs/a.txt", &[], "new")), 423);
+        assert_eq!(error_status(send(&router, "MKCOL", "/dav/docs/sub", &[], "")), 423);
+
+        let response = send(&router, "LOCK", "/dav/new.txt", &[], LOCKINFO).unwrap();
+        assert_eq!(status(&response), 201);
+        assert!(Path::new(dir.root()).join("new.txt").is_file());
+        assert!(body_text(&response).contains("<D:owner>me</D:owner>"));
+    }
+
+    #[test]
+    fn write_guard_refuses_writes_through_static_files() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a");
+        let dav = WebDav::new(dir.root());
+        let mut files = StaticFiles::new(dir.root());
+        files.set_writable(true);
+        files.set_write_guard(dav.write_guard());
+        let token = lock(&share(&dav), "/dav/a.txt");
+
+        let request = test_util::request("PUT", "/a.txt", &[], b"new");
+        assert_eq!(error_status(call(&files.router(), request)), 423);
+        let request = test_util::request("DELETE", "/a.txt", &[], b"");
+        assert_eq!(error_status(call(&files.router(), request)), 423);
+        let submitted = format!("(<{}>)", token);
+        let request = test_util::request("PUT", "/a.txt", &[("If", &submitted)], b"new");
+        assert_eq!(status(&call(&files.router(), request).unwrap()), 204);
+        // Other files are not affected
+        let request = test_util::request("PUT", "/b.txt", &[], b"new");
+        assert_eq!(status(&call(&files.router(), request).unwrap()), 201);
+    }
+
+    #[test]
+    fn timestamps_and_timeouts_are_formatted_and_clamped() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400 + 3_723)), "2000-02-29T01:02:03Z");
+        let timeout = |value: &str| lock_timeout(&test_util::request("LOCK", "/", &[("Timeout", value)], b"")[0]);
+        assert_eq!(timeout("Second-30"), 30);
+        assert_eq!(timeout("Infinite, Second-30"), MAX_LOCK_TIMEOUT);
+        assert_eq!(timeout("Second-999999"), MAX_LOCK_TIMEOUT);
+        assert_eq!(timeout("Second-0"), 1);
+        assert_eq!(timeout("bogus"), MAX_LOCK_TIMEOUT);
+    }
+}
@@ -0,0 +1,149 @@
+// A small namespace-aware element tree for the XML request bodies WebDAV uses.
+use quick_xml::{events::Event, name::ResolveResult, NsReader};
+
+use crate::HttpError;
+
+// Request bodies nested deeper than this are refused rather than walked.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Default)]
+pub(crate) struct Element {
+    // Namespace URI, empty for elements in no namespace.
+    pub(crate) namespace: String,
+    pub(crate) name: String,
+    pub(crate) children: Vec<Element>,
+    // Concatenated text content directly inside the element.
+    pub(crate) text: String,
+    // The element's content as it appeared in the document, markup included.
+    pub(crate) inner: String,
+}
+
+impl Element {
+    pub(crate) fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    pub(crate) fn child(&self, namespace: &str, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(namespace, name))
+    }
+}
+
+fn malformed(e: impl std::error::Error + Send + Sync + 'static) -> HttpError {
+    HttpError::bad_request("Malformed XML request body").with_source(e)
+}
+
+fn open_element(resolved: ResolveResult, local_name: &[u8]) -> Result<Element, HttpError> {
+    let namespace = match resolved {
+        ResolveResult::Bound(namespace) => String::from_utf8_lossy(namespace.as_ref()).into_owned(),
+        ResolveResult::Unbound => String::new(),
+        ResolveResult::Unknown(prefix) => {
+            return Err(HttpError::bad_request(&format!("Undeclared namespace prefix \"{}\"", String::from_utf8_lossy(&prefix))));
+        },
+    };
+    Ok(Element { namespace, name: String::from_utf8_lossy(local_name).into_owned(), ..Element::default() })
+}
+
+// Parse `body` into its root element. Malformed documents fail with 400.
+pub(crate) fn parse(body: &[u8]) -> Result<Element, HttpError> {
+    let text = std::str::from_utf8(body).map_err(malformed)?;
+    let mut reader = NsReader::from_str(text);
+    // Elements still open, with the offset at which their content starts
+    let mut stack: Vec<(Element, usize)> = Vec::new();
+    let mut root = None;
+
+    loop {
+        let before = reader.buffer_position();
+        let (resolved, event) = reader.read_resolved_event().map_err(malformed)?;
+        match event {
+            Event::Start(start) => {
+                if stack.len() == MAX_DEPTH {
+                    return Err(HttpError::bad_request("XML request body is nested too deeply"));
+                }
+                let element = open_element(resolved, start.local_name().as_ref())?;
+                stack.push((element, reader.buffer_position()));
+            },
+            Event::Empty(start) => {
+                let element = open_element(resolved, start.local_name().as_ref())?;
+                match stack.last_mut() {
+                    Some((parent, _)) => parent.children.push(element),
+                    None => root = Some(element),
+                }
+            },
+            Event::End(_) => {
+                let Some((mut element, start)) = stack.pop() else {
+                    return Err(HttpError::bad_request("Unbalanced XML request body"));
+                };
+                element.inner = text[start..before].to_string();
+                match stack.last_mut() {
+                    Some((parent, _)) => parent.children.push(element),
+                    None => root = Some(element),
+                }
+            },
+            Event::Text(content) => {
+                if let Some((element, _)) = stack.last_mut() {
+                    element.text.push_str(&content.unescape().map_err(malformed)?);
+                }
+            },
+            Event::CData(content) => {
+                if let Some((element, _)) = stack.last_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(&content));
+                }
+            },
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    if !stack.is_empty() {
+        return Err(HttpError::bad_request("Unexpected end of XML request body"));
+    }
+    root.ok_or_else(|| HttpError::bad_request("XML request body has no root element"))
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    quick_xml::escape::escape(text).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(result: Result<Element, HttpError>) -> u16 {
+        result.unwrap_err().status().unwrap().0
+    }
+
+    #[test]
+    fn elements_are_resolved_to_their_namespace() {
+        let root = parse(br#"<?xml version="1.0"?>
+            <D:propfind xmlns:D="DAV:"><D:prop><x:color xmlns:x="urn:x"/><plain/></D:prop></D:propfind>"#).unwrap();
+        assert!(root.is("DAV:", "propfind"));
+        let prop = root.child("DAV:", "prop").unwrap();
+        assert!(prop.children[0].is("urn:x", "color"));
+        assert!(prop.children[1].is("", "plain"));
+        assert!(root.child("urn:x", "prop").is_none());
+    }
+
+    #[test]
+    fn text_is_unescaped_and_inner_markup_kept() {
+        let root = parse(b"<a xmlns=\"urn:a\"><b>x &amp; y<![CDATA[<z>]]></b><c><d>1</d></c></a>").unwrap();
+        assert_eq!(root.children[0].text, "x & y<z>");
+        assert_eq!(root.children[1].inner, "<d>1</d>");
+        assert_eq!(escape("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+    }
+
+    #[test]
+    fn malformed_documents_are_400() {
+        assert_eq!(status(parse(b"<a><b></a>")), 400);
+        assert_eq!(status(parse(b"<a><b>")), 400);
+        assert_eq!(status(parse(b"")), 400);
+        assert_eq!(status(parse(b"<x:a/>")), 400);
+        assert_eq!(status(parse(b"<a>\xff</a>")), 400);
+    }
+
+    #[test]
+    fn deeply_nested_documents_are_refused() {
+        let body = format!("{}{}", "<a>".repeat(MAX_DEPTH + 1), "</a>".repeat(MAX_DEPTH + 1));
+        assert_eq!(status(parse(body.as_bytes())), 400);
+        let body = format!("{}{}", "<a>".repeat(MAX_DEPTH), "</a>".repeat(MAX_DEPTH));
+        assert!(parse(body.as_bytes()).is_ok());
+    }
+}